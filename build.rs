@@ -0,0 +1,4 @@
+// Rebuild when migrations change, so `sqlx::migrate!` embeds new or edited files.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
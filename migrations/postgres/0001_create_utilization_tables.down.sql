@@ -0,0 +1,6 @@
+DROP TABLE IF EXISTS oscar.gpu;
+
+DROP TABLE IF EXISTS oscar.cpu;
+
+-- Only succeeds once every other table is gone.
+DROP SCHEMA IF EXISTS oscar;
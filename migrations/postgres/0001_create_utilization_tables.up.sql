@@ -0,0 +1,26 @@
+-- Utilization samples for the oscar cluster, one row per sample time.
+
+CREATE SCHEMA IF NOT EXISTS oscar;
+
+CREATE TABLE IF NOT EXISTS oscar.cpu (
+    time TIMESTAMP NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS oscar.gpu (
+    time TIMESTAMP NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL
+);
+
+-- Grants are skipped when the service account doesn't exist yet, e.g. in test databases.
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT USAGE ON SCHEMA oscar TO elmo_app;
+        GRANT SELECT ON ALL TABLES IN SCHEMA oscar TO elmo_app;
+        ALTER DEFAULT PRIVILEGES IN SCHEMA oscar GRANT SELECT ON TABLES TO elmo_app;
+    END IF;
+END
+$$;
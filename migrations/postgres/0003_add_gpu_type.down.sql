@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS oscar.gpu_gpu_type_time_idx;
+
+ALTER TABLE oscar.gpu DROP COLUMN IF EXISTS gpu_type;
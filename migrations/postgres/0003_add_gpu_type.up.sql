@@ -0,0 +1,7 @@
+-- Oscar mixes several GPU generations, so each GPU sample records the model it counts.
+-- Samples written before this column existed keep an empty gpu_type and still count
+-- towards the cluster-wide totals.
+
+ALTER TABLE oscar.gpu ADD COLUMN IF NOT EXISTS gpu_type TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX IF NOT EXISTS gpu_gpu_type_time_idx ON oscar.gpu (gpu_type, time);
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS oscar.quotas;
+
+DROP TABLE IF EXISTS oscar.storage;
@@ -0,0 +1,28 @@
+-- Filesystem capacity samples, one row per filesystem (e.g. /scratch, /data) per sample time.
+
+CREATE TABLE IF NOT EXISTS oscar.storage (
+    time TIMESTAMP NOT NULL,
+    filesystem TEXT NOT NULL,
+    used_bytes BIGINT NOT NULL,
+    capacity_bytes BIGINT NOT NULL,
+    inodes_used BIGINT NOT NULL,
+    inodes_total BIGINT NOT NULL,
+    PRIMARY KEY (filesystem, time)
+);
+
+CREATE INDEX IF NOT EXISTS storage_time_idx ON oscar.storage (time);
+
+-- Per-group quota samples. A NULL limit means the group has no quota on that filesystem.
+
+CREATE TABLE IF NOT EXISTS oscar.quotas (
+    time TIMESTAMP NOT NULL,
+    filesystem TEXT NOT NULL,
+    group_name TEXT NOT NULL,
+    used_bytes BIGINT NOT NULL,
+    limit_bytes BIGINT,
+    inodes_used BIGINT NOT NULL,
+    inode_limit BIGINT,
+    PRIMARY KEY (filesystem, group_name, time)
+);
+
+CREATE INDEX IF NOT EXISTS quotas_time_idx ON oscar.quotas (time);
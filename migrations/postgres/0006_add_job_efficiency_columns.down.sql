@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS oscar.jobs_partition_end_time_idx;
+
+ALTER TABLE oscar.jobs
+    DROP COLUMN IF EXISTS max_rss_bytes,
+    DROP COLUMN IF EXISTS mem_requested_bytes,
+    DROP COLUMN IF EXISTS cpu_time_seconds,
+    DROP COLUMN IF EXISTS elapsed_seconds;
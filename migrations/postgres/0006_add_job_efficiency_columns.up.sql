@@ -0,0 +1,13 @@
+-- seff-style efficiency inputs for completed jobs. CPU efficiency is
+-- cpu_time_seconds / (elapsed_seconds * cpus); memory efficiency is
+-- max_rss_bytes / mem_requested_bytes.
+
+ALTER TABLE oscar.jobs ADD COLUMN IF NOT EXISTS elapsed_seconds BIGINT;
+
+ALTER TABLE oscar.jobs ADD COLUMN IF NOT EXISTS cpu_time_seconds BIGINT;
+
+ALTER TABLE oscar.jobs ADD COLUMN IF NOT EXISTS mem_requested_bytes BIGINT;
+
+ALTER TABLE oscar.jobs ADD COLUMN IF NOT EXISTS max_rss_bytes BIGINT;
+
+CREATE INDEX IF NOT EXISTS jobs_partition_end_time_idx ON oscar.jobs (partition, end_time);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS oscar.gpu_time_gpu_type_key;
+
+DROP INDEX IF EXISTS oscar.cpu_time_key;
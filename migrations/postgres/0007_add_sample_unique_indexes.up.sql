@@ -0,0 +1,14 @@
+-- The ingestion API upserts samples on their time (and GPU type for GPU samples).
+-- On an existing database, remove any duplicate samples first or this will fail.
+
+CREATE UNIQUE INDEX IF NOT EXISTS cpu_time_key ON oscar.cpu (time);
+
+CREATE UNIQUE INDEX IF NOT EXISTS gpu_time_gpu_type_key ON oscar.gpu (time, gpu_type);
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT INSERT, UPDATE ON oscar.cpu, oscar.gpu, oscar.jobs TO elmo_app;
+    END IF;
+END
+$$;
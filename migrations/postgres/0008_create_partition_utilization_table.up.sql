@@ -0,0 +1,19 @@
+-- Allocated and total per Slurm partition, written by the sinfo collector.
+-- `resource` is either 'cpu' or 'gpu'. Nodes in several partitions count towards each.
+
+CREATE TABLE IF NOT EXISTS oscar.partition_utilization (
+    time TIMESTAMP NOT NULL,
+    partition TEXT NOT NULL,
+    resource TEXT NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL,
+    PRIMARY KEY (time, partition, resource)
+);
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT SELECT, INSERT, UPDATE ON oscar.partition_utilization TO elmo_app;
+    END IF;
+END
+$$;
@@ -0,0 +1,16 @@
+-- High-water marks for incremental importers. The sacct importer stores the latest job
+-- end time it has loaded and starts its next run from there.
+
+CREATE TABLE IF NOT EXISTS oscar.import_state (
+    source TEXT PRIMARY KEY,
+    high_water_mark TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT SELECT, INSERT, UPDATE ON oscar.import_state TO elmo_app;
+    END IF;
+END
+$$;
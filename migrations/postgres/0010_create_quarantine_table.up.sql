@@ -0,0 +1,25 @@
+-- Samples that failed the ingest quality checks (negative counts, allocated above total,
+-- timestamps in the future, conflicting duplicates), kept with the reason until they are
+-- released into `cpu`/`gpu` or deleted through /admin/quarantine.
+
+CREATE TABLE IF NOT EXISTS oscar.quarantine (
+    id BIGSERIAL PRIMARY KEY,
+    resource TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL,
+    gpu_type TEXT NOT NULL DEFAULT '',
+    reason TEXT NOT NULL,
+    quarantined_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS quarantine_resource_time_idx ON oscar.quarantine (resource, time);
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT SELECT, INSERT, DELETE ON oscar.quarantine TO elmo_app;
+        GRANT USAGE ON SEQUENCE oscar.quarantine_id_seq TO elmo_app;
+    END IF;
+END
+$$;
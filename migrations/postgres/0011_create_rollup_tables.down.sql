@@ -0,0 +1,8 @@
+DROP TRIGGER IF EXISTS gpu_rollup_dirty ON oscar.gpu;
+DROP TRIGGER IF EXISTS cpu_rollup_dirty ON oscar.cpu;
+DROP FUNCTION IF EXISTS oscar.create_rollup_tables(TEXT);
+-- Also drops the triggers oscar.create_rollup_tables put on other cluster schemas.
+DROP FUNCTION IF EXISTS oscar.mark_rollup_dirty() CASCADE;
+DROP TABLE IF EXISTS oscar.rollup_dirty;
+DROP TABLE IF EXISTS oscar.gpu_type_rollup;
+DROP TABLE IF EXISTS oscar.rollup;
@@ -0,0 +1,126 @@
+-- Hourly and daily rollups of the utilization samples. Each row keeps the sums and the
+-- sample count of one bucket, so averages come out exactly as if computed from `cpu`
+-- and `gpu`. `rollup` holds the cluster-wide series (GPU samples summed per time first),
+-- `gpu_type_rollup` one series per GPU model.
+--
+-- A trigger on `cpu` and `gpu` records the buckets every write touches in `rollup_dirty`,
+-- whoever makes it; the API recomputes those in the background and reads dirty buckets
+-- from the raw tables until then. Other cluster schemas get the same tables and triggers
+-- from `SELECT oscar.create_rollup_tables('<schema>')`.
+
+CREATE TABLE IF NOT EXISTS oscar.rollup (
+    resource TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    allocated_sum BIGINT NOT NULL,
+    total_sum BIGINT NOT NULL,
+    samples BIGINT NOT NULL,
+    PRIMARY KEY (resource, bucket, time)
+);
+
+CREATE TABLE IF NOT EXISTS oscar.gpu_type_rollup (
+    bucket TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    gpu_type TEXT NOT NULL,
+    allocated_sum BIGINT NOT NULL,
+    total_sum BIGINT NOT NULL,
+    samples BIGINT NOT NULL,
+    PRIMARY KEY (bucket, time, gpu_type)
+);
+
+CREATE TABLE IF NOT EXISTS oscar.rollup_dirty (
+    resource TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    PRIMARY KEY (resource, bucket, time)
+);
+
+-- Runs as the owner, so any role that may write samples can mark their buckets. The
+-- buckets go to the `rollup_dirty` table of the schema the written table is in.
+CREATE OR REPLACE FUNCTION oscar.mark_rollup_dirty() RETURNS trigger
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = pg_catalog, pg_temp AS $$
+DECLARE
+    mark TEXT := format(
+        'INSERT INTO %I.rollup_dirty (resource, bucket, time)
+         VALUES ($1, ''hour'', date_trunc(''hour'', $2)), ($1, ''day'', date_trunc(''day'', $2))
+         ON CONFLICT DO NOTHING',
+        TG_TABLE_SCHEMA
+    );
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        EXECUTE mark USING TG_ARGV[0], OLD.time;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        EXECUTE mark USING TG_ARGV[0], NEW.time;
+    END IF;
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER cpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON oscar.cpu
+    FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty('cpu');
+CREATE TRIGGER gpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON oscar.gpu
+    FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty('gpu');
+
+-- Gives another cluster schema, which already has its `cpu` and `gpu` tables, the rollup
+-- tables and triggers of `oscar`, with every existing bucket marked dirty.
+CREATE OR REPLACE FUNCTION oscar.create_rollup_tables(cluster_schema TEXT) RETURNS void
+LANGUAGE plpgsql AS $$
+DECLARE
+    rollup_table TEXT;
+BEGIN
+    FOREACH rollup_table IN ARRAY ARRAY['rollup', 'gpu_type_rollup', 'rollup_dirty'] LOOP
+        EXECUTE format(
+            'CREATE TABLE IF NOT EXISTS %1$I.%2$I (LIKE oscar.%2$I INCLUDING ALL)',
+            cluster_schema, rollup_table
+        );
+        IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+            EXECUTE format(
+                'GRANT SELECT, INSERT, DELETE ON %I.%I TO elmo_app', cluster_schema, rollup_table
+            );
+        END IF;
+    END LOOP;
+
+    EXECUTE format('DROP TRIGGER IF EXISTS cpu_rollup_dirty ON %I.cpu', cluster_schema);
+    EXECUTE format(
+        'CREATE TRIGGER cpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON %I.cpu
+         FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty(''cpu'')',
+        cluster_schema
+    );
+    EXECUTE format('DROP TRIGGER IF EXISTS gpu_rollup_dirty ON %I.gpu', cluster_schema);
+    EXECUTE format(
+        'CREATE TRIGGER gpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON %I.gpu
+         FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty(''gpu'')',
+        cluster_schema
+    );
+
+    EXECUTE format(
+        'INSERT INTO %1$I.rollup_dirty (resource, bucket, time)
+         SELECT ''cpu'', unit, date_trunc(unit, time)
+         FROM %1$I.cpu, (VALUES (''hour''), (''day'')) AS units (unit)
+         UNION
+         SELECT ''gpu'', unit, date_trunc(unit, time)
+         FROM %1$I.gpu, (VALUES (''hour''), (''day'')) AS units (unit)
+         ON CONFLICT DO NOTHING',
+        cluster_schema
+    );
+END
+$$;
+
+-- Every existing bucket starts out dirty, so the first refresh builds the rollups.
+INSERT INTO oscar.rollup_dirty (resource, bucket, time)
+SELECT 'cpu', unit, date_trunc(unit, time)
+FROM oscar.cpu, (VALUES ('hour'), ('day')) AS units (unit)
+UNION
+SELECT 'gpu', unit, date_trunc(unit, time)
+FROM oscar.gpu, (VALUES ('hour'), ('day')) AS units (unit)
+ON CONFLICT DO NOTHING;
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT SELECT, INSERT, DELETE ON oscar.rollup, oscar.gpu_type_rollup, oscar.rollup_dirty
+            TO elmo_app;
+    END IF;
+END
+$$;
@@ -0,0 +1,34 @@
+-- Moves the samples back into plain tables.
+
+DO $$
+BEGIN
+    IF (SELECT relkind FROM pg_class WHERE oid = 'oscar.cpu'::regclass) = 'p' THEN
+        CREATE TABLE oscar.cpu_unpartitioned (LIKE oscar.cpu INCLUDING DEFAULTS);
+        INSERT INTO oscar.cpu_unpartitioned SELECT * FROM oscar.cpu;
+        DROP TABLE oscar.cpu;
+        ALTER TABLE oscar.cpu_unpartitioned RENAME TO cpu;
+        CREATE TRIGGER cpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON oscar.cpu
+            FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty('cpu');
+        CREATE UNIQUE INDEX cpu_time_key ON oscar.cpu (time);
+    END IF;
+
+    IF (SELECT relkind FROM pg_class WHERE oid = 'oscar.gpu'::regclass) = 'p' THEN
+        CREATE TABLE oscar.gpu_unpartitioned (LIKE oscar.gpu INCLUDING DEFAULTS);
+        INSERT INTO oscar.gpu_unpartitioned SELECT * FROM oscar.gpu;
+        DROP TABLE oscar.gpu;
+        ALTER TABLE oscar.gpu_unpartitioned RENAME TO gpu;
+        CREATE TRIGGER gpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON oscar.gpu
+            FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty('gpu');
+        CREATE UNIQUE INDEX gpu_time_gpu_type_key ON oscar.gpu (time, gpu_type);
+        CREATE INDEX gpu_gpu_type_time_idx ON oscar.gpu (gpu_type, time);
+    END IF;
+END
+$$;
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT SELECT, INSERT, UPDATE ON oscar.cpu, oscar.gpu TO elmo_app;
+    END IF;
+END
+$$;
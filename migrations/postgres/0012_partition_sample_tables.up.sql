@@ -0,0 +1,67 @@
+-- Range-partitions `cpu` and `gpu` by month on `time`. The existing tables become the
+-- partitions `cpu_legacy` and `gpu_legacy`, covering everything before the month after
+-- their latest sample (or after the current month). The API creates the monthly
+-- partitions that follow, and drops them once RETENTION expires them. Samples for a month
+-- without a partition yet land in `cpu_default` and `gpu_default` instead of failing.
+
+DO $$
+DECLARE
+    bound TIMESTAMP;
+BEGIN
+    IF (SELECT relkind FROM pg_class WHERE oid = 'oscar.cpu'::regclass) = 'r' THEN
+        ALTER TABLE oscar.cpu RENAME TO cpu_legacy;
+        ALTER INDEX oscar.cpu_time_key RENAME TO cpu_legacy_time_key;
+        -- The rollup trigger moves to the new parent, which passes it on to every partition.
+        DROP TRIGGER cpu_rollup_dirty ON oscar.cpu_legacy;
+
+        CREATE TABLE oscar.cpu (LIKE oscar.cpu_legacy INCLUDING DEFAULTS)
+            PARTITION BY RANGE (time);
+        CREATE UNIQUE INDEX cpu_time_key ON oscar.cpu (time);
+
+        SELECT greatest(date_trunc('month', max(time)), date_trunc('month', localtimestamp))
+            + interval '1 month'
+        INTO bound
+        FROM oscar.cpu_legacy;
+        EXECUTE format(
+            'ALTER TABLE oscar.cpu ATTACH PARTITION oscar.cpu_legacy FOR VALUES FROM (MINVALUE) TO (%L)',
+            bound
+        );
+        CREATE TRIGGER cpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON oscar.cpu
+            FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty('cpu');
+        CREATE TABLE oscar.cpu_default PARTITION OF oscar.cpu DEFAULT;
+    END IF;
+
+    IF (SELECT relkind FROM pg_class WHERE oid = 'oscar.gpu'::regclass) = 'r' THEN
+        ALTER TABLE oscar.gpu RENAME TO gpu_legacy;
+        ALTER INDEX oscar.gpu_time_gpu_type_key RENAME TO gpu_legacy_time_gpu_type_key;
+        ALTER INDEX oscar.gpu_gpu_type_time_idx RENAME TO gpu_legacy_gpu_type_time_idx;
+        DROP TRIGGER gpu_rollup_dirty ON oscar.gpu_legacy;
+
+        CREATE TABLE oscar.gpu (LIKE oscar.gpu_legacy INCLUDING DEFAULTS)
+            PARTITION BY RANGE (time);
+        CREATE UNIQUE INDEX gpu_time_gpu_type_key ON oscar.gpu (time, gpu_type);
+        CREATE INDEX gpu_gpu_type_time_idx ON oscar.gpu (gpu_type, time);
+
+        SELECT greatest(date_trunc('month', max(time)), date_trunc('month', localtimestamp))
+            + interval '1 month'
+        INTO bound
+        FROM oscar.gpu_legacy;
+        EXECUTE format(
+            'ALTER TABLE oscar.gpu ATTACH PARTITION oscar.gpu_legacy FOR VALUES FROM (MINVALUE) TO (%L)',
+            bound
+        );
+        CREATE TRIGGER gpu_rollup_dirty AFTER INSERT OR UPDATE OR DELETE ON oscar.gpu
+            FOR EACH ROW EXECUTE FUNCTION oscar.mark_rollup_dirty('gpu');
+        CREATE TABLE oscar.gpu_default PARTITION OF oscar.gpu DEFAULT;
+    END IF;
+END
+$$;
+
+-- Retention deletes expired samples that aren't in a partition of their own.
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT SELECT, INSERT, UPDATE, DELETE ON oscar.cpu, oscar.gpu TO elmo_app;
+    END IF;
+END
+$$;
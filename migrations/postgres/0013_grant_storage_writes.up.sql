@@ -0,0 +1,10 @@
+-- The API stores filesystem and quota samples from `POST /storage/samples` and
+-- `POST /storage/quotas/samples`.
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT INSERT, UPDATE ON oscar.storage, oscar.quotas TO elmo_app;
+    END IF;
+END
+$$;
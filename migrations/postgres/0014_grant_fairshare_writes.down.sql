@@ -0,0 +1,7 @@
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        REVOKE INSERT, UPDATE ON oscar.fairshare FROM elmo_app;
+    END IF;
+END
+$$;
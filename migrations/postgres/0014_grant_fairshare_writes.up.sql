@@ -0,0 +1,9 @@
+-- The API stores `sshare` snapshots from `POST /fairshare/samples`.
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_roles WHERE rolname = 'elmo_app') THEN
+        GRANT INSERT, UPDATE ON oscar.fairshare TO elmo_app;
+    END IF;
+END
+$$;
@@ -0,0 +1,6 @@
+UPDATE oscar.jobs SET cpus = COALESCE(cpus, 0), gpus = COALESCE(gpus, 0)
+WHERE cpus IS NULL OR gpus IS NULL;
+
+ALTER TABLE oscar.jobs
+    ALTER COLUMN cpus SET NOT NULL,
+    ALTER COLUMN gpus SET NOT NULL;
@@ -0,0 +1,6 @@
+-- `cpus` and `gpus` are NULL until a source reports them, so a record without them
+-- (e.g. a jobcomp document without TRES) doesn't overwrite what sacct stored.
+
+ALTER TABLE oscar.jobs
+    ALTER COLUMN cpus DROP NOT NULL,
+    ALTER COLUMN gpus DROP NOT NULL;
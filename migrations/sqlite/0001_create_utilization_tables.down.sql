@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS gpu;
+
+DROP TABLE IF EXISTS cpu;
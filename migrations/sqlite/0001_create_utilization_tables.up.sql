@@ -0,0 +1,14 @@
+-- SQLite has no schemas, so the oscar tables are created unqualified. These migrations
+-- mirror the Postgres ones version for version and are used by the tests.
+
+CREATE TABLE IF NOT EXISTS cpu (
+    time TIMESTAMP NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS gpu (
+    time TIMESTAMP NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL
+);
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    job_id TEXT PRIMARY KEY,
+    account TEXT NOT NULL,
+    user_name TEXT NOT NULL,
+    partition TEXT,
+    submit_time TIMESTAMP,
+    start_time TIMESTAMP,
+    end_time TIMESTAMP,
+    cpus INTEGER NOT NULL DEFAULT 0,
+    gpus INTEGER NOT NULL DEFAULT 0,
+    state TEXT
+);
+
+CREATE INDEX IF NOT EXISTS jobs_account_end_time_idx ON jobs (account, end_time);
+
+CREATE INDEX IF NOT EXISTS jobs_user_name_end_time_idx ON jobs (user_name, end_time);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS gpu_gpu_type_time_idx;
+
+ALTER TABLE gpu DROP COLUMN gpu_type;
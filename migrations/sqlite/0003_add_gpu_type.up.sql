@@ -0,0 +1,3 @@
+ALTER TABLE gpu ADD COLUMN gpu_type TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX IF NOT EXISTS gpu_gpu_type_time_idx ON gpu (gpu_type, time);
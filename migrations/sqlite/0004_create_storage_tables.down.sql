@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS quotas;
+
+DROP TABLE IF EXISTS storage;
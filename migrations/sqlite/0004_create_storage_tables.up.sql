@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS storage (
+    time TIMESTAMP NOT NULL,
+    filesystem TEXT NOT NULL,
+    used_bytes BIGINT NOT NULL,
+    capacity_bytes BIGINT NOT NULL,
+    inodes_used BIGINT NOT NULL,
+    inodes_total BIGINT NOT NULL,
+    PRIMARY KEY (filesystem, time)
+);
+
+CREATE INDEX IF NOT EXISTS storage_time_idx ON storage (time);
+
+CREATE TABLE IF NOT EXISTS quotas (
+    time TIMESTAMP NOT NULL,
+    filesystem TEXT NOT NULL,
+    group_name TEXT NOT NULL,
+    used_bytes BIGINT NOT NULL,
+    limit_bytes BIGINT,
+    inodes_used BIGINT NOT NULL,
+    inode_limit BIGINT,
+    PRIMARY KEY (filesystem, group_name, time)
+);
+
+CREATE INDEX IF NOT EXISTS quotas_time_idx ON quotas (time);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS fairshare (
+    time TIMESTAMP NOT NULL,
+    account TEXT NOT NULL,
+    user_name TEXT NOT NULL DEFAULT '',
+    raw_shares BIGINT NOT NULL,
+    effective_usage DOUBLE PRECISION NOT NULL,
+    fairshare DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (account, user_name, time)
+);
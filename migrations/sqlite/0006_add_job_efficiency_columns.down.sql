@@ -0,0 +1,9 @@
+DROP INDEX IF EXISTS jobs_partition_end_time_idx;
+
+ALTER TABLE jobs DROP COLUMN max_rss_bytes;
+
+ALTER TABLE jobs DROP COLUMN mem_requested_bytes;
+
+ALTER TABLE jobs DROP COLUMN cpu_time_seconds;
+
+ALTER TABLE jobs DROP COLUMN elapsed_seconds;
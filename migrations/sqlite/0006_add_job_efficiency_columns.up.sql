@@ -0,0 +1,9 @@
+ALTER TABLE jobs ADD COLUMN elapsed_seconds BIGINT;
+
+ALTER TABLE jobs ADD COLUMN cpu_time_seconds BIGINT;
+
+ALTER TABLE jobs ADD COLUMN mem_requested_bytes BIGINT;
+
+ALTER TABLE jobs ADD COLUMN max_rss_bytes BIGINT;
+
+CREATE INDEX IF NOT EXISTS jobs_partition_end_time_idx ON jobs (partition, end_time);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS gpu_time_gpu_type_key;
+
+DROP INDEX IF EXISTS cpu_time_key;
@@ -0,0 +1,3 @@
+CREATE UNIQUE INDEX IF NOT EXISTS cpu_time_key ON cpu (time);
+
+CREATE UNIQUE INDEX IF NOT EXISTS gpu_time_gpu_type_key ON gpu (time, gpu_type);
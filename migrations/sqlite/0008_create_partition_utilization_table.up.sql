@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS partition_utilization (
+    time TIMESTAMP NOT NULL,
+    partition TEXT NOT NULL,
+    resource TEXT NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL,
+    PRIMARY KEY (time, partition, resource)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS quarantine (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    resource TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    allocated INTEGER NOT NULL,
+    total INTEGER NOT NULL,
+    gpu_type TEXT NOT NULL DEFAULT '',
+    reason TEXT NOT NULL,
+    quarantined_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS quarantine_resource_time_idx ON quarantine (resource, time);
@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS gpu_rollup_dirty_delete;
+DROP TRIGGER IF EXISTS gpu_rollup_dirty_update;
+DROP TRIGGER IF EXISTS gpu_rollup_dirty_insert;
+DROP TRIGGER IF EXISTS cpu_rollup_dirty_delete;
+DROP TRIGGER IF EXISTS cpu_rollup_dirty_update;
+DROP TRIGGER IF EXISTS cpu_rollup_dirty_insert;
+DROP TABLE IF EXISTS rollup_dirty;
+DROP TABLE IF EXISTS gpu_type_rollup;
+DROP TABLE IF EXISTS rollup;
@@ -0,0 +1,73 @@
+CREATE TABLE IF NOT EXISTS rollup (
+    resource TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    allocated_sum BIGINT NOT NULL,
+    total_sum BIGINT NOT NULL,
+    samples BIGINT NOT NULL,
+    PRIMARY KEY (resource, bucket, time)
+);
+
+CREATE TABLE IF NOT EXISTS gpu_type_rollup (
+    bucket TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    gpu_type TEXT NOT NULL,
+    allocated_sum BIGINT NOT NULL,
+    total_sum BIGINT NOT NULL,
+    samples BIGINT NOT NULL,
+    PRIMARY KEY (bucket, time, gpu_type)
+);
+
+CREATE TABLE IF NOT EXISTS rollup_dirty (
+    resource TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    PRIMARY KEY (resource, bucket, time)
+);
+
+-- SQLite triggers fire on one event each, and its times are text.
+CREATE TRIGGER IF NOT EXISTS cpu_rollup_dirty_insert AFTER INSERT ON cpu
+BEGIN
+    INSERT OR IGNORE INTO rollup_dirty (resource, bucket, time) VALUES
+    ('cpu', 'hour', strftime('%Y-%m-%d %H:00:00', NEW.time)),
+    ('cpu', 'day', strftime('%Y-%m-%d 00:00:00', NEW.time));
+END;
+
+CREATE TRIGGER IF NOT EXISTS cpu_rollup_dirty_update AFTER UPDATE ON cpu
+BEGIN
+    INSERT OR IGNORE INTO rollup_dirty (resource, bucket, time) VALUES
+    ('cpu', 'hour', strftime('%Y-%m-%d %H:00:00', OLD.time)),
+    ('cpu', 'day', strftime('%Y-%m-%d 00:00:00', OLD.time)),
+    ('cpu', 'hour', strftime('%Y-%m-%d %H:00:00', NEW.time)),
+    ('cpu', 'day', strftime('%Y-%m-%d 00:00:00', NEW.time));
+END;
+
+CREATE TRIGGER IF NOT EXISTS cpu_rollup_dirty_delete AFTER DELETE ON cpu
+BEGIN
+    INSERT OR IGNORE INTO rollup_dirty (resource, bucket, time) VALUES
+    ('cpu', 'hour', strftime('%Y-%m-%d %H:00:00', OLD.time)),
+    ('cpu', 'day', strftime('%Y-%m-%d 00:00:00', OLD.time));
+END;
+
+CREATE TRIGGER IF NOT EXISTS gpu_rollup_dirty_insert AFTER INSERT ON gpu
+BEGIN
+    INSERT OR IGNORE INTO rollup_dirty (resource, bucket, time) VALUES
+    ('gpu', 'hour', strftime('%Y-%m-%d %H:00:00', NEW.time)),
+    ('gpu', 'day', strftime('%Y-%m-%d 00:00:00', NEW.time));
+END;
+
+CREATE TRIGGER IF NOT EXISTS gpu_rollup_dirty_update AFTER UPDATE ON gpu
+BEGIN
+    INSERT OR IGNORE INTO rollup_dirty (resource, bucket, time) VALUES
+    ('gpu', 'hour', strftime('%Y-%m-%d %H:00:00', OLD.time)),
+    ('gpu', 'day', strftime('%Y-%m-%d 00:00:00', OLD.time)),
+    ('gpu', 'hour', strftime('%Y-%m-%d %H:00:00', NEW.time)),
+    ('gpu', 'day', strftime('%Y-%m-%d 00:00:00', NEW.time));
+END;
+
+CREATE TRIGGER IF NOT EXISTS gpu_rollup_dirty_delete AFTER DELETE ON gpu
+BEGIN
+    INSERT OR IGNORE INTO rollup_dirty (resource, bucket, time) VALUES
+    ('gpu', 'hour', strftime('%Y-%m-%d %H:00:00', OLD.time)),
+    ('gpu', 'day', strftime('%Y-%m-%d 00:00:00', OLD.time));
+END;
@@ -0,0 +1,2 @@
+-- SQLite has no table partitioning, so the sample tables stay as they are.
+SELECT 1;
@@ -0,0 +1,2 @@
+-- SQLite has no roles, so there is nothing to grant.
+SELECT 1;
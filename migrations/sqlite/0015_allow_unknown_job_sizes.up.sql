@@ -0,0 +1,33 @@
+-- SQLite can't drop NOT NULL in place, so the table is rebuilt.
+
+CREATE TABLE jobs_new (
+    job_id TEXT PRIMARY KEY,
+    account TEXT NOT NULL,
+    user_name TEXT NOT NULL,
+    partition TEXT,
+    submit_time TIMESTAMP,
+    start_time TIMESTAMP,
+    end_time TIMESTAMP,
+    cpus INTEGER DEFAULT 0,
+    gpus INTEGER DEFAULT 0,
+    state TEXT,
+    elapsed_seconds BIGINT,
+    cpu_time_seconds BIGINT,
+    mem_requested_bytes BIGINT,
+    max_rss_bytes BIGINT
+);
+
+INSERT INTO jobs_new
+SELECT job_id, account, user_name, partition, submit_time, start_time, end_time,
+    cpus, gpus, state, elapsed_seconds, cpu_time_seconds, mem_requested_bytes, max_rss_bytes
+FROM jobs;
+
+DROP TABLE jobs;
+
+ALTER TABLE jobs_new RENAME TO jobs;
+
+CREATE INDEX IF NOT EXISTS jobs_account_end_time_idx ON jobs (account, end_time);
+
+CREATE INDEX IF NOT EXISTS jobs_user_name_end_time_idx ON jobs (user_name, end_time);
+
+CREATE INDEX IF NOT EXISTS jobs_partition_end_time_idx ON jobs (partition, end_time);
@@ -1,15 +1,8 @@
 -- sql/create_service_account.sql
 
+-- Run this before `elmo-api migrate up`. The migrations create the oscar schema and grant
+-- this role access to each table they add, but only once the role exists.
 
 CREATE ROLE elmo_app WITH LOGIN PASSWORD 'password';
 
 GRANT CONNECT ON DATABASE elmo TO elmo_app;
-
-\c elmo
-
-GRANT USAGE ON SCHEMA oscar TO elmo_app;
-
-GRANT SELECT ON ALL TABLES IN SCHEMA oscar TO elmo_app;
-
-ALTER DEFAULT PRIVILEGES IN SCHEMA oscar GRANT SELECT ON TABLES TO elmo_app;
-
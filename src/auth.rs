@@ -0,0 +1,170 @@
+use std::env;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+/// Bearer tokens allowed to call the write endpoints. With no tokens configured every
+/// write is rejected, so a deployment is read-only until someone opts in.
+#[derive(Debug, Clone, Default)]
+pub struct IngestTokens(Arc<Vec<String>>);
+
+impl IngestTokens {
+    pub fn new(tokens: Vec<String>) -> Self {
+        IngestTokens(Arc::new(
+            tokens
+                .into_iter()
+                .filter(|token| !token.is_empty())
+                .collect(),
+        ))
+    }
+
+    /// Reads the comma-separated `INGEST_TOKENS` environment variable.
+    pub fn from_env() -> Self {
+        let tokens = env::var("INGEST_TOKENS").unwrap_or_default();
+
+        Self::new(tokens.split(',').map(|t| t.trim().to_string()).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn allows(&self, candidate: &str) -> bool {
+        self.0
+            .iter()
+            .any(|token| constant_time_eq(token.as_bytes(), candidate.as_bytes()))
+    }
+}
+
+/// Compares two byte strings without exiting early on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Extracts the token from `Bearer <token>`, or the password from `Basic` credentials.
+/// Basic auth is accepted because Slurm's jobcomp plugins can only send credentials
+/// embedded in their URL (`http://elmo:<token>@host/jobcomp`).
+fn credentials(value: &str) -> Option<String> {
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Some(token.trim().to_string());
+    }
+
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = BASE64_STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (_user, password) = decoded.split_once(':')?;
+
+    Some(password.to_string())
+}
+
+/// Bearer tokens allowed to call the `/admin` endpoints, read from `ADMIN_TOKENS`. They
+/// are kept apart from ingest tokens so a collector can't release or delete data.
+#[derive(Debug, Clone, Default)]
+pub struct AdminTokens(IngestTokens);
+
+impl AdminTokens {
+    pub fn new(tokens: Vec<String>) -> Self {
+        AdminTokens(IngestTokens::new(tokens))
+    }
+
+    /// Reads the comma-separated `ADMIN_TOKENS` environment variable.
+    pub fn from_env() -> Self {
+        let tokens = env::var("ADMIN_TOKENS").unwrap_or_default();
+
+        Self::new(tokens.split(',').map(|t| t.trim().to_string()).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn allows(&self, candidate: &str) -> bool {
+        self.0.allows(candidate)
+    }
+}
+
+fn request_token(request: &Request) -> Option<String> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(credentials)
+}
+
+/// Middleware for the write endpoints: requires `Authorization: Bearer <token>` (or
+/// Basic auth with the token as password) with one of the configured ingest tokens.
+pub async fn require_ingest_token(
+    State(tokens): State<IngestTokens>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    match request_token(&request) {
+        Some(token) if tokens.allows(&token) => Ok(next.run(request).await),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Middleware for the `/admin` endpoints, like `require_ingest_token` but checking the
+/// admin tokens.
+pub async fn require_admin_token(
+    State(tokens): State<AdminTokens>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    match request_token(&request) {
+        Some(token) if tokens.allows(&token) => Ok(next.run(request).await),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_allow_only_configured_values() {
+        let tokens = IngestTokens::new(vec!["secret".to_string(), String::new()]);
+
+        assert!(tokens.allows("secret"));
+        assert!(!tokens.allows("secre"));
+        assert!(!tokens.allows(""));
+    }
+
+    #[test]
+    fn test_credentials() {
+        assert_eq!(credentials("Bearer secret").as_deref(), Some("secret"));
+        // base64("slurm:secret")
+        assert_eq!(
+            credentials("Basic c2x1cm06c2VjcmV0").as_deref(),
+            Some("secret")
+        );
+        assert_eq!(credentials("Basic not-base64"), None);
+        assert_eq!(credentials("Token secret"), None);
+    }
+
+    #[test]
+    fn test_admin_tokens_are_separate() {
+        let tokens = AdminTokens::new(vec!["root".to_string()]);
+
+        assert!(tokens.allows("root"));
+        assert!(!AdminTokens::default().allows("root"));
+    }
+
+    #[test]
+    fn test_no_tokens_allow_nothing() {
+        let tokens = IngestTokens::default();
+
+        assert!(tokens.is_empty());
+        assert!(!tokens.allows(""));
+    }
+}
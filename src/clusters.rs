@@ -0,0 +1,464 @@
+use chrono::NaiveDateTime;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::postgres::{PgConnectOptions, PgPool};
+
+use crate::replicas::Replicas;
+use crate::routes::{
+    fetch_utilization, utilization_response, Bucket, GpuFilter, GpuGrouping, Resource, TimeRange,
+    Utilization,
+};
+use crate::timeouts;
+
+/// Name of the cluster used when `ELMO_CLUSTERS` is not set, and by the routes that
+/// predate multi-cluster support (`/cpu`, `/gpu/hourly`, ...).
+pub const DEFAULT_CLUSTER: &str = "oscar";
+
+/// Pseudo-cluster that sums utilization across every registered cluster.
+pub const ALL_CLUSTERS: &str = "all";
+
+/// The tables every cluster schema needs; the migrations create them in `oscar`.
+pub const CLUSTER_TABLES: [&str; 12] = [
+    "cpu",
+    "gpu",
+    "jobs",
+    "storage",
+    "quotas",
+    "fairshare",
+    "partition_utilization",
+    "import_state",
+    "quarantine",
+    "rollup",
+    "gpu_type_rollup",
+    "rollup_dirty",
+];
+
+/// A cluster and where its utilization tables live.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub name: String,
+    pub schema: String,
+    pub pool: PgPool,
+    /// Read replicas of `pool`; only clusters in the main database have any.
+    pub replicas: Replicas,
+}
+
+impl Cluster {
+    /// The pool to run read-only queries on, a replica where one is usable.
+    pub fn read_pool(&self) -> PgPool {
+        self.replicas.read_pool(&self.pool)
+    }
+
+    /// The tables of `CLUSTER_TABLES` the cluster's schema lacks.
+    pub async fn missing_tables(&self) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar(
+            "SELECT name FROM unnest($1::text[]) AS name \
+             WHERE to_regclass(format('%I.%I', $2::text, name)) IS NULL",
+        )
+        .bind(CLUSTER_TABLES)
+        .bind(&self.schema)
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ClusterInfo {
+    pub name: String,
+    pub schema: String,
+}
+
+/// One entry of `ELMO_CLUSTERS`, before any connection is made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterSpec {
+    pub name: String,
+    pub schema: String,
+    pub database_url: Option<String>,
+}
+
+/// The clusters this service reports on. The first cluster is the default one.
+#[derive(Debug)]
+pub struct ClusterRegistry {
+    clusters: Vec<Cluster>,
+}
+
+/// Schema names are interpolated into SQL, so only plain identifiers are allowed.
+fn is_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_cluster_name(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Parses a cluster list such as `oscar=oscar,hydra=hydra@postgres://elmo@hydra-db/elmo`.
+///
+/// Each entry maps a cluster name to a schema. Clusters without a database URL live in
+/// the main database; the others get their own connection pool.
+pub fn parse_cluster_specs(value: &str) -> Result<Vec<ClusterSpec>> {
+    let mut specs: Vec<ClusterSpec> = Vec::new();
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((name, target)) = entry.split_once('=') else {
+            bail!("cluster entry `{entry}` must look like name=schema or name=schema@url");
+        };
+        let name = name.trim();
+        let (schema, database_url) = match target.split_once('@') {
+            Some((schema, url)) => (schema.trim(), Some(url.trim().to_string())),
+            None => (target.trim(), None),
+        };
+
+        if !is_cluster_name(name) || name == ALL_CLUSTERS {
+            bail!("invalid cluster name `{name}`");
+        }
+        if !is_identifier(schema) {
+            bail!("invalid schema `{schema}` for cluster `{name}`");
+        }
+        if specs.iter().any(|spec| spec.name == name) {
+            bail!("cluster `{name}` is listed more than once");
+        }
+
+        specs.push(ClusterSpec {
+            name: name.to_string(),
+            schema: schema.to_string(),
+            database_url,
+        });
+    }
+
+    if specs.is_empty() {
+        bail!("no clusters configured");
+    }
+
+    Ok(specs)
+}
+
+impl ClusterRegistry {
+    /// A registry with only the default `oscar` cluster on `pool`.
+    pub fn single(pool: PgPool) -> Self {
+        ClusterRegistry {
+            clusters: vec![Cluster {
+                name: DEFAULT_CLUSTER.to_string(),
+                schema: DEFAULT_CLUSTER.to_string(),
+                pool,
+                replicas: Replicas::default(),
+            }],
+        }
+    }
+
+    /// Builds the registry from parsed specs. Clusters in other databases get a lazy
+    /// pool, so an unreachable cluster doesn't stop the others from being served.
+    /// Clusters in the main database read from its `replicas`.
+    pub fn from_specs(specs: Vec<ClusterSpec>, pool: PgPool, replicas: &Replicas) -> Result<Self> {
+        let clusters = specs
+            .into_iter()
+            .map(|spec| {
+                let (pool, replicas) = match &spec.database_url {
+                    Some(url) => {
+                        let options: PgConnectOptions = url.parse().with_context(|| {
+                            format!("invalid database url for cluster {}", spec.name)
+                        })?;
+                        (
+                            timeouts::pool_options(&options).connect_lazy_with(options),
+                            Replicas::default(),
+                        )
+                    }
+                    None => (pool.clone(), replicas.clone()),
+                };
+                Ok(Cluster {
+                    name: spec.name,
+                    schema: spec.schema,
+                    pool,
+                    replicas,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ClusterRegistry { clusters })
+    }
+
+    /// Reads `ELMO_CLUSTERS`, falling back to the single `oscar` cluster.
+    pub fn from_env(pool: PgPool, replicas: &Replicas) -> Result<Self> {
+        let specs = match env::var("ELMO_CLUSTERS") {
+            Ok(value) => parse_cluster_specs(&value)?,
+            Err(_) => vec![ClusterSpec {
+                name: DEFAULT_CLUSTER.to_string(),
+                schema: DEFAULT_CLUSTER.to_string(),
+                database_url: None,
+            }],
+        };
+
+        Self::from_specs(specs, pool, replicas)
+    }
+
+    pub fn default_cluster(&self) -> &Cluster {
+        &self.clusters[0]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Cluster> {
+        self.clusters.iter().find(|cluster| cluster.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cluster> {
+        self.clusters.iter()
+    }
+
+    /// Fails when a cluster's schema lacks any of `CLUSTER_TABLES`. A cluster whose
+    /// database can't be reached is only warned about, as it is served anyway.
+    pub async fn check_tables(&self) -> Result<()> {
+        for cluster in &self.clusters {
+            let missing = match cluster.missing_tables().await {
+                Ok(missing) => missing,
+                Err(e @ (sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut)) => {
+                    tracing::warn!(
+                        "Could not check the tables of cluster {}: {:?}",
+                        cluster.name,
+                        e
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("failed to check the tables of cluster {}", cluster.name)
+                    })
+                }
+            };
+            if !missing.is_empty() {
+                bail!(
+                    "schema `{}` of cluster `{}` is missing the tables {}; create them like the `oscar` ones",
+                    cluster.schema,
+                    cluster.name,
+                    missing.join(", ")
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Selects a cluster with `?cluster=`; every endpoint that reads or writes a single
+/// cluster's tables accepts it.
+#[derive(Debug, serde::Deserialize)]
+pub struct ClusterQuery {
+    /// Defaults to the first configured cluster.
+    pub cluster: Option<String>,
+}
+
+/// Looks up `name`, or the default cluster when it is `None`.
+pub fn find_cluster<'a>(
+    clusters: &'a ClusterRegistry,
+    name: Option<&str>,
+) -> Result<&'a Cluster, (StatusCode, String)> {
+    match name {
+        Some(name) => clusters
+            .get(name)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, format!("unknown cluster `{name}`"))),
+        None => Ok(clusters.default_cluster()),
+    }
+}
+
+/// Sums bucketed utilization from several clusters. A bucket is only reported once
+/// every cluster has a value for it, so a lagging cluster shows up as a shorter series
+/// rather than as a drop in capacity.
+fn sum_aligned(series: Vec<Vec<Utilization>>) -> Vec<Utilization> {
+    let mut buckets: BTreeMap<NaiveDateTime, (usize, i64, i64)> = BTreeMap::new();
+
+    for utilization in series.iter().flatten() {
+        if let Some(time) = utilization.time {
+            let bucket = buckets.entry(time).or_default();
+            bucket.0 += 1;
+            bucket.1 += i64::from(utilization.allocated.unwrap_or(0));
+            bucket.2 += i64::from(utilization.total.unwrap_or(0));
+        }
+    }
+
+    buckets
+        .into_iter()
+        .filter(|(_, (count, _, _))| *count == series.len())
+        .map(|(time, (_, allocated, total))| Utilization {
+            time: Some(time),
+            allocated: i32::try_from(allocated).ok(),
+            total: i32::try_from(total).ok(),
+        })
+        .collect()
+}
+
+pub async fn get_clusters(State(clusters): State<Arc<ClusterRegistry>>) -> Json<Vec<ClusterInfo>> {
+    let clusters = clusters
+        .iter()
+        .map(|cluster| ClusterInfo {
+            name: cluster.name.clone(),
+            schema: cluster.schema.clone(),
+        })
+        .collect();
+
+    Json(clusters)
+}
+
+async fn cluster_utilization(
+    clusters: &ClusterRegistry,
+    name: &str,
+    resource: Resource,
+    bucket: Option<Bucket>,
+    time_range: TimeRange,
+    filter: GpuFilter,
+) -> Result<Response, (StatusCode, String)> {
+    if name != ALL_CLUSTERS {
+        let Some(cluster) = clusters.get(name) else {
+            return Err((StatusCode::NOT_FOUND, format!("unknown cluster `{name}`")));
+        };
+
+        return utilization_response(cluster, resource, bucket, &time_range, &filter)
+            .await
+            .map_err(|e| {
+                tracing::error!(
+                    "Error: failed to get {:?} utilization for {}: {:?}",
+                    resource,
+                    name,
+                    e
+                );
+                (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+            });
+    }
+
+    let Some(bucket) = bucket else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "raw samples can't be aligned across clusters, use /hourly or /daily".to_string(),
+        ));
+    };
+    if filter.group_by == Some(GpuGrouping::GpuType) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "group_by is not supported for the all cluster".to_string(),
+        ));
+    }
+
+    let gpu_type = filter.gpu_type.as_deref();
+    let series = futures::future::try_join_all(clusters.iter().map(|cluster| async {
+        fetch_utilization(
+            &cluster.read_pool(),
+            &cluster.schema,
+            resource,
+            Some(bucket),
+            &time_range,
+            gpu_type,
+        )
+        .await
+    }))
+    .await
+    .map_err(|e| {
+        tracing::error!(
+            "Error: failed to get {:?} utilization for all clusters: {:?}",
+            resource,
+            e
+        );
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    Ok(Json(sum_aligned(series)).into_response())
+}
+
+pub async fn get_cluster_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Path((cluster, resource)): Path<(String, Resource)>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<GpuFilter>,
+) -> Result<Response, (StatusCode, String)> {
+    cluster_utilization(&clusters, &cluster, resource, None, time_range, filter).await
+}
+
+pub async fn get_bucketed_cluster_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Path((cluster, resource, bucket)): Path<(String, Resource, Bucket)>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<GpuFilter>,
+) -> Result<Response, (StatusCode, String)> {
+    cluster_utilization(
+        &clusters,
+        &cluster,
+        resource,
+        Some(bucket),
+        time_range,
+        filter,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utilization(time: &str, allocated: i32, total: i32) -> Utilization {
+        Utilization {
+            time: Some(time.parse().unwrap()),
+            allocated: Some(allocated),
+            total: Some(total),
+        }
+    }
+
+    #[test]
+    fn test_parse_cluster_specs() {
+        let specs =
+            parse_cluster_specs("oscar=oscar, hydra=hydra@postgres://elmo@hydra-db/elmo").unwrap();
+
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].name, "oscar");
+        assert_eq!(specs[0].database_url, None);
+        assert_eq!(specs[1].schema, "hydra");
+        assert_eq!(
+            specs[1].database_url.as_deref(),
+            Some("postgres://elmo@hydra-db/elmo")
+        );
+    }
+
+    #[test]
+    fn test_parse_cluster_specs_rejects_bad_entries() {
+        assert!(parse_cluster_specs("").is_err());
+        assert!(parse_cluster_specs("oscar").is_err());
+        assert!(parse_cluster_specs("all=oscar").is_err());
+        assert!(parse_cluster_specs("oscar=oscar; DROP TABLE x").is_err());
+        assert!(parse_cluster_specs("oscar=oscar,oscar=other").is_err());
+    }
+
+    #[test]
+    fn test_sum_aligned_only_keeps_shared_buckets() {
+        let oscar = vec![
+            utilization("2024-03-27T00:00:00", 75, 100),
+            utilization("2024-03-27T01:00:00", 80, 100),
+        ];
+        let hydra = vec![utilization("2024-03-27T01:00:00", 10, 50)];
+
+        let summed = sum_aligned(vec![oscar, hydra]);
+
+        assert_eq!(summed.len(), 1);
+        assert_eq!(summed[0].time, Some("2024-03-27T01:00:00".parse().unwrap()));
+        assert_eq!(summed[0].allocated, Some(90));
+        assert_eq!(summed[0].total, Some(150));
+    }
+
+    #[tokio::test]
+    async fn test_registry_lookup() {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let specs = parse_cluster_specs("oscar=oscar,hydra=hydra").unwrap();
+        let registry = ClusterRegistry::from_specs(specs, pool, &Replicas::default()).unwrap();
+
+        assert_eq!(registry.default_cluster().name, "oscar");
+        assert_eq!(registry.get("hydra").unwrap().schema, "hydra");
+        assert!(registry.get("missing").is_none());
+    }
+}
@@ -0,0 +1,641 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use csv::StringRecord;
+
+use crate::clusters::{Cluster, ClusterRegistry};
+use crate::ingest::{prepare_batch, upsert_samples, Sample};
+use crate::metrics::time_from_millis;
+use crate::quality;
+use crate::routes::Resource;
+
+/// Formats tried, after RFC 3339, when no `--time-format` is given.
+const AUTO_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+/// How many skipped rows are logged individually before only being counted.
+const LOGGED_SKIPS: usize = 20;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ImportArgs {
+    /// CSV file to import. The first row must be a header.
+    pub input: PathBuf,
+
+    /// Resource table the rows are written to.
+    #[arg(long, value_enum)]
+    pub resource: Resource,
+
+    /// Cluster to write samples to. Defaults to the first configured cluster.
+    #[arg(long)]
+    pub cluster: Option<String>,
+
+    /// Column holding the sample time.
+    #[arg(long, default_value = "time")]
+    pub time_column: String,
+
+    /// Column holding the allocated count.
+    #[arg(long, default_value = "allocated")]
+    pub allocated_column: String,
+
+    /// Column holding the total count.
+    #[arg(long, default_value = "total")]
+    pub total_column: String,
+
+    /// Column holding the GPU model. Defaults to `gpu_type` when the file has one.
+    #[arg(long)]
+    pub gpu_type_column: Option<String>,
+
+    /// GPU model for every row, for files without a GPU model column.
+    #[arg(long, conflicts_with = "gpu_type_column")]
+    pub gpu_type: Option<String>,
+
+    /// `auto`, `epoch` (seconds), `epoch_ms`, or a strftime format such as
+    /// `%m/%d/%Y %H:%M`.
+    #[arg(long, default_value = "auto")]
+    pub time_format: TimeFormat,
+
+    /// IANA time zone of timestamps without an offset, e.g. `America/New_York`.
+    /// Defaults to the server's local time, which is how samples are stored.
+    #[arg(long)]
+    pub timezone: Option<Tz>,
+
+    /// Field delimiter.
+    #[arg(long, default_value_t = ',')]
+    pub delimiter: char,
+
+    /// Rows written per transaction.
+    #[arg(long, default_value_t = 5000)]
+    pub batch_size: usize,
+
+    /// Parse and validate the file without writing anything.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// File recording how far the import got. Defaults to `<input>.checkpoint`.
+    #[arg(long)]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Ignore an existing checkpoint and start from the first row.
+    #[arg(long)]
+    pub restart: bool,
+
+    /// Write every skipped row's line number and reason to this CSV file.
+    #[arg(long)]
+    pub skipped: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeFormat {
+    Auto,
+    Epoch,
+    EpochMillis,
+    Custom(String),
+}
+
+impl std::str::FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "auto" => TimeFormat::Auto,
+            "epoch" => TimeFormat::Epoch,
+            "epoch_ms" => TimeFormat::EpochMillis,
+            "" => return Err("time format must not be empty".to_string()),
+            _ => TimeFormat::Custom(value.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum GpuTypeSource {
+    None,
+    Column(usize),
+    Fixed(String),
+}
+
+/// Turns CSV records into samples, using the column mapping and time options.
+#[derive(Debug, Clone)]
+pub struct RowParser {
+    time: usize,
+    allocated: usize,
+    total: usize,
+    gpu_type: GpuTypeSource,
+    time_format: TimeFormat,
+    timezone: Option<Tz>,
+}
+
+fn column(headers: &StringRecord, name: &str) -> Option<usize> {
+    headers.iter().position(|header| header.trim() == name)
+}
+
+fn required_column(headers: &StringRecord, name: &str) -> Result<usize> {
+    column(headers, name).ok_or_else(|| {
+        anyhow!(
+            "column `{name}` not found, the header has: {}",
+            headers.iter().collect::<Vec<_>>().join(", ")
+        )
+    })
+}
+
+fn parse_count(value: &str, name: &str) -> Result<i32, String> {
+    let value = value.trim();
+    if let Ok(count) = value.parse::<i32>() {
+        return Ok(count);
+    }
+
+    // Some exports write counts as floats (`75.0`).
+    match value.parse::<f64>() {
+        Ok(count) if count.fract() == 0.0 && count.abs() <= f64::from(i32::MAX) => Ok(count as i32),
+        _ => Err(format!("invalid {name} `{value}`")),
+    }
+}
+
+impl RowParser {
+    pub fn new(headers: &StringRecord, args: &ImportArgs) -> Result<Self> {
+        let gpu_type = match (&args.gpu_type_column, &args.gpu_type) {
+            _ if args.resource != Resource::Gpu => GpuTypeSource::None,
+            (Some(name), _) => GpuTypeSource::Column(required_column(headers, name)?),
+            (None, Some(gpu_type)) => GpuTypeSource::Fixed(gpu_type.clone()),
+            (None, None) => column(headers, "gpu_type")
+                .map(GpuTypeSource::Column)
+                .unwrap_or(GpuTypeSource::None),
+        };
+
+        Ok(RowParser {
+            time: required_column(headers, &args.time_column)?,
+            allocated: required_column(headers, &args.allocated_column)?,
+            total: required_column(headers, &args.total_column)?,
+            gpu_type,
+            time_format: args.time_format.clone(),
+            timezone: args.timezone,
+        })
+    }
+
+    /// Interprets a time without an offset in the configured time zone. Times that
+    /// repeat when clocks go back resolve to the earlier one.
+    fn localize(&self, time: NaiveDateTime) -> Result<NaiveDateTime, String> {
+        let Some(timezone) = self.timezone else {
+            return Ok(time);
+        };
+
+        timezone
+            .from_local_datetime(&time)
+            .earliest()
+            .map(quality::stored_time)
+            .ok_or_else(|| format!("time {time} does not exist in {timezone}"))
+    }
+
+    fn parse_time(&self, value: &str) -> Result<NaiveDateTime, String> {
+        let value = value.trim();
+        let invalid = || format!("invalid time `{value}`");
+
+        match &self.time_format {
+            TimeFormat::Epoch | TimeFormat::EpochMillis => {
+                let number = value.parse::<f64>().map_err(|_| invalid())?;
+                let millis = match self.time_format {
+                    TimeFormat::Epoch => number * 1000.0,
+                    _ => number,
+                };
+                time_from_millis(millis.round() as i64).ok_or_else(invalid)
+            }
+            TimeFormat::Auto => {
+                if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+                    return Ok(quality::stored_time(time));
+                }
+                let time = AUTO_FORMATS
+                    .iter()
+                    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+                    .ok_or_else(invalid)?;
+                self.localize(time)
+            }
+            TimeFormat::Custom(format) => {
+                if let Ok(time) = DateTime::parse_from_str(value, format) {
+                    return Ok(quality::stored_time(time));
+                }
+                let time = NaiveDateTime::parse_from_str(value, format).map_err(|_| invalid())?;
+                self.localize(time)
+            }
+        }
+    }
+
+    pub fn parse(&self, record: &StringRecord) -> Result<Sample, String> {
+        let field = |index: usize, name: &str| {
+            record
+                .get(index)
+                .ok_or_else(|| format!("row has no {name} column"))
+        };
+
+        let gpu_type = match &self.gpu_type {
+            GpuTypeSource::None => None,
+            GpuTypeSource::Column(index) => Some(field(*index, "gpu type")?.trim().to_string()),
+            GpuTypeSource::Fixed(gpu_type) => Some(gpu_type.clone()),
+        };
+
+        Ok(Sample {
+            time: Some(self.parse_time(field(self.time, "time")?)?),
+            allocated: Some(parse_count(
+                field(self.allocated, "allocated")?,
+                "allocated",
+            )?),
+            total: Some(parse_count(field(self.total, "total")?, "total")?),
+            gpu_type,
+        })
+    }
+}
+
+/// Number of data rows already imported from an input file, kept next to it so an
+/// interrupted import continues where it stopped.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(input: &Path, path: Option<PathBuf>) -> Self {
+        let path = path.unwrap_or_else(|| {
+            let mut path = input.as_os_str().to_owned();
+            path.push(".checkpoint");
+            PathBuf::from(path)
+        });
+        Checkpoint { path }
+    }
+
+    pub fn load(&self) -> Result<u64> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid checkpoint in {}", self.path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", self.path.display())),
+        }
+    }
+
+    /// Written through a temporary file so a crash never leaves a torn checkpoint.
+    pub fn store(&self, rows: u64) -> Result<()> {
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+
+        fs::write(&temporary, format!("{rows}\n"))
+            .and_then(|_| fs::rename(&temporary, &self.path))
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {}", self.path.display())),
+        }
+    }
+}
+
+/// Rows imported, quarantined and skipped so far. Skipped and quarantined rows are
+/// logged (the first few) and written to `--skipped` with their reason.
+#[derive(Default)]
+struct ImportSummary {
+    imported: usize,
+    quarantined: usize,
+    skipped: usize,
+    noted: usize,
+    skipped_rows: Option<csv::Writer<fs::File>>,
+}
+
+impl ImportSummary {
+    fn note(&mut self, line: u64, reason: &str) -> Result<()> {
+        self.noted += 1;
+        if self.noted <= LOGGED_SKIPS {
+            tracing::warn!("Line {}: {}", line, reason);
+        }
+        if let Some(writer) = &mut self.skipped_rows {
+            writer
+                .write_record([line.to_string().as_str(), reason])
+                .context("failed to write skipped rows")?;
+        }
+        Ok(())
+    }
+}
+
+/// `prepare_batch` refers to other rows by their position in the batch; point to the
+/// file's line numbers instead.
+fn with_line_numbers(reason: &str, lines: &[u64]) -> String {
+    let Some((head, row)) = reason.split_once("superseded by row ") else {
+        return reason.to_string();
+    };
+    match row.parse::<usize>().ok().and_then(|row| lines.get(row)) {
+        Some(line) => format!("{head}superseded by line {line}"),
+        None => reason.to_string(),
+    }
+}
+
+/// Validates a batch of `(line, row)` pairs and, unless this is a dry run, writes it in
+/// one transaction.
+async fn flush(
+    cluster: &Cluster,
+    args: &ImportArgs,
+    batch: &mut Vec<(u64, Result<Sample, String>)>,
+    summary: &mut ImportSummary,
+) -> Result<()> {
+    let (lines, rows): (Vec<u64>, Vec<_>) = batch.drain(..).unzip();
+    let (samples, mut quarantined, mut report) = prepare_batch(args.resource, rows);
+
+    for entry in &mut quarantined {
+        entry.reason = with_line_numbers(&entry.reason, &lines);
+    }
+
+    if !args.dry_run {
+        upsert_samples(
+            &cluster.pool,
+            &cluster.schema,
+            args.resource,
+            &samples,
+            &quarantined,
+            &mut report,
+        )
+        .await
+        .context("failed to write samples")?;
+    }
+
+    // Noted after writing, so rows that conflict with stored samples are listed too.
+    for error in &report.errors {
+        summary.note(
+            lines[error.index],
+            &with_line_numbers(&error.reason, &lines),
+        )?;
+    }
+    summary.imported += report.accepted;
+    summary.quarantined += report.quarantined;
+    summary.skipped += report.rejected;
+
+    Ok(())
+}
+
+/// Streams a CSV file into a resource table in batches. After each batch the number of
+/// rows done is checkpointed, and samples are upserted, so re-running an interrupted
+/// import neither skips nor duplicates rows.
+pub async fn import_csv(args: ImportArgs, clusters: Arc<ClusterRegistry>) -> Result<()> {
+    let cluster = match &args.cluster {
+        Some(name) => clusters
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown cluster `{name}`"))?,
+        None => clusters.default_cluster(),
+    };
+    if args.batch_size == 0 {
+        bail!("--batch-size must be at least 1");
+    }
+    let delimiter = u8::try_from(args.delimiter)
+        .ok()
+        .filter(u8::is_ascii)
+        .ok_or_else(|| anyhow!("--delimiter must be an ASCII character"))?;
+
+    let checkpoint = Checkpoint::new(&args.input, args.checkpoint.clone());
+    let done = if args.restart || args.dry_run {
+        0
+    } else {
+        checkpoint.load()?
+    };
+    if done > 0 {
+        tracing::info!(
+            "Resuming after {} rows from {}",
+            done,
+            checkpoint.path.display()
+        );
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_path(&args.input)
+        .with_context(|| format!("failed to open {}", args.input.display()))?;
+    let parser = RowParser::new(reader.headers()?, &args)?;
+
+    let mut summary = ImportSummary {
+        skipped_rows: match &args.skipped {
+            Some(path) => {
+                let mut writer = csv::Writer::from_path(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                writer.write_record(["line", "reason"])?;
+                Some(writer)
+            }
+            None => None,
+        },
+        ..Default::default()
+    };
+    let mut batch = Vec::with_capacity(args.batch_size);
+    let mut rows = 0u64;
+
+    for record in reader.records() {
+        rows += 1;
+        if rows <= done {
+            continue;
+        }
+
+        let row = match record {
+            Ok(record) => {
+                let line = record.position().map_or(0, |position| position.line());
+                (line, parser.parse(&record))
+            }
+            Err(e) if e.is_io_error() => {
+                return Err(e).with_context(|| format!("failed to read {}", args.input.display()))
+            }
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line());
+                (line, Err(e.to_string()))
+            }
+        };
+        batch.push(row);
+
+        if batch.len() >= args.batch_size {
+            flush(cluster, &args, &mut batch, &mut summary).await?;
+            if !args.dry_run {
+                checkpoint.store(rows)?;
+            }
+        }
+    }
+    flush(cluster, &args, &mut batch, &mut summary).await?;
+
+    if let Some(writer) = &mut summary.skipped_rows {
+        writer.flush().context("failed to write skipped rows")?;
+    }
+    if !args.dry_run {
+        checkpoint.clear()?;
+    }
+
+    if summary.noted > LOGGED_SKIPS {
+        tracing::warn!(
+            "{} more skipped or quarantined rows not shown",
+            summary.noted - LOGGED_SKIPS
+        );
+    }
+    tracing::info!(
+        "{} {} {} samples from {} into {}.{}, quarantined {}, skipped {} rows",
+        if args.dry_run { "Dry run:" } else { "Done:" },
+        if args.dry_run {
+            "would import"
+        } else {
+            "imported"
+        },
+        summary.imported,
+        args.input.display(),
+        cluster.schema,
+        args.resource.table(),
+        summary.quarantined,
+        summary.skipped
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: ImportArgs,
+    }
+
+    fn args(extra: &[&str]) -> ImportArgs {
+        let mut argv = vec!["import", "backfill.csv"];
+        argv.extend_from_slice(extra);
+        Cli::try_parse_from(argv).unwrap().args
+    }
+
+    fn parse_fixture(args: &ImportArgs) -> Vec<(u64, Result<Sample, String>)> {
+        let mut reader =
+            csv::Reader::from_reader(include_str!("../../tests/fixtures/backfill.csv").as_bytes());
+        let parser = RowParser::new(reader.headers().unwrap(), args).unwrap();
+
+        reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.position().unwrap().line(), parser.parse(&record))
+            })
+            .collect()
+    }
+
+    fn local(utc: &str) -> NaiveDateTime {
+        utc.parse::<DateTime<Utc>>()
+            .unwrap()
+            .with_timezone(&chrono::Local)
+            .naive_local()
+    }
+
+    #[test]
+    fn test_column_mapping_and_timezone() {
+        let args = args(&[
+            "--resource",
+            "gpu",
+            "--time-column",
+            "timestamp",
+            "--allocated-column",
+            "gpus_used",
+            "--total-column",
+            "gpus_total",
+            "--gpu-type-column",
+            "model",
+            "--timezone",
+            "America/New_York",
+        ]);
+        let rows = parse_fixture(&args);
+
+        let first = rows[0].1.clone().unwrap();
+        assert_eq!(rows[0].0, 2);
+        assert_eq!(first.time, Some(local("2024-03-10T06:30:00Z")));
+        assert_eq!(first.gpu_type.as_deref(), Some("a100"));
+        assert_eq!(
+            rows[1].1,
+            Err("time 2024-03-10 02:30:00 does not exist in America/New_York".to_string())
+        );
+        assert_eq!(rows[3].1, Err("invalid allocated `n/a`".to_string()));
+        // An explicit offset wins over --timezone.
+        assert_eq!(
+            rows[4].1.clone().unwrap().time,
+            Some(local("2024-03-10T09:30:00Z"))
+        );
+        assert_eq!(rows[5].1.clone().unwrap().allocated, Some(3));
+
+        let lines: Vec<u64> = rows.iter().map(|(line, _)| *line).collect();
+        let (samples, quarantined, report) = prepare_batch(
+            Resource::Gpu,
+            rows.into_iter().map(|(_, row)| row).collect(),
+        );
+        assert_eq!(samples.len(), 2);
+        assert_eq!(report.rejected, 3);
+        assert_eq!(quarantined[0].reason, "allocated (5) exceeds total (4)");
+        // 05:30-04:00 and 05:30 in New York are the same sample.
+        assert_eq!(
+            with_line_numbers(&report.errors[3].reason, &lines),
+            "duplicate sample, superseded by line 7"
+        );
+    }
+
+    #[test]
+    fn test_missing_column_is_an_error() {
+        let mut reader = csv::Reader::from_reader("time,used,total\n".as_bytes());
+
+        let error =
+            RowParser::new(reader.headers().unwrap(), &args(&["--resource", "cpu"])).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "column `allocated` not found, the header has: time, used, total"
+        );
+    }
+
+    #[test]
+    fn test_time_formats() {
+        let mut reader = csv::Reader::from_reader("time,allocated,total\n".as_bytes());
+        let headers = reader.headers().unwrap().clone();
+        let parser = |extra: &[&str]| {
+            let mut argv = vec!["--resource", "cpu"];
+            argv.extend_from_slice(extra);
+            RowParser::new(&headers, &args(&argv)).unwrap()
+        };
+
+        assert_eq!(
+            parser(&["--time-format", "epoch"]).parse_time("1710052200"),
+            Ok(local("2024-03-10T06:30:00Z"))
+        );
+        assert_eq!(
+            parser(&["--time-format", "epoch_ms"]).parse_time("1710052200000"),
+            Ok(local("2024-03-10T06:30:00Z"))
+        );
+        assert_eq!(
+            parser(&["--time-format", "%m/%d/%Y %H:%M"]).parse_time("03/10/2024 01:30"),
+            Ok("2024-03-10T01:30:00".parse().unwrap())
+        );
+        assert_eq!(
+            parser(&[]).parse_time("yesterday"),
+            Err("invalid time `yesterday`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let directory = std::env::temp_dir().join(format!("elmo-backfill-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let checkpoint = Checkpoint::new(&directory.join("cpu.csv"), None);
+
+        assert_eq!(checkpoint.path, directory.join("cpu.csv.checkpoint"));
+        assert_eq!(checkpoint.load().unwrap(), 0);
+        checkpoint.store(5000).unwrap();
+        assert_eq!(checkpoint.load().unwrap(), 5000);
+        checkpoint.clear().unwrap();
+        assert_eq!(checkpoint.load().unwrap(), 0);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+pub mod backfill;
+pub mod sacct;
+pub mod sinfo;
+pub mod slurmrestd;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{NaiveDateTime, Timelike};
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::{Postgres, QueryBuilder};
+use tokio_util::sync::CancellationToken;
+
+use crate::clusters::{Cluster, ClusterRegistry};
+use crate::ingest::{insert_samples, IngestReport, OnConflict};
+use crate::jobs::{upsert_jobs, validate_jobs};
+use crate::quality;
+use crate::routes::Resource;
+use sacct::{latest_end_time, parse_sacct, sacct_args};
+use sinfo::{parse_sinfo, summarize, NodeRecord, PartitionUtilization, Snapshot, SINFO_ARGS};
+use slurmrestd::SlurmRestClient;
+
+/// Where the collector reads node state from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// Run `sinfo` (or read its captured output).
+    Sinfo,
+    /// Poll the slurmrestd API. Finished jobs are imported as well.
+    Slurmrestd,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct CollectArgs {
+    /// Seconds between samples.
+    #[arg(long, default_value_t = 300)]
+    pub interval: u64,
+
+    #[arg(long, value_enum, default_value_t = Backend::Sinfo)]
+    pub backend: Backend,
+
+    /// Read captured `sinfo` output from this file instead of running `sinfo`.
+    #[arg(long)]
+    pub input: Option<PathBuf>,
+
+    /// Path to the `sinfo` binary.
+    #[arg(long, default_value = "sinfo")]
+    pub sinfo: String,
+
+    /// Base URL of slurmrestd.
+    #[arg(long, env = "SLURMRESTD_URL", default_value = "http://localhost:6820")]
+    pub slurmrestd_url: String,
+
+    /// User name sent to slurmrestd with the JWT.
+    #[arg(long, env = "SLURMRESTD_USER", default_value = "slurm")]
+    pub slurmrestd_user: String,
+
+    /// JWT for slurmrestd, e.g. from `scontrol token`.
+    #[arg(long, env = "SLURM_JWT", hide_env_values = true)]
+    pub slurm_jwt: Option<String>,
+
+    /// Cluster to write samples to. Defaults to the first configured cluster.
+    #[arg(long)]
+    pub cluster: Option<String>,
+
+    /// Take a single sample and exit.
+    #[arg(long)]
+    pub once: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ImportJobsArgs {
+    /// Read captured `sacct --parsable2` output from this file instead of running `sacct`.
+    #[arg(long)]
+    pub input: Option<PathBuf>,
+
+    /// Path to the `sacct` binary.
+    #[arg(long, default_value = "sacct")]
+    pub sacct: String,
+
+    /// Import jobs that ended after this time (e.g. 2024-03-01T00:00:00) when there is
+    /// no stored high-water mark yet. Defaults to one day ago.
+    #[arg(long)]
+    pub since: Option<NaiveDateTime>,
+
+    /// Cluster to write jobs to. Defaults to the first configured cluster.
+    #[arg(long)]
+    pub cluster: Option<String>,
+}
+
+/// Key of the sacct importer's row in a cluster's `import_state` table.
+const SACCT_SOURCE: &str = "sacct";
+
+async fn run_command(program: &str, args: &[String]) -> Result<String> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("failed to run {program}"))?;
+
+    if !output.status.success() {
+        bail!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8(output.stdout).with_context(|| format!("{program} output is not UTF-8"))
+}
+
+async fn read_sinfo(args: &CollectArgs) -> Result<String> {
+    if let Some(path) = &args.input {
+        return tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()));
+    }
+
+    let sinfo_args: Vec<String> = SINFO_ARGS.iter().map(|arg| arg.to_string()).collect();
+    run_command(&args.sinfo, &sinfo_args).await
+}
+
+/// Sample time for a collection run: the current time, truncated to the minute so a
+/// retried run writes the same samples again rather than new ones.
+fn sample_time() -> NaiveDateTime {
+    let now = quality::now();
+    now.with_second(0)
+        .and_then(|time| time.with_nanosecond(0))
+        .unwrap_or(now)
+}
+
+async fn insert_partitions(
+    conn: &mut PgConnection,
+    schema: &str,
+    time: NaiveDateTime,
+    partitions: &[PartitionUtilization],
+) -> Result<u64, sqlx::Error> {
+    if partitions.is_empty() {
+        return Ok(0);
+    }
+
+    let mut query = QueryBuilder::<Postgres>::new(format!(
+        "INSERT INTO {schema}.partition_utilization (time, partition, resource, allocated, total) "
+    ));
+
+    query.push_values(partitions, |mut row, partition| {
+        row.push_bind(time)
+            .push_bind(&partition.partition)
+            .push_bind(partition.resource.table())
+            .push_bind(partition.allocated)
+            .push_bind(partition.total);
+    });
+
+    query.push(
+        " ON CONFLICT (time, partition, resource) DO UPDATE SET \
+         allocated = EXCLUDED.allocated, \
+         total = EXCLUDED.total",
+    );
+
+    Ok(query.build().execute(&mut *conn).await?.rows_affected())
+}
+
+/// Writes a snapshot's cluster and partition samples in one transaction. Cluster
+/// samples that fail the quality checks, or differ from samples already stored for the
+/// same minute, are quarantined instead.
+pub async fn write_snapshot(cluster: &Cluster, snapshot: &Snapshot) -> Result<(), sqlx::Error> {
+    let now = quality::now();
+    let mut report = IngestReport::default();
+    let (cpu, mut quarantined) =
+        quality::screen(Resource::Cpu, [(0, snapshot.cpu.clone())], now, &mut report);
+    let (gpu, suspect) = quality::screen(
+        Resource::Gpu,
+        snapshot.gpu.iter().cloned().enumerate(),
+        now,
+        &mut report,
+    );
+    quarantined.extend(suspect);
+    report.accepted = cpu.len() + gpu.len();
+
+    let mut tx = cluster.pool.begin().await?;
+
+    for (resource, samples) in [(Resource::Cpu, cpu), (Resource::Gpu, gpu)] {
+        let conflicts = insert_samples(
+            &mut tx,
+            &cluster.schema,
+            resource,
+            &samples,
+            OnConflict::Keep,
+        )
+        .await?;
+        quarantined.extend(quality::quarantine_conflicts(
+            resource,
+            conflicts,
+            &mut report,
+        ));
+    }
+    for entry in &quarantined {
+        tracing::warn!(
+            "Quarantining {} sample at {}: {}",
+            entry.resource.table(),
+            entry.sample.time,
+            entry.reason
+        );
+    }
+    quality::insert_quarantined(&mut tx, &cluster.schema, &quarantined).await?;
+    insert_partitions(
+        &mut tx,
+        &cluster.schema,
+        snapshot.time,
+        &snapshot.partitions,
+    )
+    .await?;
+
+    tx.commit().await
+}
+
+/// Reads node records from the configured backend. With slurmrestd, finished jobs are
+/// written to the jobs table on the way.
+async fn read_nodes(
+    args: &CollectArgs,
+    client: Option<&SlurmRestClient>,
+    cluster: &Cluster,
+) -> Result<Vec<NodeRecord>> {
+    let Some(client) = client else {
+        return parse_sinfo(&read_sinfo(args).await?);
+    };
+
+    let (jobs, report) = validate_jobs(client.finished_jobs().await?);
+    for error in &report.errors {
+        tracing::warn!("Skipping slurmrestd job {}: {}", error.index, error.reason);
+    }
+    upsert_jobs(&cluster.pool, &cluster.schema, &jobs)
+        .await
+        .context("failed to write job records")?;
+
+    client.nodes().await
+}
+
+async fn collect_once(
+    args: &CollectArgs,
+    client: Option<&SlurmRestClient>,
+    cluster: &Cluster,
+) -> Result<()> {
+    let records = read_nodes(args, client, cluster).await?;
+    let snapshot = summarize(&records, sample_time());
+
+    write_snapshot(cluster, &snapshot)
+        .await
+        .context("failed to write samples")?;
+
+    tracing::info!(
+        "Collected {} nodes for {}: {}/{} CPUs, {}/{} GPUs",
+        records.len(),
+        cluster.name,
+        snapshot.cpu.allocated,
+        snapshot.cpu.total,
+        snapshot.gpu.iter().map(|s| s.allocated).sum::<i32>(),
+        snapshot.gpu.iter().map(|s| s.total).sum::<i32>()
+    );
+
+    Ok(())
+}
+
+/// Runs the collector until `shutdown` is cancelled, finishing the run in progress. A
+/// failed run is logged and retried on the next tick, except with `--once` where it is
+/// returned.
+pub async fn run(
+    args: CollectArgs,
+    clusters: Arc<ClusterRegistry>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let cluster = match &args.cluster {
+        Some(name) => clusters
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown cluster `{name}`"))?,
+        None => clusters.default_cluster(),
+    };
+
+    let client = match args.backend {
+        Backend::Sinfo => None,
+        Backend::Slurmrestd => {
+            let token = args
+                .slurm_jwt
+                .as_deref()
+                .context("the slurmrestd backend needs a token in SLURM_JWT or --slurm-jwt")?;
+            Some(SlurmRestClient::new(
+                &args.slurmrestd_url,
+                &args.slurmrestd_user,
+                token,
+            )?)
+        }
+    };
+
+    if args.once {
+        return collect_once(&args, client.as_ref(), cluster).await;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(args.interval.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+
+        if let Err(e) = collect_once(&args, client.as_ref(), cluster).await {
+            tracing::error!("Error: failed to collect utilization: {:?}", e);
+        }
+    }
+}
+
+async fn high_water_mark(
+    pool: &PgPool,
+    schema: &str,
+) -> Result<Option<NaiveDateTime>, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "SELECT high_water_mark FROM {schema}.import_state WHERE source = $1"
+    ))
+    .bind(SACCT_SOURCE)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn store_high_water_mark(
+    pool: &PgPool,
+    schema: &str,
+    time: NaiveDateTime,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO {schema}.import_state (source, high_water_mark) VALUES ($1, $2) \
+         ON CONFLICT (source) DO UPDATE SET \
+         high_water_mark = GREATEST(import_state.high_water_mark, EXCLUDED.high_water_mark), \
+         updated_at = now()"
+    ))
+    .bind(SACCT_SOURCE)
+    .bind(time)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Imports finished jobs from `sacct` into the cluster's `jobs` table. Each run starts
+/// at the latest end time seen by the previous one; jobs are upserted, so the overlap
+/// is harmless.
+pub async fn import_jobs(args: ImportJobsArgs, clusters: Arc<ClusterRegistry>) -> Result<()> {
+    let cluster = match &args.cluster {
+        Some(name) => clusters
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown cluster `{name}`"))?,
+        None => clusters.default_cluster(),
+    };
+
+    let output = match &args.input {
+        Some(path) => tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?,
+        None => {
+            let since = match high_water_mark(&cluster.pool, &cluster.schema).await? {
+                Some(time) => time,
+                None => args
+                    .since
+                    .unwrap_or_else(|| quality::now() - chrono::Duration::days(1)),
+            };
+            tracing::info!("Importing jobs that ended since {}", since);
+            run_command(&args.sacct, &sacct_args(since)).await?
+        }
+    };
+
+    let (jobs, report) = validate_jobs(parse_sacct(&output)?);
+
+    for error in &report.errors {
+        tracing::warn!("Skipping sacct job {}: {}", error.index, error.reason);
+    }
+
+    upsert_jobs(&cluster.pool, &cluster.schema, &jobs)
+        .await
+        .context("failed to write job records")?;
+
+    if let Some(time) = latest_end_time(&jobs) {
+        store_high_water_mark(&cluster.pool, &cluster.schema, time)
+            .await
+            .context("failed to store the high-water mark")?;
+    }
+
+    tracing::info!(
+        "Imported {} jobs, skipped {}",
+        report.accepted,
+        report.rejected
+    );
+
+    Ok(())
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use chrono::NaiveDateTime;
+
+use crate::jobs::JobRecord;
+
+/// Fields requested from `sacct`. The parser reads columns by header name, so captured
+/// output with extra columns or a different order also works.
+pub const SACCT_FIELDS: &str = "JobID,Account,User,Partition,Submit,Start,End,NNodes,AllocCPUS,\
+                                AllocTRES,State,Elapsed,TotalCPU,ReqMem,MaxRSS";
+
+/// Job states sacct is asked for, i.e. jobs that have finished one way or another.
+pub const SACCT_STATES: &str = "CA,CD,DL,F,NF,OOM,PR,TO";
+
+const REQUIRED_FIELDS: [&str; 15] = [
+    "JobID",
+    "Account",
+    "User",
+    "Partition",
+    "Submit",
+    "Start",
+    "End",
+    "NNodes",
+    "AllocCPUS",
+    "AllocTRES",
+    "State",
+    "Elapsed",
+    "TotalCPU",
+    "ReqMem",
+    "MaxRSS",
+];
+
+/// Parses a Slurm duration: `[DD-][HH:]MM:SS[.mmm]`, rounded down to whole seconds.
+pub fn parse_duration(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (days, rest) = match value.split_once('-') {
+        Some((days, rest)) => (days.parse::<i64>().ok()?, rest),
+        None => (0, value),
+    };
+    let rest = rest.split('.').next()?;
+
+    let parts = rest
+        .split(':')
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [hours, minutes, seconds] => (*hours, *minutes, *seconds),
+        [minutes, seconds] => (0, *minutes, *seconds),
+        _ => return None,
+    };
+
+    Some(((days * 24 + hours) * 60 + minutes) * 60 + seconds)
+}
+
+/// What a memory figure is relative to. Older Slurm versions suffix `ReqMem` with `n`
+/// (per node) or `c` (per CPU).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPer {
+    Job,
+    Node,
+    Cpu,
+}
+
+/// Parses a memory figure such as `16G`, `4000Mn` or `3145728K` into bytes. A bare
+/// number is in megabytes, as in Slurm's own configuration.
+pub fn parse_memory(value: &str) -> Option<(i64, MemoryPer)> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    let (value, per) = match value.strip_suffix('n') {
+        Some(value) => (value, MemoryPer::Node),
+        None => match value.strip_suffix('c') {
+            Some(value) => (value, MemoryPer::Cpu),
+            None => (value, MemoryPer::Job),
+        },
+    };
+
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => value.split_at(i),
+        None => (value, "M"),
+    };
+    let number: f64 = number.parse().ok()?;
+    let multiplier: i64 = match unit {
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        "P" => 1 << 50,
+        _ => return None,
+    };
+
+    Some(((number * multiplier as f64) as i64, per))
+}
+
+/// Splits a TRES list such as `cpu=4,gres/gpu=2,mem=16G` into name/value pairs.
+pub fn parse_tres(value: &str) -> HashMap<&str, &str> {
+    value
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .collect()
+}
+
+fn parse_time(value: &str) -> Option<NaiveDateTime> {
+    // sacct prints `Unknown` or `None` for times that haven't happened.
+    value.parse().ok()
+}
+
+/// One parsed line of sacct output, keyed by column name.
+struct Row<'a> {
+    fields: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Row<'a> {
+    fn get(&self, name: &str) -> &'a str {
+        self.fields.get(name).copied().unwrap_or_default().trim()
+    }
+
+    fn int(&self, name: &str) -> i32 {
+        self.get(name).parse().unwrap_or(0)
+    }
+}
+
+fn parse_rows(output: &str) -> Result<Vec<Row<'_>>> {
+    let mut lines = output.lines().filter(|line| !line.trim().is_empty());
+
+    let Some(header) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let columns: Vec<&str> = header.split('|').map(str::trim).collect();
+
+    if let Some(missing) = REQUIRED_FIELDS.iter().find(|f| !columns.contains(f)) {
+        bail!("sacct output has no {missing} column; run sacct with --parsable2 and a header");
+    }
+
+    lines
+        .enumerate()
+        .map(|(i, line)| {
+            let values: Vec<&str> = line.split('|').collect();
+            if values.len() != columns.len() {
+                bail!(
+                    "sacct line {}: expected {} fields, got {}",
+                    i + 2,
+                    columns.len(),
+                    values.len()
+                );
+            }
+            Ok(Row {
+                fields: columns.iter().copied().zip(values).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Requested memory for a job, preferring the allocated TRES over `ReqMem`, whose
+/// meaning depends on the Slurm version.
+fn requested_memory(row: &Row) -> Option<i64> {
+    if let Some(mem) = parse_tres(row.get("AllocTRES")).get("mem") {
+        if let Some((bytes, _)) = parse_memory(mem) {
+            return Some(bytes);
+        }
+    }
+
+    let (bytes, per) = parse_memory(row.get("ReqMem"))?;
+    Some(match per {
+        MemoryPer::Job => bytes,
+        MemoryPer::Node => bytes * i64::from(row.int("NNodes").max(1)),
+        MemoryPer::Cpu => bytes * i64::from(row.int("AllocCPUS").max(1)),
+    })
+}
+
+/// Parses `sacct --parsable2` output (with its header) into job records.
+///
+/// Job steps (`1001.batch`, `1001.0`) are folded into their job: the job's peak memory
+/// is the largest step `MaxRSS`, and its CPU time falls back to the sum of the steps'
+/// `TotalCPU` when the allocation line reports none. Array tasks (`2000_1`) are jobs
+/// of their own; pending array ranges (`2000_[2-5]`) are skipped.
+pub fn parse_sacct(output: &str) -> Result<Vec<JobRecord>> {
+    let mut jobs: Vec<JobRecord> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut step_cpu: HashMap<usize, i64> = HashMap::new();
+
+    for row in parse_rows(output)? {
+        let job_id = row.get("JobID");
+
+        if let Some((parent, _step)) = job_id.split_once('.') {
+            let Some(&i) = index.get(parent) else {
+                continue;
+            };
+            let job = &mut jobs[i];
+
+            if let Some((rss, _)) = parse_memory(row.get("MaxRSS")) {
+                job.max_rss_bytes = Some(job.max_rss_bytes.map_or(rss, |max| max.max(rss)));
+            }
+            if let Some(cpu) = parse_duration(row.get("TotalCPU")) {
+                *step_cpu.entry(i).or_default() += cpu;
+            }
+            continue;
+        }
+
+        if job_id.is_empty() || job_id.contains('[') {
+            continue;
+        }
+
+        let tres = parse_tres(row.get("AllocTRES"));
+        let gpus = tres
+            .get("gres/gpu")
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0);
+        // `CANCELLED by 1234` -> `CANCELLED`
+        let state = row.get("State").split_whitespace().next().map(String::from);
+        let partition = Some(row.get("Partition"))
+            .filter(|p| !p.is_empty())
+            .map(String::from);
+
+        index.insert(job_id.to_string(), jobs.len());
+        jobs.push(JobRecord {
+            job_id: job_id.to_string(),
+            account: row.get("Account").to_string(),
+            user_name: row.get("User").to_string(),
+            partition,
+            submit_time: parse_time(row.get("Submit")),
+            start_time: parse_time(row.get("Start")),
+            end_time: parse_time(row.get("End")),
+            cpus: Some(row.int("AllocCPUS")),
+            gpus: Some(gpus),
+            state,
+            elapsed_seconds: parse_duration(row.get("Elapsed")),
+            cpu_time_seconds: parse_duration(row.get("TotalCPU")),
+            mem_requested_bytes: requested_memory(&row),
+            max_rss_bytes: parse_memory(row.get("MaxRSS")).map(|(bytes, _)| bytes),
+        });
+    }
+
+    for (i, cpu) in step_cpu {
+        let job = &mut jobs[i];
+        if job.cpu_time_seconds.unwrap_or(0) == 0 {
+            job.cpu_time_seconds = Some(cpu);
+        }
+    }
+
+    Ok(jobs)
+}
+
+/// Latest end time in a batch, used as the next high-water mark.
+pub fn latest_end_time(jobs: &[JobRecord]) -> Option<NaiveDateTime> {
+    jobs.iter().filter_map(|job| job.end_time).max()
+}
+
+/// Command-line arguments for `sacct` covering jobs that ended between `since` and now.
+pub fn sacct_args(since: NaiveDateTime) -> Vec<String> {
+    vec![
+        "--allusers".to_string(),
+        "--parsable2".to_string(),
+        format!("--format={SACCT_FIELDS}"),
+        format!("--state={SACCT_STATES}"),
+        format!("--starttime={}", since.format("%Y-%m-%dT%H:%M:%S")),
+        "--endtime=now".to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../../tests/fixtures/sacct.txt");
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("02:00:00"), Some(7200));
+        assert_eq!(parse_duration("1-01:00:00"), Some(90_000));
+        assert_eq!(parse_duration("09:00.250"), Some(540));
+        assert_eq!(parse_duration("2-04:00:00"), Some(187_200));
+        assert_eq!(parse_duration("INVALID"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("16G"), Some((16 << 30, MemoryPer::Job)));
+        assert_eq!(parse_memory("4000Mn"), Some((4000 << 20, MemoryPer::Node)));
+        assert_eq!(parse_memory("2Gc"), Some((2 << 30, MemoryPer::Cpu)));
+        assert_eq!(parse_memory("1.5K"), Some((1536, MemoryPer::Job)));
+        assert_eq!(parse_memory("100"), Some((100 << 20, MemoryPer::Job)));
+        assert_eq!(parse_memory(""), None);
+        assert_eq!(parse_memory("12X"), None);
+    }
+
+    #[test]
+    fn test_parse_sacct_folds_steps_into_jobs() {
+        let jobs = parse_sacct(FIXTURE).unwrap();
+
+        assert_eq!(jobs.len(), 3);
+
+        let job = &jobs[0];
+        assert_eq!(job.job_id, "1001");
+        assert_eq!(job.user_name, "alice");
+        assert_eq!(job.elapsed_seconds, Some(7200));
+        assert_eq!(job.cpu_time_seconds, Some(27_000));
+        assert_eq!(job.mem_requested_bytes, Some(16 << 30));
+        assert_eq!(job.max_rss_bytes, Some(3 << 30));
+    }
+
+    #[test]
+    fn test_parse_sacct_normalizes_tres_and_state() {
+        let jobs = parse_sacct(FIXTURE).unwrap();
+
+        let job = &jobs[1];
+        assert_eq!(job.gpus, Some(4));
+        assert_eq!(job.cpus, Some(16));
+        assert_eq!(job.state.as_deref(), Some("TIMEOUT"));
+        assert_eq!(job.elapsed_seconds, Some(90_000));
+        // The allocation line has no CPU time, so the steps are summed.
+        assert_eq!(job.cpu_time_seconds, Some(36_000 + 187_200));
+        assert_eq!(job.mem_requested_bytes, Some(64 << 30));
+        assert_eq!(job.max_rss_bytes, Some(20 << 30));
+    }
+
+    #[test]
+    fn test_parse_sacct_array_tasks() {
+        let jobs = parse_sacct(FIXTURE).unwrap();
+
+        let job = &jobs[2];
+        assert_eq!(job.job_id, "2000_1");
+        assert_eq!(job.cpu_time_seconds, Some(540));
+        assert_eq!(job.max_rss_bytes, Some(3500 << 20));
+        assert_eq!(
+            latest_end_time(&jobs),
+            Some("2024-03-28T01:15:00".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_sacct_requires_header() {
+        assert!(parse_sacct("1001|ccv|alice").is_err());
+        assert!(parse_sacct("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_requested_memory_falls_back_to_req_mem() {
+        let output = "JobID|Account|User|Partition|Submit|Start|End|NNodes|AllocCPUS|AllocTRES|\
+                      State|Elapsed|TotalCPU|ReqMem|MaxRSS\n\
+                      1|ccv|alice|batch||||2|8|cpu=8|COMPLETED|00:01:00|00:00:00|2Gc|";
+
+        let jobs = parse_sacct(output).unwrap();
+
+        assert_eq!(jobs[0].mem_requested_bytes, Some(16 << 30));
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDateTime;
+
+use crate::ingest::ValidSample;
+use crate::routes::Resource;
+
+/// Output format passed to `sinfo --Format`. Every field is padded to a fixed width and
+/// followed by `|`, so the parser splits on `|` and trims. The widths are generous so
+/// long GRES strings aren't truncated.
+pub const SINFO_FORMAT: &str =
+    "NodeHost:32|,Partition:32|,CPUsState:32|,Gres:128|,GresUsed:128|,StateLong:32";
+
+/// Arguments for `sinfo` producing output in [`SINFO_FORMAT`], one line per node and
+/// partition.
+pub const SINFO_ARGS: [&str; 4] = ["--noheader", "--Node", "--Format", SINFO_FORMAT];
+
+/// GPUs of one type on a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuCount {
+    /// GPU model, or empty when the GRES has no type (`gpu:2`).
+    pub gpu_type: String,
+    pub allocated: i32,
+    pub total: i32,
+}
+
+/// One line of `sinfo --Node` output. Nodes in several partitions appear once per
+/// partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeRecord {
+    pub node: String,
+    pub partition: String,
+    pub cpus_allocated: i32,
+    pub cpus_idle: i32,
+    pub cpus_other: i32,
+    pub cpus_total: i32,
+    pub gpus: Vec<GpuCount>,
+    pub state: String,
+}
+
+/// Allocated and total of one resource in one partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionUtilization {
+    pub partition: String,
+    pub resource: Resource,
+    pub allocated: i32,
+    pub total: i32,
+}
+
+/// Everything a single `sinfo` run contributes to the store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub time: NaiveDateTime,
+    pub cpu: ValidSample,
+    /// One sample per GPU type.
+    pub gpu: Vec<ValidSample>,
+    pub partitions: Vec<PartitionUtilization>,
+}
+
+/// Parses the `A/I/O/T` CPU state counts.
+fn parse_cpu_states(value: &str) -> Result<[i32; 4]> {
+    let counts = value
+        .split('/')
+        .map(|count| count.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid CPU state `{value}`"))?;
+
+    match counts.as_slice() {
+        [allocated, idle, other, total] => Ok([*allocated, *idle, *other, *total]),
+        _ => bail!("expected A/I/O/T CPU states, got `{value}`"),
+    }
+}
+
+/// Splits a GRES list on commas that aren't inside parentheses, since the socket and
+/// index annotations (`(IDX:0-1,3)`) contain commas themselves.
+fn split_gres(value: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                entries.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    entries.push(&value[start..]);
+
+    entries
+}
+
+/// Parses a GRES list such as `gpu:a100:4(S:0-1),mps:100` into GPU counts by type.
+/// Non-GPU resources are ignored.
+fn parse_gpu_gres(value: &str) -> Result<BTreeMap<String, i32>> {
+    let mut gpus = BTreeMap::new();
+
+    if value == "(null)" || value.is_empty() {
+        return Ok(gpus);
+    }
+
+    for entry in split_gres(value) {
+        // Drop the `(S:..)`/`(IDX:..)` annotation.
+        let entry = entry.split('(').next().unwrap_or_default().trim();
+        let parts: Vec<&str> = entry.split(':').collect();
+
+        let (gpu_type, count) = match parts.as_slice() {
+            ["gpu", count] => ("", *count),
+            ["gpu", gpu_type, count] => (*gpu_type, *count),
+            _ => continue,
+        };
+        let count: i32 = count
+            .parse()
+            .with_context(|| format!("invalid GPU count in GRES `{entry}`"))?;
+
+        *gpus.entry(gpu_type.to_string()).or_default() += count;
+    }
+
+    Ok(gpus)
+}
+
+/// Pairs a node's configured GPUs (`Gres`) with the allocated ones (`GresUsed`).
+pub fn gpu_counts(gres: &str, gres_used: &str) -> Result<Vec<GpuCount>> {
+    let totals = parse_gpu_gres(gres)?;
+    let used = parse_gpu_gres(gres_used)?;
+
+    Ok(totals
+        .into_iter()
+        .map(|(gpu_type, total)| GpuCount {
+            allocated: used.get(&gpu_type).copied().unwrap_or(0),
+            gpu_type,
+            total,
+        })
+        .collect())
+}
+
+fn parse_line(line: &str) -> Result<NodeRecord> {
+    let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+
+    let [node, partition, cpus, gres, gres_used, state] = fields.as_slice() else {
+        bail!("expected 6 fields, got {}", fields.len());
+    };
+
+    let [cpus_allocated, cpus_idle, cpus_other, cpus_total] = parse_cpu_states(cpus)?;
+    let gpus = gpu_counts(gres, gres_used)?;
+
+    Ok(NodeRecord {
+        node: node.to_string(),
+        // The default partition is marked with a trailing `*`.
+        partition: partition.trim_end_matches('*').to_string(),
+        cpus_allocated,
+        cpus_idle,
+        cpus_other,
+        cpus_total,
+        gpus,
+        state: state.to_string(),
+    })
+}
+
+/// Parses `sinfo` output produced with [`SINFO_ARGS`]. Blank lines are skipped; any
+/// other malformed line fails the whole parse, since a partial snapshot would report
+/// a drop in capacity.
+pub fn parse_sinfo(output: &str) -> Result<Vec<NodeRecord>> {
+    output
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_line(line).with_context(|| format!("sinfo line {}", i + 1)))
+        .collect()
+}
+
+fn sample(time: NaiveDateTime, gpu_type: &str, allocated: i32, total: i32) -> ValidSample {
+    ValidSample {
+        time,
+        allocated,
+        total,
+        gpu_type: gpu_type.to_string(),
+    }
+}
+
+/// Sums node records into cluster and partition utilization. Cluster totals count each
+/// node once even when it belongs to several partitions; nodes without a partition only
+/// count towards the cluster.
+pub fn summarize(records: &[NodeRecord], time: NaiveDateTime) -> Snapshot {
+    let mut seen = HashSet::new();
+    let mut cpu = (0, 0);
+    let mut gpu: BTreeMap<&str, (i32, i32)> = BTreeMap::new();
+    let mut partitions: BTreeMap<(&str, Resource), (i32, i32)> = BTreeMap::new();
+
+    for record in records {
+        let gpus_allocated: i32 = record.gpus.iter().map(|g| g.allocated).sum();
+        let gpus_total: i32 = record.gpus.iter().map(|g| g.total).sum();
+
+        if !record.partition.is_empty() {
+            let partition_cpu = partitions
+                .entry((&record.partition, Resource::Cpu))
+                .or_default();
+            partition_cpu.0 += record.cpus_allocated;
+            partition_cpu.1 += record.cpus_total;
+        }
+
+        if !record.partition.is_empty() && gpus_total > 0 {
+            let partition_gpu = partitions
+                .entry((&record.partition, Resource::Gpu))
+                .or_default();
+            partition_gpu.0 += gpus_allocated;
+            partition_gpu.1 += gpus_total;
+        }
+
+        if !seen.insert(record.node.as_str()) {
+            continue;
+        }
+
+        cpu.0 += record.cpus_allocated;
+        cpu.1 += record.cpus_total;
+        for count in &record.gpus {
+            let totals = gpu.entry(&count.gpu_type).or_default();
+            totals.0 += count.allocated;
+            totals.1 += count.total;
+        }
+    }
+
+    Snapshot {
+        time,
+        cpu: sample(time, "", cpu.0, cpu.1),
+        gpu: gpu
+            .into_iter()
+            .map(|(gpu_type, (allocated, total))| sample(time, gpu_type, allocated, total))
+            .collect(),
+        partitions: partitions
+            .into_iter()
+            .map(
+                |((partition, resource), (allocated, total))| PartitionUtilization {
+                    partition: partition.to_string(),
+                    resource,
+                    allocated,
+                    total,
+                },
+            )
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../../tests/fixtures/sinfo.txt");
+
+    fn time() -> NaiveDateTime {
+        "2024-03-27T00:00:00".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_sinfo_fixture() {
+        let records = parse_sinfo(FIXTURE).unwrap();
+
+        assert_eq!(records.len(), 9);
+        assert_eq!(records[0].partition, "batch");
+        assert_eq!(records[1].cpus_idle, 16);
+        assert_eq!(records[2].cpus_other, 32);
+        assert!(records[0].gpus.is_empty());
+        assert_eq!(
+            records[6].gpus,
+            vec![
+                GpuCount {
+                    gpu_type: "titanrtx".to_string(),
+                    allocated: 1,
+                    total: 4
+                },
+                GpuCount {
+                    gpu_type: "v100".to_string(),
+                    allocated: 2,
+                    total: 2
+                },
+            ]
+        );
+        assert_eq!(records[8].gpus[0].gpu_type, "");
+    }
+
+    #[test]
+    fn test_parse_gpu_gres_handles_index_lists() {
+        let gpus = parse_gpu_gres("gpu:a100:3(IDX:0-1,3),mps:0").unwrap();
+
+        assert_eq!(gpus.len(), 1);
+        assert_eq!(gpus["a100"], 3);
+    }
+
+    #[test]
+    fn test_parse_sinfo_rejects_malformed_lines() {
+        assert!(parse_sinfo("node1|batch|32/0/0|(null)|(null)|idle").is_err());
+        assert!(parse_sinfo("node1|batch|32/0/0/32").is_err());
+        assert!(parse_sinfo("node1|batch|32/0/0/32|gpu:a100:x|(null)|idle").is_err());
+    }
+
+    #[test]
+    fn test_summarize_counts_shared_nodes_once() {
+        let snapshot = summarize(&parse_sinfo(FIXTURE).unwrap(), time());
+
+        assert_eq!(snapshot.cpu.allocated, 84);
+        assert_eq!(snapshot.cpu.total, 232);
+
+        let gpu: Vec<_> = snapshot
+            .gpu
+            .iter()
+            .map(|s| (s.gpu_type.as_str(), s.allocated, s.total))
+            .collect();
+        assert_eq!(
+            gpu,
+            vec![
+                ("", 1, 2),
+                ("a100", 3, 8),
+                ("titanrtx", 1, 4),
+                ("v100", 2, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summarize_partitions() {
+        let snapshot = summarize(&parse_sinfo(FIXTURE).unwrap(), time());
+
+        let find = |partition: &str, resource: Resource| {
+            snapshot
+                .partitions
+                .iter()
+                .find(|p| p.partition == partition && p.resource == resource)
+                .map(|p| (p.allocated, p.total))
+        };
+
+        assert_eq!(find("batch", Resource::Cpu), Some((48, 96)));
+        assert_eq!(find("batch", Resource::Gpu), None);
+        assert_eq!(find("debug", Resource::Cpu), Some((0, 32)));
+        assert_eq!(find("gpu", Resource::Gpu), Some((7, 16)));
+        assert_eq!(find("gpu-debug", Resource::Gpu), Some((3, 6)));
+    }
+}
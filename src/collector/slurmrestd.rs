@@ -0,0 +1,361 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDateTime};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::sacct::{parse_memory, parse_tres};
+use super::sinfo::{gpu_counts, NodeRecord};
+use crate::jobs::JobRecord;
+use crate::quality;
+
+/// The slurmrestd data parser version this client speaks.
+pub const API_VERSION: &str = "v0.0.40";
+
+/// Job states after which slurmctld won't change a job any more.
+const FINISHED_STATES: [&str; 9] = [
+    "BOOT_FAIL",
+    "CANCELLED",
+    "COMPLETED",
+    "DEADLINE",
+    "FAILED",
+    "NODE_FAIL",
+    "OUT_OF_MEMORY",
+    "PREEMPTED",
+    "TIMEOUT",
+];
+
+/// slurmrestd's wrapper for numbers that may be unset or infinite.
+#[derive(Debug, Default, Deserialize)]
+struct NoValue {
+    #[serde(default)]
+    set: bool,
+    #[serde(default)]
+    infinite: bool,
+    #[serde(default)]
+    number: i64,
+}
+
+impl NoValue {
+    fn value(&self) -> Option<i64> {
+        (self.set && !self.infinite).then_some(self.number)
+    }
+
+    fn time(&self) -> Option<NaiveDateTime> {
+        // Slurm reports 0 for times that haven't happened yet.
+        let seconds = self.value().filter(|seconds| *seconds > 0)?;
+        let time = DateTime::from_timestamp(seconds, 0)?;
+        Some(quality::stored_time(time))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    error: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NodesResponse {
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Node {
+    name: String,
+    #[serde(default)]
+    partitions: Vec<String>,
+    #[serde(default)]
+    cpus: i32,
+    #[serde(default)]
+    alloc_cpus: i32,
+    #[serde(default)]
+    alloc_idle_cpus: i32,
+    #[serde(default)]
+    gres: String,
+    #[serde(default)]
+    gres_used: String,
+    #[serde(default)]
+    state: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JobsResponse {
+    #[serde(default)]
+    jobs: Vec<Job>,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Job {
+    job_id: i64,
+    #[serde(default)]
+    array_job_id: NoValue,
+    #[serde(default)]
+    array_task_id: NoValue,
+    #[serde(default)]
+    account: String,
+    #[serde(default)]
+    user_name: String,
+    #[serde(default)]
+    partition: String,
+    #[serde(default)]
+    submit_time: NoValue,
+    #[serde(default)]
+    start_time: NoValue,
+    #[serde(default)]
+    end_time: NoValue,
+    #[serde(default)]
+    cpus: NoValue,
+    #[serde(default)]
+    tres_alloc_str: String,
+    #[serde(default)]
+    job_state: Vec<String>,
+}
+
+fn check_errors(errors: &[ApiError]) -> Result<()> {
+    if let Some(error) = errors.first() {
+        bail!(
+            "slurmrestd returned an error: {} {}",
+            error.error,
+            error.description
+        );
+    }
+    Ok(())
+}
+
+impl Node {
+    /// One record per partition, matching `sinfo --Node`.
+    fn into_records(self) -> Result<Vec<NodeRecord>> {
+        let gpus = gpu_counts(&self.gres, &self.gres_used)
+            .with_context(|| format!("invalid GRES for node {}", self.name))?;
+        let cpus_other = (self.cpus - self.alloc_cpus - self.alloc_idle_cpus).max(0);
+        let state = self.state.join("+").to_lowercase();
+
+        let partitions = if self.partitions.is_empty() {
+            vec![String::new()]
+        } else {
+            self.partitions
+        };
+
+        Ok(partitions
+            .into_iter()
+            .map(|partition| NodeRecord {
+                node: self.name.clone(),
+                partition,
+                cpus_allocated: self.alloc_cpus,
+                cpus_idle: self.alloc_idle_cpus,
+                cpus_other,
+                cpus_total: self.cpus,
+                gpus: gpus.clone(),
+                state: state.clone(),
+            })
+            .collect())
+    }
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        self.job_state
+            .iter()
+            .any(|state| FINISHED_STATES.contains(&state.as_str()))
+    }
+
+    /// Converts a finished job into a record with the same `job_id` sacct would use,
+    /// i.e. `2000_1` for array tasks.
+    fn into_record(self) -> JobRecord {
+        let job_id = match (self.array_job_id.value(), self.array_task_id.value()) {
+            (Some(array_job_id), Some(task_id)) if array_job_id > 0 => {
+                format!("{array_job_id}_{task_id}")
+            }
+            _ => self.job_id.to_string(),
+        };
+
+        let tres = parse_tres(&self.tres_alloc_str);
+        let start_time = self.start_time.time();
+        let end_time = self.end_time.time();
+
+        JobRecord {
+            job_id,
+            account: self.account,
+            user_name: self.user_name,
+            partition: Some(self.partition).filter(|p| !p.is_empty()),
+            submit_time: self.submit_time.time(),
+            start_time,
+            end_time,
+            cpus: self.cpus.value().and_then(|cpus| i32::try_from(cpus).ok()),
+            gpus: (!self.tres_alloc_str.is_empty()).then(|| {
+                tres.get("gres/gpu")
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(0)
+            }),
+            state: self.job_state.first().cloned(),
+            elapsed_seconds: start_time
+                .zip(end_time)
+                .map(|(start, end)| (end - start).num_seconds()),
+            cpu_time_seconds: None,
+            mem_requested_bytes: tres
+                .get("mem")
+                .and_then(|mem| parse_memory(mem))
+                .map(|(bytes, _)| bytes),
+            max_rss_bytes: None,
+        }
+    }
+}
+
+/// Client for the slurmrestd OpenAPI, authenticating with a Slurm JWT.
+#[derive(Debug, Clone)]
+pub struct SlurmRestClient {
+    http: reqwest::Client,
+    base_url: String,
+    user: String,
+    token: String,
+}
+
+impl SlurmRestClient {
+    pub fn new(base_url: &str, user: &str, token: &str) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .context("failed to build the HTTP client")?;
+
+        Ok(SlurmRestClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            user: user.to_string(),
+            token: token.to_string(),
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T> {
+        let url = format!("{}/slurm/{API_VERSION}/{endpoint}", self.base_url);
+
+        let response = self
+            .http
+            .get(&url)
+            .header("X-SLURM-USER-NAME", &self.user)
+            .header("X-SLURM-USER-TOKEN", &self.token)
+            .send()
+            .await
+            .with_context(|| format!("failed to reach {url}"))?
+            .error_for_status()
+            .with_context(|| format!("{url} returned an error"))?;
+
+        response
+            .json()
+            .await
+            .with_context(|| format!("invalid response from {url}"))
+    }
+
+    /// Node records in the same shape as [`super::sinfo::parse_sinfo`] returns.
+    pub async fn nodes(&self) -> Result<Vec<NodeRecord>> {
+        let response: NodesResponse = self.get("nodes").await?;
+        check_errors(&response.errors)?;
+
+        let mut records = Vec::new();
+        for node in response.nodes {
+            records.extend(node.into_records()?);
+        }
+        Ok(records)
+    }
+
+    /// Jobs slurmctld still remembers that have finished. Running and pending jobs are
+    /// left out since their end time is only an estimate.
+    pub async fn finished_jobs(&self) -> Result<Vec<JobRecord>> {
+        let response: JobsResponse = self.get("jobs").await?;
+        check_errors(&response.errors)?;
+
+        Ok(response
+            .jobs
+            .into_iter()
+            .filter(Job::is_finished)
+            .map(Job::into_record)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::sinfo::{parse_sinfo, summarize};
+    use axum::{
+        http::{header, HeaderMap, StatusCode},
+        response::{IntoResponse, Response},
+        routing::get,
+        Router,
+    };
+
+    const NODES: &str = include_str!("../../tests/fixtures/slurmrestd_nodes.json");
+    const JOBS: &str = include_str!("../../tests/fixtures/slurmrestd_jobs.json");
+    const SINFO: &str = include_str!("../../tests/fixtures/sinfo.txt");
+
+    fn recorded(headers: &HeaderMap, body: &'static str) -> Response {
+        if headers
+            .get("x-slurm-user-token")
+            .is_none_or(|token| token != "token")
+        {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+    }
+
+    /// Serves the recorded responses on a random local port.
+    async fn mock_slurmrestd() -> String {
+        let app = Router::new()
+            .route(
+                "/slurm/v0.0.40/nodes",
+                get(|headers: HeaderMap| async move { recorded(&headers, NODES) }),
+            )
+            .route(
+                "/slurm/v0.0.40/jobs",
+                get(|headers: HeaderMap| async move { recorded(&headers, JOBS) }),
+            );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{address}")
+    }
+
+    #[tokio::test]
+    async fn test_nodes_match_sinfo_samples() {
+        let client = SlurmRestClient::new(&mock_slurmrestd().await, "slurm", "token").unwrap();
+        let time = "2024-03-27T00:00:00".parse().unwrap();
+
+        let records = client.nodes().await.unwrap();
+
+        assert_eq!(records.len(), 9);
+        assert_eq!(records[2].cpus_other, 32);
+        assert_eq!(
+            summarize(&records, time),
+            summarize(&parse_sinfo(SINFO).unwrap(), time)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_finished_jobs() {
+        let client = SlurmRestClient::new(&mock_slurmrestd().await, "slurm", "token").unwrap();
+
+        let jobs = client.finished_jobs().await.unwrap();
+
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].job_id, "1001");
+        assert_eq!(jobs[0].elapsed_seconds, Some(7200));
+        assert_eq!(jobs[0].mem_requested_bytes, Some(16 << 30));
+        assert_eq!(jobs[1].job_id, "2000_1");
+        assert_eq!(jobs[1].state.as_deref(), Some("FAILED"));
+        assert!(jobs.iter().all(|job| job.validate().is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token() {
+        let client = SlurmRestClient::new(&mock_slurmrestd().await, "slurm", "wrong").unwrap();
+
+        assert!(client.nodes().await.is_err());
+    }
+}
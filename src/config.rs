@@ -0,0 +1,613 @@
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::HeaderValue;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgSslMode;
+use tracing_subscriber::EnvFilter;
+
+use crate::database::DbConfig;
+use crate::shutdown::DrainTimes;
+
+/// What `config check` prints in place of secrets.
+const REDACTED: &str = "<redacted>";
+
+/// Settings for the server and its database. Each layer overrides the one before:
+/// built-in defaults, the TOML file given with `--config`, environment variables, then
+/// command-line flags. The other integrations (clusters, tokens, metric mappings,
+/// retention, ...) are still configured only through their environment variables.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// `tracing` filter directives, in the `RUST_LOG` syntax.
+    pub log_filter: String,
+    /// Origins allowed to call the API from a browser; `*` allows any.
+    pub cors_origins: Vec<String>,
+    /// `max-age` of the `Cache-Control` header on read responses; 0 leaves it out.
+    pub cache_max_age_seconds: u64,
+    /// How long `/ready` fails after SIGTERM before the listener closes.
+    pub shutdown_delay_seconds: u64,
+    /// How long in-flight requests, background tasks and database connections then
+    /// get to finish.
+    pub drain_timeout_seconds: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3000,
+            log_filter: "tower_http=trace,axum=trace,elmo_api=trace".to_string(),
+            cors_origins: vec!["*".to_string()],
+            cache_max_age_seconds: 0,
+            // Long enough for load balancers to see /ready fail; together with the
+            // drain timeout it fits in the 10 seconds Cloud Run allows after SIGTERM.
+            shutdown_delay_seconds: 5,
+            drain_timeout_seconds: 4,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    pub fn drain_times(&self) -> DrainTimes {
+        DrainTimes {
+            delay: Duration::from_secs(self.shutdown_delay_seconds),
+            timeout: Duration::from_secs(self.drain_timeout_seconds),
+        }
+    }
+}
+
+/// The main database, as configured. [`DatabaseConfig::resolve`] turns it into the
+/// [`DbConfig`] used to connect.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub host: Option<String>,
+    pub port: u16,
+    pub socket_dir: Option<PathBuf>,
+    pub name: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub sslmode: String,
+    pub sslrootcert: Option<PathBuf>,
+    pub sslcert: Option<PathBuf>,
+    pub sslkey: Option<PathBuf>,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_seconds: u64,
+    /// How often the CLI commands try to reach the database before giving up.
+    pub connect_attempts: u32,
+    /// Read replicas as `host` or `host:port`.
+    pub replicas: Vec<String>,
+    pub replica_max_lag_seconds: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            host: None,
+            port: 5432,
+            socket_dir: None,
+            name: None,
+            user: None,
+            password: None,
+            password_file: None,
+            sslmode: "allow".to_string(),
+            sslrootcert: None,
+            sslcert: None,
+            sslkey: None,
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_seconds: 10,
+            connect_attempts: 10,
+            replicas: Vec::new(),
+            replica_max_lag_seconds: 30,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// Checks the settings and reads the password file, if any.
+    pub fn resolve(&self) -> Result<DbConfig> {
+        let required = |value: &Option<String>, name: &str| {
+            value.clone().ok_or_else(|| anyhow!("{name} is not set"))
+        };
+
+        let host = match self.socket_dir {
+            Some(_) => self.host.clone(),
+            None => Some(required(&self.host, "DB_HOST")?),
+        };
+
+        let password = match (&self.password, &self.password_file) {
+            (Some(_), Some(_)) => bail!("set only one of DB_PASSWORD and DB_PASSWORD_FILE"),
+            (Some(password), None) => password.clone(),
+            (None, Some(file)) => fs::read_to_string(file)
+                .with_context(|| format!("failed to read DB_PASSWORD_FILE `{}`", file.display()))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            (None, None) => bail!("DB_PASSWORD or DB_PASSWORD_FILE is not set"),
+        };
+
+        let ssl_mode = self.sslmode.trim().parse::<PgSslMode>().map_err(|_| {
+            anyhow!(
+                "invalid DB_SSLMODE `{}`, expected one of disable, allow, prefer, require, \
+                 verify-ca or verify-full",
+                self.sslmode
+            )
+        })?;
+
+        if self.max_connections == 0 {
+            bail!("DB_MAX_CONNECTIONS must be at least 1");
+        }
+        if self.min_connections > self.max_connections {
+            bail!("DB_MIN_CONNECTIONS can't be above DB_MAX_CONNECTIONS");
+        }
+
+        let config = DbConfig {
+            host,
+            port: self.port,
+            socket_dir: self.socket_dir.clone(),
+            name: required(&self.name, "DB_NAME")?,
+            user: required(&self.user, "DB_USER")?,
+            password,
+            ssl_mode,
+            ssl_root_cert: self.sslrootcert.clone(),
+            ssl_client_cert: self.sslcert.clone(),
+            ssl_client_key: self.sslkey.clone(),
+            max_connections: self.max_connections,
+            min_connections: self.min_connections,
+            acquire_timeout: Duration::from_secs(self.acquire_timeout_seconds.max(1)),
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+/// Command-line overrides, accepted by every command. The database password can only
+/// come from a file here, so it doesn't show up in the process list.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ConfigArgs {
+    /// Address to listen on
+    #[arg(long, global = true)]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    /// Log filter, in the RUST_LOG syntax
+    #[arg(long, global = true)]
+    pub log_filter: Option<String>,
+    /// Origin allowed by CORS; repeat for several, `*` allows any
+    #[arg(long = "cors-origin", global = true)]
+    pub cors_origins: Vec<String>,
+    /// Cache-Control max-age for read responses, in seconds
+    #[arg(long, global = true)]
+    pub cache_max_age: Option<u64>,
+    /// Seconds /ready fails after SIGTERM before the listener closes
+    #[arg(long, global = true)]
+    pub shutdown_delay: Option<u64>,
+    /// Seconds requests and background tasks get to finish when shutting down
+    #[arg(long, global = true)]
+    pub drain_timeout: Option<u64>,
+    #[arg(long, global = true)]
+    pub db_host: Option<String>,
+    #[arg(long, global = true)]
+    pub db_port: Option<u16>,
+    #[arg(long, global = true)]
+    pub db_socket_dir: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub db_name: Option<String>,
+    #[arg(long, global = true)]
+    pub db_user: Option<String>,
+    #[arg(long, global = true)]
+    pub db_password_file: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub db_sslmode: Option<String>,
+    #[arg(long, global = true)]
+    pub db_max_connections: Option<u32>,
+    #[arg(long, global = true)]
+    pub db_min_connections: Option<u32>,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the configuration and print it, with secrets redacted
+    Check,
+}
+
+/// Overwrites `field` with the parsed variable `name`, if it is set.
+fn set<T: FromStr>(var: &impl Fn(&str) -> Option<String>, name: &str, field: &mut T) -> Result<()> {
+    if let Some(value) = var(name) {
+        *field = value
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid {name} `{value}`"))?;
+    }
+    Ok(())
+}
+
+fn set_some<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    field: &mut Option<T>,
+) -> Result<()> {
+    if let Some(value) = var(name) {
+        *field = Some(
+            value
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid {name} `{value}`"))?,
+        );
+    }
+    Ok(())
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Config {
+    /// Loads every layer and validates the result.
+    pub fn load(file: Option<&Path>, args: &ConfigArgs) -> Result<Self> {
+        let mut config = match file {
+            Some(file) => Self::from_toml(
+                &fs::read_to_string(file)
+                    .with_context(|| format!("failed to read {}", file.display()))?,
+            )
+            .with_context(|| format!("invalid config file {}", file.display()))?,
+            None => Config::default(),
+        };
+        config.apply_env(|name| env::var(name).ok())?;
+        config.apply_args(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Applies the environment variables `var` looks up.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        let server = &mut self.server;
+        set(&var, "BIND_ADDRESS", &mut server.bind)?;
+        set(&var, "PORT", &mut server.port)?;
+        set(&var, "RUST_LOG", &mut server.log_filter)?;
+        if let Some(origins) = var("CORS_ORIGINS") {
+            server.cors_origins = list(&origins);
+        }
+        set(
+            &var,
+            "CACHE_MAX_AGE_SECONDS",
+            &mut server.cache_max_age_seconds,
+        )?;
+        set(
+            &var,
+            "SHUTDOWN_DELAY_SECONDS",
+            &mut server.shutdown_delay_seconds,
+        )?;
+        set(
+            &var,
+            "DRAIN_TIMEOUT_SECONDS",
+            &mut server.drain_timeout_seconds,
+        )?;
+
+        let database = &mut self.database;
+        set_some(&var, "DB_HOST", &mut database.host)?;
+        set(&var, "DB_PORT", &mut database.port)?;
+        set_some(&var, "DB_SOCKET_DIR", &mut database.socket_dir)?;
+        set_some(&var, "DB_NAME", &mut database.name)?;
+        set_some(&var, "DB_USER", &mut database.user)?;
+        // A password from the environment replaces one from the file, whichever way
+        // each is given.
+        match (var("DB_PASSWORD"), var("DB_PASSWORD_FILE")) {
+            (Some(_), Some(_)) => bail!("set only one of DB_PASSWORD and DB_PASSWORD_FILE"),
+            (Some(password), None) => {
+                database.password = Some(password);
+                database.password_file = None;
+            }
+            (None, Some(file)) => {
+                database.password = None;
+                database.password_file = Some(file.into());
+            }
+            (None, None) => {}
+        }
+        set(&var, "DB_SSLMODE", &mut database.sslmode)?;
+        set_some(&var, "DB_SSLROOTCERT", &mut database.sslrootcert)?;
+        set_some(&var, "DB_SSLCERT", &mut database.sslcert)?;
+        set_some(&var, "DB_SSLKEY", &mut database.sslkey)?;
+        set(&var, "DB_MAX_CONNECTIONS", &mut database.max_connections)?;
+        set(&var, "DB_MIN_CONNECTIONS", &mut database.min_connections)?;
+        set(
+            &var,
+            "DB_ACQUIRE_TIMEOUT_SECONDS",
+            &mut database.acquire_timeout_seconds,
+        )?;
+        set(&var, "DB_CONNECT_ATTEMPTS", &mut database.connect_attempts)?;
+        if let Some(replicas) = var("DB_REPLICAS") {
+            database.replicas = list(&replicas);
+        }
+        set(
+            &var,
+            "DB_REPLICA_MAX_LAG_SECONDS",
+            &mut database.replica_max_lag_seconds,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn apply_args(&mut self, args: &ConfigArgs) {
+        let ConfigArgs {
+            bind,
+            port,
+            log_filter,
+            cors_origins,
+            cache_max_age,
+            shutdown_delay,
+            drain_timeout,
+            db_host,
+            db_port,
+            db_socket_dir,
+            db_name,
+            db_user,
+            db_password_file,
+            db_sslmode,
+            db_max_connections,
+            db_min_connections,
+        } = args.clone();
+        let (server, database) = (&mut self.server, &mut self.database);
+
+        server.bind = bind.unwrap_or(server.bind);
+        server.port = port.unwrap_or(server.port);
+        server.log_filter = log_filter.unwrap_or(server.log_filter.clone());
+        if !cors_origins.is_empty() {
+            server.cors_origins = cors_origins;
+        }
+        server.cache_max_age_seconds = cache_max_age.unwrap_or(server.cache_max_age_seconds);
+        server.shutdown_delay_seconds = shutdown_delay.unwrap_or(server.shutdown_delay_seconds);
+        server.drain_timeout_seconds = drain_timeout.unwrap_or(server.drain_timeout_seconds);
+
+        database.host = db_host.or(database.host.take());
+        database.port = db_port.unwrap_or(database.port);
+        database.socket_dir = db_socket_dir.or(database.socket_dir.take());
+        database.name = db_name.or(database.name.take());
+        database.user = db_user.or(database.user.take());
+        if db_password_file.is_some() {
+            database.password = None;
+            database.password_file = db_password_file;
+        }
+        database.sslmode = db_sslmode.unwrap_or(database.sslmode.clone());
+        database.max_connections = db_max_connections.unwrap_or(database.max_connections);
+        database.min_connections = db_min_connections.unwrap_or(database.min_connections);
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        EnvFilter::try_new(&self.server.log_filter)
+            .with_context(|| format!("invalid log filter `{}`", self.server.log_filter))?;
+        if self.server.cors_origins.is_empty() {
+            bail!("no CORS origins configured, use `*` to allow any");
+        }
+        for origin in &self.server.cors_origins {
+            if origin != "*" && origin.parse::<HeaderValue>().is_err() {
+                bail!("invalid CORS origin `{origin}`");
+            }
+        }
+
+        self.database.resolve()?;
+
+        Ok(())
+    }
+
+    /// A copy that is safe to print.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if config.database.password.is_some() {
+            config.database.password = Some(REDACTED.to_string());
+        }
+        config
+    }
+
+    /// The redacted configuration as TOML, as printed by `config check`.
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(&self.redacted())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn with_env(config: &mut Config, vars: &[(&str, &str)]) -> Result<()> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        config.apply_env(|name| vars.get(name).cloned())
+    }
+
+    fn database(vars: &[(&str, &str)]) -> Result<DbConfig> {
+        let mut config = Config::default();
+        with_env(&mut config, vars)?;
+        config.database.resolve()
+    }
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let mut config = Config::from_toml(
+            r#"
+            [server]
+            port = 8000
+            cors_origins = ["https://dashboard.example.com"]
+            cache_max_age_seconds = 60
+
+            [database]
+            host = "file-db"
+            name = "elmo"
+            user = "elmo"
+            password_file = "/run/secrets/db"
+            max_connections = 20
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 8000);
+        assert_eq!(config.server.bind, ServerConfig::default().bind);
+
+        with_env(
+            &mut config,
+            &[
+                ("PORT", "8080"),
+                ("DB_HOST", "env-db"),
+                ("DB_PASSWORD", "secret"),
+                ("RUST_LOG", "elmo_api=info"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.database.password_file, None);
+
+        config.apply_args(&ConfigArgs {
+            port: Some(9000),
+            db_max_connections: Some(5),
+            ..ConfigArgs::default()
+        });
+        config.validate().unwrap();
+
+        assert_eq!(config.server.address().to_string(), "0.0.0.0:9000");
+        assert_eq!(config.server.log_filter, "elmo_api=info");
+        assert_eq!(config.server.cache_max_age_seconds, 60);
+        let db = config.database.resolve().unwrap();
+        assert_eq!(db.host.as_deref(), Some("env-db"));
+        assert_eq!(db.password, "secret");
+        assert_eq!(db.max_connections, 5);
+
+        let printed = config.to_redacted_toml().unwrap();
+        assert!(printed.contains("password = \"<redacted>\""));
+        assert!(!printed.contains("secret"));
+        assert_eq!(Config::from_toml(&printed).unwrap(), config.redacted());
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        assert!(Config::from_toml("[server]\nprot = 3000").is_err());
+        assert!(Config::from_toml("[server]\nbind = \"localhost\"").is_err());
+        assert!(with_env(&mut Config::default(), &[("PORT", "http")]).is_err());
+
+        let mut config = Config::default();
+        with_env(
+            &mut config,
+            &[
+                ("DB_HOST", "db"),
+                ("DB_NAME", "elmo"),
+                ("DB_USER", "elmo"),
+                ("DB_PASSWORD", "secret"),
+            ],
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let mut bad = config.clone();
+        bad.server.log_filter = "elmo_api=loud".to_string();
+        assert!(bad.validate().is_err());
+        let mut bad = config.clone();
+        bad.server.cors_origins = vec!["https://a.example.com\n".to_string()];
+        assert!(bad.validate().is_err());
+        let mut bad = config;
+        bad.database.min_connections = 20;
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_db_config_defaults_and_errors() {
+        let base = [
+            ("DB_HOST", "db"),
+            ("DB_NAME", "elmo"),
+            ("DB_USER", "elmo"),
+            ("DB_PASSWORD", "secret"),
+        ];
+
+        let db = database(&base).unwrap();
+        assert_eq!(db.host.as_deref(), Some("db"));
+        assert_eq!(db.port, 5432);
+        assert!(matches!(db.ssl_mode, PgSslMode::Allow));
+
+        let err = |vars: &[(&str, &str)]| database(vars).unwrap_err().to_string();
+        assert_eq!(err(&base[1..]), "DB_HOST is not set");
+        assert_eq!(
+            err(&base[..3]),
+            "DB_PASSWORD or DB_PASSWORD_FILE is not set"
+        );
+        assert!(err(&[&base[..], &[("DB_PORT", "x")]].concat()).contains("DB_PORT"));
+        assert!(err(&[&base[..], &[("DB_SSLMODE", "on")]].concat()).contains("DB_SSLMODE"));
+        assert!(err(&[&base[..], &[("DB_SSLCERT", "client.crt")]].concat()).contains("DB_SSLKEY"));
+        assert!(
+            err(&[&base[..], &[("DB_PASSWORD_FILE", "/run/secret")]].concat()).contains("only one")
+        );
+    }
+
+    #[test]
+    fn test_db_config_socket_tls_and_password_file() {
+        let file = env::temp_dir().join(format!("elmo-db-password-{}", std::process::id()));
+        fs::write(&file, "from file\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        let db = database(&[
+            ("DB_SOCKET_DIR", "/cloudsql/project:region:instance"),
+            ("DB_NAME", "elmo"),
+            ("DB_USER", "elmo"),
+            ("DB_PASSWORD_FILE", file),
+        ])
+        .unwrap();
+        assert_eq!(db.host, None);
+        assert_eq!(db.password, "from file");
+        assert_eq!(
+            db.connect_options().get_socket().unwrap().to_str(),
+            Some("/cloudsql/project:region:instance")
+        );
+        fs::remove_file(file).unwrap();
+
+        let tls = [
+            ("DB_HOST", "db.example.com"),
+            ("DB_PORT", "6432"),
+            ("DB_NAME", "elmo"),
+            ("DB_USER", "elmo"),
+            ("DB_PASSWORD", "secret"),
+            ("DB_SSLMODE", "verify-full"),
+            ("DB_SSLROOTCERT", "/etc/elmo/ca.pem"),
+            ("DB_SSLCERT", "/etc/elmo/client.crt"),
+            ("DB_SSLKEY", "/etc/elmo/client.key"),
+        ];
+        let db = database(&tls).unwrap();
+        assert!(matches!(db.ssl_mode, PgSslMode::VerifyFull));
+        assert_eq!(db.connect_options().get_port(), 6432);
+
+        // TLS isn't available on the socket.
+        let err = database(&[&tls[..], &[("DB_SOCKET_DIR", "/cloudsql/x")]].concat()).unwrap_err();
+        assert!(err.to_string().contains("DB_SOCKET_DIR"));
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use sqlx::postgres::{PgConnectOptions, PgPool, PgSslMode};
+use tokio_util::sync::CancellationToken;
+
+use crate::timeouts;
+
+/// How long a connectivity check may take before the database counts as down.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the server checks the database once it is up.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Connection settings for the main database, resolved from the `[database]` section
+/// of [`Config`](crate::config::Config).
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    /// Required unless connecting through `socket_dir`.
+    pub host: Option<String>,
+    pub port: u16,
+    /// Directory holding the server's Unix socket, e.g. the one Cloud Run mounts
+    /// under `/cloudsql` for `--set-cloudsql-instances`. Takes precedence over `host`.
+    pub socket_dir: Option<PathBuf>,
+    pub name: String,
+    pub user: String,
+    pub password: String,
+    pub ssl_mode: PgSslMode,
+    /// CA bundle the server certificate is checked against.
+    pub ssl_root_cert: Option<PathBuf>,
+    /// Client certificate and key, for servers that require them.
+    pub ssl_client_cert: Option<PathBuf>,
+    pub ssl_client_key: Option<PathBuf>,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout: Duration,
+}
+
+impl DbConfig {
+    /// Checks that the settings fit together.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let needs_tls = matches!(
+            self.ssl_mode,
+            PgSslMode::Require | PgSslMode::VerifyCa | PgSslMode::VerifyFull
+        );
+
+        // Postgres never offers TLS on its Unix socket.
+        if self.socket_dir.is_some() && needs_tls {
+            bail!("DB_SSLMODE must be disable, allow or prefer with DB_SOCKET_DIR");
+        }
+        if self.ssl_client_cert.is_some() != self.ssl_client_key.is_some() {
+            bail!("DB_SSLCERT and DB_SSLKEY must be set together");
+        }
+        if matches!(self.ssl_mode, PgSslMode::VerifyFull) && self.host.is_none() {
+            bail!("DB_SSLMODE=verify-full needs DB_HOST to check the certificate against");
+        }
+
+        Ok(())
+    }
+
+    pub fn connect_options(&self) -> PgConnectOptions {
+        let mut options = PgConnectOptions::new()
+            .port(self.port)
+            .database(&self.name)
+            .username(&self.user)
+            .password(&self.password)
+            .ssl_mode(self.ssl_mode);
+
+        if let Some(host) = &self.host {
+            options = options.host(host);
+        }
+        if let Some(dir) = &self.socket_dir {
+            options = options.socket(dir);
+        }
+        if let Some(cert) = &self.ssl_root_cert {
+            options = options.ssl_root_cert(cert);
+        }
+        if let Some(cert) = &self.ssl_client_cert {
+            options = options.ssl_client_cert(cert);
+        }
+        if let Some(key) = &self.ssl_client_key {
+            options = options.ssl_client_key(key);
+        }
+
+        options
+    }
+
+    /// A pool that opens connections on first use, so it can be built while the
+    /// database is still starting. Connections idle for more than 30 seconds are checked
+    /// before they are handed out, so the pool replaces the ones a database restart broke.
+    pub fn lazy_pool(&self) -> PgPool {
+        let options = self.connect_options();
+
+        timeouts::pool_options(&options)
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .connect_lazy_with(options)
+    }
+}
+
+/// Exponential backoff between connection attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay after failed attempt `attempt` (0-based): `initial` doubled per attempt
+    /// up to `max`, then scaled to between half and all of that by `jitter` (0 to 1), so
+    /// instances restarted together don't retry in lockstep.
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let base = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max);
+
+        base.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// A random number between 0 and 1, from the standard library's randomly keyed hasher.
+fn jitter() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+async fn ping(pool: &PgPool) -> Result<()> {
+    tokio::time::timeout(PING_TIMEOUT, sqlx::query("SELECT 1").execute(pool))
+        .await
+        .map_err(|_| anyhow!("timed out after {:?}", PING_TIMEOUT))??;
+
+    Ok(())
+}
+
+/// Waits until the database answers, retrying with backoff. Gives up after
+/// `max_attempts`, or never if it is `None`.
+pub async fn wait_for_db(pool: &PgPool, max_attempts: Option<u32>) -> Result<()> {
+    let backoff = Backoff::default();
+    let mut attempt = 0;
+
+    loop {
+        let error = match ping(pool).await {
+            Ok(()) => {
+                if attempt > 0 {
+                    tracing::info!("Connected to the database after {} attempts", attempt + 1);
+                }
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        attempt += 1;
+        if max_attempts.is_some_and(|max| attempt >= max) {
+            return Err(error).context(format!("database is unreachable after {attempt} attempts"));
+        }
+
+        let delay = backoff.delay(attempt - 1, jitter());
+        tracing::warn!(
+            "Database is unreachable ({:#}), retrying in {:?}",
+            error,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether the main database is reachable, shared between the monitor and the
+/// handlers.
+#[derive(Debug, Clone, Default)]
+pub struct DbStatus(Arc<AtomicBool>);
+
+impl DbStatus {
+    pub fn new(up: bool) -> Self {
+        DbStatus(Arc::new(AtomicBool::new(up)))
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Records the database as up or down, returning the previous state.
+    pub fn set_up(&self, up: bool) -> bool {
+        self.0.swap(up, Ordering::Relaxed)
+    }
+}
+
+/// Checks the database every few seconds until `shutdown` is cancelled, and logs when
+/// it goes away or comes back.
+pub async fn monitor(pool: PgPool, status: DbStatus, shutdown: CancellationToken) {
+    let mut ticker = tokio::time::interval(MONITOR_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        match ping(&pool).await {
+            Ok(()) => {
+                if !status.set_up(true) {
+                    tracing::info!("Database connection restored");
+                }
+            }
+            Err(e) => {
+                if status.set_up(false) {
+                    tracing::error!("Error: lost the database connection: {:#}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Middleware for the endpoints that need the database: answers 503 while it is down,
+/// instead of making each request wait for a connection that won't come.
+pub async fn require_db(
+    State(status): State<DbStatus>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    if !status.is_up() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "database unavailable".to_string(),
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Liveness: the process is up and serving, whatever the database is doing.
+pub async fn get_health() -> &'static str {
+    "ok"
+}
+
+/// Readiness: 503 until the database is reachable, and again once the server starts
+/// shutting down, so that load balancers stop sending requests before it stops
+/// accepting them.
+pub async fn get_ready(
+    State(status): State<DbStatus>,
+    State(shutdown): State<CancellationToken>,
+) -> (StatusCode, &'static str) {
+    if shutdown.is_cancelled() {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+    } else if status.is_up() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let backoff = Backoff::default();
+
+        assert_eq!(backoff.delay(0, 1.0), Duration::from_millis(500));
+        assert_eq!(backoff.delay(3, 1.0), Duration::from_secs(4));
+        assert_eq!(backoff.delay(10, 1.0), Duration::from_secs(30));
+        assert_eq!(backoff.delay(u32::MAX, 1.0), Duration::from_secs(30));
+        // Jitter takes off up to half.
+        assert_eq!(backoff.delay(3, 0.0), Duration::from_secs(2));
+        assert!((0.0..=1.0).contains(&jitter()));
+    }
+
+    #[test]
+    fn test_db_status() {
+        let status = DbStatus::default();
+        assert!(!status.is_up());
+
+        assert!(!status.set_up(true));
+        assert!(status.clone().is_up());
+        assert!(status.set_up(false));
+    }
+}
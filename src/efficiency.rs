@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::routes::TimeRange;
+
+/// seff-style efficiency for one account, user or partition.
+#[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct Efficiency {
+    pub name: Option<String>,
+    pub jobs: Option<i64>,
+    pub cpu_efficiency: Option<f64>,
+    pub mem_efficiency: Option<f64>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub over_requesting: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EfficiencyGroup {
+    #[default]
+    Account,
+    User,
+    Partition,
+}
+
+impl EfficiencyGroup {
+    fn column(self) -> &'static str {
+        match self {
+            EfficiencyGroup::Account => "account",
+            EfficiencyGroup::User => "user_name",
+            EfficiencyGroup::Partition => "partition",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EfficiencyQuery {
+    pub group: Option<EfficiencyGroup>,
+    pub threshold: Option<f64>,
+    pub min_jobs: Option<i64>,
+    pub flagged: Option<bool>,
+}
+
+/// Groups whose CPU or memory efficiency is below this are flagged as over-requesting.
+const DEFAULT_THRESHOLD: f64 = 0.5;
+
+/// A group needs at least this many jobs before it is flagged, so a single badly
+/// sized job doesn't mark a whole account.
+const DEFAULT_MIN_JOBS: i64 = 10;
+
+/// Whether a group consistently requests more than it uses.
+fn is_over_requesting(efficiency: &Efficiency, threshold: f64, min_jobs: i64) -> bool {
+    if efficiency.jobs.unwrap_or(0) < min_jobs {
+        return false;
+    }
+
+    [efficiency.cpu_efficiency, efficiency.mem_efficiency]
+        .iter()
+        .flatten()
+        .any(|value| *value < threshold)
+}
+
+pub async fn get_efficiency(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(params): Query<EfficiencyQuery>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<Efficiency>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let group = params.group.unwrap_or_default();
+    let threshold = params.threshold.unwrap_or(DEFAULT_THRESHOLD);
+    let min_jobs = params.min_jobs.unwrap_or(DEFAULT_MIN_JOBS);
+
+    // Efficiencies are ratios of sums rather than averages of per-job ratios, so
+    // long, wide jobs weigh more than short ones, as they do on the cluster.
+    let query = format!(
+        r#"
+        SELECT
+            {column} as name,
+            COUNT(*) as jobs,
+            SUM(cpu_time_seconds)::float8
+                / NULLIF(SUM(elapsed_seconds * cpus) FILTER (WHERE cpu_time_seconds IS NOT NULL), 0) as cpu_efficiency,
+            (SUM(max_rss_bytes) FILTER (WHERE mem_requested_bytes IS NOT NULL))::float8
+                / NULLIF(SUM(mem_requested_bytes) FILTER (WHERE max_rss_bytes IS NOT NULL), 0) as mem_efficiency
+        FROM
+            {schema}.jobs
+        WHERE end_time IS NOT NULL
+          AND elapsed_seconds > 0
+          AND {column} IS NOT NULL
+          AND ($1::timestamp IS NULL OR end_time BETWEEN $1 AND $2)
+        GROUP BY {column}
+        ORDER BY name
+        "#,
+        schema = cluster.schema,
+        column = group.column()
+    );
+
+    let (start, end) = time_range.bounds();
+
+    let mut efficiency = sqlx::query_as::<_, Efficiency>(&query)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&cluster.read_pool())
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to get job efficiency: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    for row in efficiency.iter_mut() {
+        row.over_requesting = is_over_requesting(row, threshold, min_jobs);
+    }
+
+    if params.flagged == Some(true) {
+        efficiency.retain(|row| row.over_requesting);
+    }
+
+    Ok(Json(efficiency))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn efficiency(jobs: i64, cpu: Option<f64>, mem: Option<f64>) -> Efficiency {
+        Efficiency {
+            name: Some("ccv".to_string()),
+            jobs: Some(jobs),
+            cpu_efficiency: cpu,
+            mem_efficiency: mem,
+            over_requesting: false,
+        }
+    }
+
+    #[test]
+    fn test_low_memory_efficiency_is_flagged() {
+        let row = efficiency(50, Some(0.9), Some(0.1));
+
+        assert!(is_over_requesting(&row, 0.5, 10));
+    }
+
+    #[test]
+    fn test_few_jobs_are_not_flagged() {
+        let row = efficiency(3, Some(0.05), Some(0.05));
+
+        assert!(!is_over_requesting(&row, 0.5, 10));
+    }
+
+    #[test]
+    fn test_missing_efficiency_is_not_flagged() {
+        let row = efficiency(50, None, Some(0.8));
+
+        assert!(!is_over_requesting(&row, 0.5, 10));
+    }
+}
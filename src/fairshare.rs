@@ -0,0 +1,294 @@
+use chrono::NaiveDateTime;
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::ingest::{is_ndjson, keep_last, parse_batch, IngestReport};
+use crate::routes::TimeRange;
+
+/// One `sshare` snapshot for an account, or for a user within an account.
+#[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct FairshareSnapshot {
+    pub time: Option<NaiveDateTime>,
+    pub account: Option<String>,
+    pub user_name: Option<String>,
+    pub raw_shares: Option<i64>,
+    pub effective_usage: Option<f64>,
+    pub fairshare: Option<f64>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Fairshare {
+    pub current: Option<FairshareSnapshot>,
+    pub history: Vec<FairshareSnapshot>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FairshareQuery {
+    pub account: Option<String>,
+    pub user: Option<String>,
+}
+
+/// An `sshare` row as sent to `POST /fairshare/samples`. Rows without a user are the
+/// account's own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FairshareSample {
+    pub time: NaiveDateTime,
+    pub account: String,
+    #[serde(default, alias = "user")]
+    pub user_name: Option<String>,
+    pub raw_shares: i64,
+    pub effective_usage: f64,
+    pub fairshare: f64,
+}
+
+impl FairshareSample {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.account.trim().is_empty() {
+            return Err("account is empty".to_string());
+        }
+        if self.raw_shares < 0 {
+            return Err("raw_shares must not be negative".to_string());
+        }
+        if !(self.effective_usage >= 0.0 && self.effective_usage.is_finite()) {
+            return Err("effective_usage must be a non-negative number".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.fairshare) {
+            return Err(format!(
+                "fairshare ({}) must be between 0 and 1",
+                self.fairshare
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Each row binds 6 parameters and Postgres allows at most 65535 per statement.
+const UPSERT_CHUNK_SIZE: usize = 5000;
+
+/// Upserts snapshots into `schema`, keyed on account, user and time.
+pub async fn upsert_fairshare(
+    pool: &PgPool,
+    schema: &str,
+    samples: &[FairshareSample],
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut affected = 0;
+
+    for chunk in samples.chunks(UPSERT_CHUNK_SIZE) {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "INSERT INTO {schema}.fairshare (time, account, user_name, raw_shares, \
+             effective_usage, fairshare) "
+        ));
+        query.push_values(chunk, |mut row, sample| {
+            row.push_bind(sample.time)
+                .push_bind(&sample.account)
+                .push_bind(sample.user_name.as_deref().unwrap_or_default())
+                .push_bind(sample.raw_shares)
+                .push_bind(sample.effective_usage)
+                .push_bind(sample.fairshare);
+        });
+        query.push(
+            " ON CONFLICT (account, user_name, time) DO UPDATE SET \
+             raw_shares = EXCLUDED.raw_shares, \
+             effective_usage = EXCLUDED.effective_usage, \
+             fairshare = EXCLUDED.fairshare",
+        );
+
+        affected += query.build().execute(&mut *tx).await?.rows_affected();
+    }
+
+    tx.commit().await?;
+
+    Ok(affected)
+}
+
+/// Validates a batch of snapshots; the last row per account, user and time wins.
+pub fn prepare_fairshare(
+    rows: Vec<Result<FairshareSample, String>>,
+) -> (Vec<FairshareSample>, IngestReport) {
+    keep_last(rows, FairshareSample::validate, |sample| {
+        (
+            sample.account.clone(),
+            sample.user_name.clone().unwrap_or_default(),
+            sample.time,
+        )
+    })
+}
+
+pub async fn post_fairshare_samples(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(cluster): Query<ClusterQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let rows = parse_batch(&body, is_ndjson(&headers))
+        .map_err(|reason| (StatusCode::BAD_REQUEST, reason))?;
+    let (samples, report) = prepare_fairshare(rows);
+
+    upsert_fairshare(&cluster.pool, &cluster.schema, &samples)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to insert fairshare snapshots: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    tracing::info!(
+        "Ingested fairshare snapshots: {} accepted, {} rejected",
+        report.accepted,
+        report.rejected
+    );
+
+    Ok(Json(report))
+}
+
+pub async fn get_fairshare(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(params): Query<FairshareQuery>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Fairshare>, (StatusCode, String)> {
+    let Some(account) = params.account else {
+        return Err((StatusCode::BAD_REQUEST, "account is required".to_string()));
+    };
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let pool = cluster.read_pool();
+    // Account-level snapshots are stored with an empty user name.
+    let user = params.user.unwrap_or_default();
+
+    let current_query = format!(
+        r#"
+        SELECT
+            time,
+            account,
+            NULLIF(user_name, '') as user_name,
+            raw_shares,
+            effective_usage,
+            fairshare
+        FROM
+            {schema}.fairshare
+        WHERE account = $1 AND user_name = $2
+        ORDER BY time DESC
+        LIMIT 1
+        "#,
+        schema = cluster.schema
+    );
+
+    let history_query = format!(
+        r#"
+        SELECT
+            time,
+            account,
+            NULLIF(user_name, '') as user_name,
+            raw_shares,
+            effective_usage,
+            fairshare
+        FROM
+            {schema}.fairshare
+        WHERE account = $1 AND user_name = $2
+          AND ($3::timestamp IS NULL OR time BETWEEN $3 AND $4)
+        ORDER BY time
+        "#,
+        schema = cluster.schema
+    );
+
+    let current = sqlx::query_as::<_, FairshareSnapshot>(&current_query)
+        .bind(&account)
+        .bind(&user)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to get current fairshare: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    let (start, end) = time_range.bounds();
+
+    let history = sqlx::query_as::<_, FairshareSnapshot>(&history_query)
+        .bind(&account)
+        .bind(&user)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to get fairshare history: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(Fairshare { current, history }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Uri;
+
+    #[test]
+    fn test_fairshare_query_parses_account_and_user() {
+        let uri: Uri = "/fairshare?account=ccv&user=alice".parse().unwrap();
+        let Query(params) = Query::<FairshareQuery>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(params.account.as_deref(), Some("ccv"));
+        assert_eq!(params.user.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_fairshare_requires_account() {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let clusters = Arc::new(ClusterRegistry::single(pool));
+        let time_range = TimeRange {
+            start: None,
+            end: None,
+        };
+        let params = FairshareQuery {
+            account: None,
+            user: None,
+        };
+
+        let cluster = ClusterQuery { cluster: None };
+
+        let result = get_fairshare(
+            State(clusters),
+            Query(time_range),
+            Query(params),
+            Query(cluster),
+        )
+        .await;
+
+        assert_eq!(result.unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_prepare_fairshare_validates_rows() {
+        let body = br#"[
+            {"time": "2024-03-27T00:00:00", "account": "ccv", "raw_shares": 100,
+             "effective_usage": 0.25, "fairshare": 0.6},
+            {"time": "2024-03-27T00:00:00", "account": "ccv", "user": "alice",
+             "raw_shares": 1, "effective_usage": 0.1, "fairshare": 1.5},
+            {"time": "2024-03-27T00:00:00", "account": "ccv", "user": "bob",
+             "raw_shares": 1, "effective_usage": 0.05, "fairshare": 0.7}
+        ]"#;
+
+        let (samples, report) = prepare_fairshare(parse_batch(body, false).unwrap());
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].user_name, None);
+        assert_eq!(samples[1].user_name.as_deref(), Some("bob"));
+        assert_eq!(report.rejected, 1);
+        assert_eq!(
+            report.errors[0].reason,
+            "fairshare (1.5) must be between 0 and 1"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use chrono::NaiveDateTime;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::quality::{self, Quarantined};
+use crate::routes::Resource;
+
+/// A utilization sample as sent to the ingestion API. This is a `Utilization` record
+/// plus the GPU model for GPU samples.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub time: Option<NaiveDateTime>,
+    pub allocated: Option<i32>,
+    pub total: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu_type: Option<String>,
+}
+
+/// A sample that passed validation and is ready to be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidSample {
+    pub time: NaiveDateTime,
+    pub allocated: i32,
+    pub total: i32,
+    pub gpu_type: String,
+}
+
+/// What to do when a different sample is already stored at the same time (and GPU
+/// type).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Keep the stored sample and hand the new one back as a conflict.
+    Keep,
+    /// Overwrite the stored sample, as releasing a quarantined sample does.
+    Replace,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RowError {
+    /// Position of the row in the batch, starting at 0. Blank NDJSON lines don't count.
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Per-batch outcome returned by the write endpoints. Quarantined rows are listed in
+/// `errors` too, with their reason prefixed by `quarantined: `.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IngestReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    #[serde(default)]
+    pub quarantined: usize,
+    pub errors: Vec<RowError>,
+}
+
+impl IngestReport {
+    pub fn reject(&mut self, index: usize, reason: impl Into<String>) {
+        self.rejected += 1;
+        self.errors.push(RowError {
+            index,
+            reason: reason.into(),
+        });
+    }
+
+    pub fn quarantine(&mut self, index: usize, reason: &str) {
+        self.quarantined += 1;
+        self.errors.push(RowError {
+            index,
+            reason: format!("quarantined: {reason}"),
+        });
+    }
+}
+
+impl Sample {
+    pub fn validate(self, resource: Resource) -> Result<ValidSample, String> {
+        let time = self.time.ok_or("time is missing")?;
+        let allocated = self.allocated.ok_or("allocated is missing")?;
+        let total = self.total.ok_or("total is missing")?;
+
+        if resource != Resource::Gpu && self.gpu_type.is_some() {
+            return Err("gpu_type is only valid for gpu samples".to_string());
+        }
+
+        Ok(ValidSample {
+            time,
+            allocated,
+            total,
+            gpu_type: self.gpu_type.unwrap_or_default(),
+        })
+    }
+}
+
+/// Splits a request body into rows. NDJSON bodies are parsed line by line so one bad
+/// line only rejects that row; JSON bodies must be an array.
+pub fn parse_batch<T: DeserializeOwned>(
+    body: &[u8],
+    ndjson: bool,
+) -> Result<Vec<Result<T, String>>, String> {
+    if ndjson {
+        let body = std::str::from_utf8(body).map_err(|e| format!("body is not UTF-8: {e}"))?;
+
+        return Ok(body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str::<T>(line).map_err(|e| e.to_string()))
+            .collect());
+    }
+
+    let rows: Vec<serde_json::Value> =
+        serde_json::from_slice(body).map_err(|e| format!("expected a JSON array: {e}"))?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| serde_json::from_value::<T>(row).map_err(|e| e.to_string()))
+        .collect())
+}
+
+/// Validates parsed rows and screens them with the data-quality checks, returning the
+/// clean samples with their row index. When the same sample key appears more than once
+/// the last row wins, since a single INSERT .. ON CONFLICT can't update a row twice;
+/// earlier rows with different values are quarantined rather than dropped.
+pub fn prepare_batch(
+    resource: Resource,
+    rows: Vec<Result<Sample, String>>,
+) -> (Vec<(usize, ValidSample)>, Vec<Quarantined>, IngestReport) {
+    let mut report = IngestReport::default();
+    let mut latest: HashMap<(NaiveDateTime, String), usize> = HashMap::new();
+    let mut valid: Vec<(usize, ValidSample)> = Vec::with_capacity(rows.len());
+
+    for (index, row) in rows.into_iter().enumerate() {
+        match row.and_then(|sample| sample.validate(resource)) {
+            Ok(sample) => {
+                latest.insert((sample.time, sample.gpu_type.clone()), valid.len());
+                valid.push((index, sample));
+            }
+            Err(reason) => report.reject(index, reason),
+        }
+    }
+
+    let mut winners = Vec::with_capacity(latest.len());
+    let mut quarantined = Vec::new();
+    for (position, (index, sample)) in valid.iter().enumerate() {
+        let (winner_index, winner) = &valid[latest[&(sample.time, sample.gpu_type.clone())]];
+        if winner_index == index {
+            winners.push((*index, sample.clone()));
+        } else if (winner.allocated, winner.total) == (sample.allocated, sample.total) {
+            report.reject(
+                *index,
+                format!("duplicate sample, superseded by row {winner_index}"),
+            );
+        } else {
+            let reason = format!("conflicting duplicate, superseded by row {winner_index}");
+            report.quarantine(*index, &reason);
+            quarantined.push(Quarantined {
+                resource,
+                sample: valid[position].1.clone(),
+                reason,
+            });
+        }
+    }
+
+    let (samples, suspects) = quality::screen(resource, winners, quality::now(), &mut report);
+    quarantined.extend(suspects);
+
+    report.errors.sort_by_key(|error| error.index);
+    report.accepted = samples.len();
+
+    (samples, quarantined, report)
+}
+
+/// Validates rows that skip the utilization quality checks, such as storage and
+/// fairshare snapshots. When `key` repeats the last row wins, since a single
+/// INSERT .. ON CONFLICT can't update a row twice.
+pub fn keep_last<T, K: Eq + Hash>(
+    rows: Vec<Result<T, String>>,
+    validate: impl Fn(&T) -> Result<(), String>,
+    key: impl Fn(&T) -> K,
+) -> (Vec<T>, IngestReport) {
+    let mut report = IngestReport::default();
+    let mut latest: HashMap<K, usize> = HashMap::new();
+    let mut valid = Vec::with_capacity(rows.len());
+
+    for (index, row) in rows.into_iter().enumerate() {
+        match row.and_then(|row| validate(&row).map(|()| row)) {
+            Ok(row) => {
+                latest.insert(key(&row), index);
+                valid.push((index, row));
+            }
+            Err(reason) => report.reject(index, reason),
+        }
+    }
+
+    let mut kept = Vec::with_capacity(latest.len());
+    for (index, row) in valid {
+        let winner = latest[&key(&row)];
+        if winner == index {
+            kept.push(row);
+        } else {
+            report.reject(index, format!("duplicate row, superseded by row {winner}"));
+        }
+    }
+
+    report.errors.sort_by_key(|error| error.index);
+    report.accepted = kept.len();
+
+    (kept, report)
+}
+
+// Each row binds at most 4 parameters and Postgres allows at most 65535 per statement.
+const UPSERT_CHUNK_SIZE: usize = 10_000;
+
+/// Writes samples for `resource` into `schema`, and quarantined samples into its
+/// quarantine table, in a single transaction. Re-sending a stored sample is harmless;
+/// a sample that differs from the stored one is quarantined and moved from accepted to
+/// quarantined in `report`.
+pub async fn upsert_samples(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+    samples: &[(usize, ValidSample)],
+    quarantined: &[Quarantined],
+    report: &mut IngestReport,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let conflicts = insert_samples(&mut tx, schema, resource, samples, OnConflict::Keep).await?;
+    let conflicts = quality::quarantine_conflicts(resource, conflicts, report);
+    quality::insert_quarantined(&mut tx, schema, quarantined).await?;
+    quality::insert_quarantined(&mut tx, schema, &conflicts).await?;
+    tx.commit().await
+}
+
+/// Inserts samples on an existing connection, so callers can write several resources
+/// in one transaction, and returns the ones that were not written because a different
+/// sample is stored at their time (and GPU type).
+pub async fn insert_samples(
+    conn: &mut PgConnection,
+    schema: &str,
+    resource: Resource,
+    samples: &[(usize, ValidSample)],
+    on_conflict: OnConflict,
+) -> Result<Vec<(usize, ValidSample)>, sqlx::Error> {
+    // With `Keep`, an identical sample still "updates" its row so that RETURNING lists
+    // it; only the rows left out of RETURNING conflict.
+    let table = resource.table();
+    let keep = match on_conflict {
+        OnConflict::Keep => format!(
+            " WHERE ({table}.allocated, {table}.total) = (EXCLUDED.allocated, EXCLUDED.total)"
+        ),
+        OnConflict::Replace => String::new(),
+    };
+    let mut written: HashSet<(NaiveDateTime, String)> = HashSet::with_capacity(samples.len());
+
+    for chunk in samples.chunks(UPSERT_CHUNK_SIZE) {
+        let mut query = QueryBuilder::<Postgres>::new("");
+
+        match resource {
+            Resource::Cpu => {
+                query.push(format!(
+                    "INSERT INTO {schema}.cpu (time, allocated, total) "
+                ));
+                query.push_values(chunk, |mut row, (_, sample)| {
+                    row.push_bind(sample.time)
+                        .push_bind(sample.allocated)
+                        .push_bind(sample.total);
+                });
+                query.push(format!(
+                    " ON CONFLICT (time) DO UPDATE SET \
+                     allocated = EXCLUDED.allocated, \
+                     total = EXCLUDED.total{keep} \
+                     RETURNING time, ''::text"
+                ));
+            }
+            Resource::Gpu => {
+                query.push(format!(
+                    "INSERT INTO {schema}.gpu (time, gpu_type, allocated, total) "
+                ));
+                query.push_values(chunk, |mut row, (_, sample)| {
+                    row.push_bind(sample.time)
+                        .push_bind(sample.gpu_type.clone())
+                        .push_bind(sample.allocated)
+                        .push_bind(sample.total);
+                });
+                query.push(format!(
+                    " ON CONFLICT (time, gpu_type) DO UPDATE SET \
+                     allocated = EXCLUDED.allocated, \
+                     total = EXCLUDED.total{keep} \
+                     RETURNING time, gpu_type"
+                ));
+            }
+        }
+
+        written.extend(
+            query
+                .build_query_as::<(NaiveDateTime, String)>()
+                .fetch_all(&mut *conn)
+                .await?,
+        );
+    }
+
+    Ok(samples
+        .iter()
+        .filter(|(_, sample)| !written.contains(&(sample.time, sample.gpu_type.clone())))
+        .cloned()
+        .collect())
+}
+
+pub fn is_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            let value = value.split(';').next().unwrap_or_default().trim();
+            value == "application/x-ndjson" || value == "application/jsonl"
+        })
+        .unwrap_or(false)
+}
+
+pub async fn post_samples(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Path(resource): Path<Resource>,
+    Query(cluster): Query<ClusterQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let rows = parse_batch(&body, is_ndjson(&headers))
+        .map_err(|reason| (StatusCode::BAD_REQUEST, reason))?;
+
+    let (samples, quarantined, mut report) = prepare_batch(resource, rows);
+
+    upsert_samples(
+        &cluster.pool,
+        &cluster.schema,
+        resource,
+        &samples,
+        &quarantined,
+        &mut report,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to insert {:?} samples: {:?}", resource, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    tracing::info!(
+        "Ingested {:?} samples: {} accepted, {} quarantined, {} rejected",
+        resource,
+        report.accepted,
+        report.quarantined,
+        report.rejected
+    );
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ndjson_rejects_only_bad_lines() {
+        let body = br#"{"time": "2024-03-27T00:00:00", "allocated": 75, "total": 100}
+
+not json
+{"time": "2024-03-27T00:15:00", "allocated": 80, "total": 100}
+"#;
+
+        let rows = parse_batch::<Sample>(body, true).unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].is_ok());
+        assert!(rows[1].is_err());
+        assert!(rows[2].is_ok());
+    }
+
+    #[test]
+    fn test_parse_json_requires_array() {
+        assert!(parse_batch::<Sample>(br#"{"time": "2024-03-27T00:00:00"}"#, false).is_err());
+        assert_eq!(parse_batch::<Sample>(b"[]", false).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_prepare_batch_validates_rows() {
+        let body = br#"[
+            {"time": "2024-03-27T00:00:00", "allocated": 75, "total": 100},
+            {"time": "2024-03-27T00:15:00", "allocated": 120, "total": 100},
+            {"time": "2024-03-27T00:30:00", "allocated": -1, "total": 100},
+            {"allocated": 10, "total": 100},
+            {"time": "2024-03-27T00:45:00", "allocated": 1, "total": 2, "gpu_type": "a100"}
+        ]"#;
+
+        let (samples, quarantined, report) =
+            prepare_batch(Resource::Cpu, parse_batch(body, false).unwrap());
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(report.accepted, 1);
+        assert_eq!(report.rejected, 2);
+        assert_eq!(report.quarantined, 2);
+        assert_eq!(
+            report.errors[0].reason,
+            "quarantined: allocated (120) exceeds total (100)"
+        );
+        assert_eq!(quarantined[1].sample.allocated, -1);
+        assert_eq!(report.errors[2].reason, "time is missing");
+        assert_eq!(
+            report.errors[3].reason,
+            "gpu_type is only valid for gpu samples"
+        );
+    }
+
+    #[test]
+    fn test_prepare_batch_keeps_last_duplicate() {
+        let body = br#"[
+            {"time": "2024-03-27T00:00:00", "allocated": 1, "total": 8, "gpu_type": "a100"},
+            {"time": "2024-03-27T00:00:00", "allocated": 2, "total": 8, "gpu_type": "v100"},
+            {"time": "2024-03-27T00:00:00", "allocated": 3, "total": 8, "gpu_type": "a100"}
+        ]"#;
+
+        let (samples, quarantined, report) =
+            prepare_batch(Resource::Gpu, parse_batch(body, false).unwrap());
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].1.gpu_type, "v100");
+        assert_eq!(samples[1].0, 2);
+        assert_eq!(samples[1].1.allocated, 3);
+        assert_eq!(report.quarantined, 1);
+        assert_eq!(report.errors[0].index, 0);
+        assert_eq!(
+            quarantined[0].reason,
+            "conflicting duplicate, superseded by row 2"
+        );
+    }
+
+    #[test]
+    fn test_prepare_batch_drops_identical_duplicates() {
+        let body = br#"[
+            {"time": "2024-03-27T00:00:00", "allocated": 1, "total": 8},
+            {"time": "2024-03-27T00:00:00", "allocated": 1, "total": 8}
+        ]"#;
+
+        let (samples, quarantined, report) =
+            prepare_batch(Resource::Cpu, parse_batch(body, false).unwrap());
+
+        assert_eq!(samples.len(), 1);
+        assert!(quarantined.is_empty());
+        assert_eq!(report.rejected, 1);
+        assert_eq!(
+            report.errors[0].reason,
+            "duplicate sample, superseded by row 1"
+        );
+    }
+
+    #[test]
+    fn test_ndjson_content_type() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_ndjson(&headers));
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/x-ndjson; charset=utf-8".parse().unwrap(),
+        );
+        assert!(is_ndjson(&headers));
+    }
+}
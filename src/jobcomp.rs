@@ -0,0 +1,233 @@
+use chrono::{DateTime, NaiveDateTime};
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::HeaderMap,
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::collector::sacct::{parse_memory, parse_tres};
+use crate::ingest::{is_ndjson, parse_batch, IngestReport};
+use crate::jobs::{prepare_jobs, upsert_jobs, JobRecord};
+use crate::quality;
+
+/// Slurm marks unset array task IDs with `NO_VAL`.
+const NO_VAL: i64 = 0xffff_fffe;
+
+/// A job-completion document as sent by Slurm's `jobcomp/elasticsearch` and
+/// `jobcomp/kafka` plugins. Only the fields we store are read.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobCompDocument {
+    #[serde(alias = "job_id")]
+    pub jobid: Option<i64>,
+    pub array_job_id: Option<i64>,
+    pub array_task_id: Option<i64>,
+    pub account: Option<String>,
+    pub username: Option<String>,
+    pub partition: Option<String>,
+    #[serde(rename = "@submit")]
+    pub submit: Option<String>,
+    #[serde(rename = "@start")]
+    pub start: Option<String>,
+    #[serde(rename = "@end")]
+    pub end: Option<String>,
+    pub elapsed: Option<i64>,
+    pub total_cpus: Option<i32>,
+    pub state: Option<String>,
+    pub tres_alloc: Option<String>,
+}
+
+/// Parses the plugins' timestamps, which are either local (`2024-03-27T00:00:00`) or
+/// carry an offset, in which case they are converted to local time like the rest of
+/// the store.
+fn parse_time(value: Option<&str>) -> Result<Option<NaiveDateTime>, String> {
+    let Some(value) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+
+    if let Ok(time) = value.parse::<NaiveDateTime>() {
+        return Ok(Some(time));
+    }
+
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%z"))
+        .map(|time| Some(quality::stored_time(time)))
+        .map_err(|_| format!("invalid time `{value}`"))
+}
+
+impl JobCompDocument {
+    /// Maps the document onto a job record, using the same `job_id` as the sacct
+    /// importer (`2000_1` for array tasks) so both sources update the same row.
+    pub fn into_record(self) -> Result<JobRecord, String> {
+        let jobid = self.jobid.ok_or("jobid is missing")?;
+        let job_id = match (self.array_job_id, self.array_task_id) {
+            (Some(array_job_id), Some(task_id)) if array_job_id > 0 && task_id < NO_VAL => {
+                format!("{array_job_id}_{task_id}")
+            }
+            _ => jobid.to_string(),
+        };
+
+        let tres = parse_tres(self.tres_alloc.as_deref().unwrap_or_default());
+
+        Ok(JobRecord {
+            job_id,
+            account: self.account.unwrap_or_default(),
+            user_name: self.username.unwrap_or_default(),
+            partition: self.partition.filter(|p| !p.is_empty()),
+            submit_time: parse_time(self.submit.as_deref())?,
+            start_time: parse_time(self.start.as_deref())?,
+            end_time: parse_time(self.end.as_deref())?,
+            cpus: self
+                .total_cpus
+                .or_else(|| tres.get("cpu").and_then(|cpus| cpus.parse().ok())),
+            // Without `tres_alloc` the GPU count is unknown; with it, no GPUs means 0.
+            gpus: self.tres_alloc.as_ref().map(|_| {
+                tres.get("gres/gpu")
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(0)
+            }),
+            // `CANCELLED by 1234` -> `CANCELLED`
+            state: self
+                .state
+                .as_deref()
+                .and_then(|state| state.split_whitespace().next())
+                .map(String::from),
+            elapsed_seconds: self.elapsed,
+            cpu_time_seconds: None,
+            mem_requested_bytes: tres
+                .get("mem")
+                .and_then(|mem| parse_memory(mem))
+                .map(|(bytes, _)| bytes),
+            max_rss_bytes: None,
+        })
+    }
+}
+
+/// The plugins post one document per request; batches may be a JSON array or NDJSON.
+fn parse_documents(body: &[u8], ndjson: bool) -> Result<Vec<Result<JobRecord, String>>, String> {
+    let is_object = body
+        .iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .is_some_and(|byte| *byte == b'{');
+
+    let documents: Vec<Result<JobCompDocument, String>> = if is_object && !ndjson {
+        vec![serde_json::from_slice(body).map_err(|e| e.to_string())]
+    } else {
+        parse_batch(body, ndjson)?
+    };
+
+    Ok(documents
+        .into_iter()
+        .map(|document| document.and_then(JobCompDocument::into_record))
+        .collect())
+}
+
+pub async fn post_jobcomp(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(cluster): Query<ClusterQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let rows = parse_documents(&body, is_ndjson(&headers))
+        .map_err(|reason| (StatusCode::BAD_REQUEST, reason))?;
+
+    let (jobs, report) = prepare_jobs(rows);
+
+    upsert_jobs(&cluster.pool, &cluster.schema, &jobs)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to insert job completion records: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"{
+        "jobid": 1001,
+        "username": "alice",
+        "user_id": 1000,
+        "groupname": "ccv",
+        "account": "ccv",
+        "partition": "batch",
+        "@submit": "2024-03-26T23:50:00",
+        "@start": "2024-03-27T00:00:00",
+        "@end": "2024-03-27T02:00:00",
+        "elapsed": 7200,
+        "total_cpus": 4,
+        "total_nodes": 1,
+        "state": "CANCELLED by 1000",
+        "cpu_hours": 8.0,
+        "tres_alloc": "cpu=4,mem=16G,node=1,billing=4,gres/gpu=1",
+        "cluster": "oscar"
+    }"#;
+
+    #[test]
+    fn test_document_into_record() {
+        let rows = parse_documents(DOCUMENT.as_bytes(), false).unwrap();
+        let job = rows.into_iter().next().unwrap().unwrap();
+
+        assert_eq!(job.job_id, "1001");
+        assert_eq!(job.user_name, "alice");
+        assert_eq!(job.cpus, Some(4));
+        assert_eq!(job.gpus, Some(1));
+        assert_eq!(job.state.as_deref(), Some("CANCELLED"));
+        assert_eq!(job.elapsed_seconds, Some(7200));
+        assert_eq!(job.mem_requested_bytes, Some(16 << 30));
+        assert_eq!(job.end_time, Some("2024-03-27T02:00:00".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_array_task_ids() {
+        let document: JobCompDocument = serde_json::from_str(
+            r#"{"jobid": 2001, "array_job_id": 2000, "array_task_id": 1,
+                "account": "mri", "username": "carol"}"#,
+        )
+        .unwrap();
+        assert_eq!(document.into_record().unwrap().job_id, "2000_1");
+
+        let document: JobCompDocument = serde_json::from_str(
+            r#"{"jobid": 2000, "array_job_id": 2000, "array_task_id": 4294967294,
+                "account": "mri", "username": "carol"}"#,
+        )
+        .unwrap();
+        assert_eq!(document.into_record().unwrap().job_id, "2000");
+    }
+
+    #[test]
+    fn test_ndjson_batch_deduplicates_job_ids() {
+        let body = br#"{"jobid": 1, "account": "ccv", "username": "alice", "state": "RUNNING"}
+{"jobid": 2, "account": "ccv", "username": "alice", "@end": "yesterday"}
+{"jobid": 1, "account": "ccv", "username": "alice", "state": "COMPLETED"}
+"#;
+
+        let (jobs, report) = prepare_jobs(parse_documents(body, true).unwrap());
+
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].state.as_deref(), Some("COMPLETED"));
+        assert_eq!(report.rejected, 2);
+        assert_eq!(report.errors[0].index, 0);
+        assert_eq!(report.errors[1].reason, "invalid time `yesterday`");
+    }
+
+    #[test]
+    fn test_parse_time_with_offset() {
+        assert!(parse_time(Some("2024-03-27T02:00:00-04:00"))
+            .unwrap()
+            .is_some());
+        assert!(parse_time(Some("2024-03-27T02:00:00+0000"))
+            .unwrap()
+            .is_some());
+        assert_eq!(parse_time(Some("")).unwrap(), None);
+    }
+}
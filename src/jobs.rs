@@ -0,0 +1,256 @@
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::ingest::IngestReport;
+
+/// A completed (or at least started) Slurm job as stored in a cluster's `jobs` table.
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub job_id: String,
+    pub account: String,
+    #[serde(alias = "user")]
+    pub user_name: String,
+    pub partition: Option<String>,
+    pub submit_time: Option<NaiveDateTime>,
+    pub start_time: Option<NaiveDateTime>,
+    pub end_time: Option<NaiveDateTime>,
+    /// `None` when the source doesn't report it, in which case any stored value is kept.
+    #[serde(default)]
+    pub cpus: Option<i32>,
+    #[serde(default)]
+    pub gpus: Option<i32>,
+    pub state: Option<String>,
+    pub elapsed_seconds: Option<i64>,
+    pub cpu_time_seconds: Option<i64>,
+    pub mem_requested_bytes: Option<i64>,
+    pub max_rss_bytes: Option<i64>,
+}
+
+impl JobRecord {
+    /// Checks the invariants the accounting queries rely on.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.job_id.trim().is_empty() {
+            return Err("job_id is empty".to_string());
+        }
+        if self.account.trim().is_empty() {
+            return Err("account is empty".to_string());
+        }
+        if self.user_name.trim().is_empty() {
+            return Err("user_name is empty".to_string());
+        }
+        if self.cpus.is_some_and(|cpus| cpus < 0) || self.gpus.is_some_and(|gpus| gpus < 0) {
+            return Err("cpus and gpus must not be negative".to_string());
+        }
+        if let (Some(start), Some(end)) = (self.start_time, self.end_time) {
+            if end < start {
+                return Err("end_time is before start_time".to_string());
+            }
+        }
+        let counters = [
+            self.elapsed_seconds,
+            self.cpu_time_seconds,
+            self.mem_requested_bytes,
+            self.max_rss_bytes,
+        ];
+        if counters.iter().flatten().any(|value| *value < 0) {
+            return Err("elapsed, cpu time and memory must not be negative".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Each row binds 14 parameters and Postgres allows at most 65535 per statement.
+const UPSERT_CHUNK_SIZE: usize = 1000;
+
+/// Inserts or updates job records in a single transaction, keyed on `job_id`, so
+/// re-sending the same job (e.g. from an overlapping import) is harmless. Optional
+/// fields missing from the new record keep their stored value, so a source that
+/// doesn't report e.g. `max_rss_bytes` or `gpus` doesn't erase what `sacct` found.
+pub async fn upsert_jobs(
+    pool: &PgPool,
+    schema: &str,
+    jobs: &[JobRecord],
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut affected = 0;
+
+    for chunk in jobs.chunks(UPSERT_CHUNK_SIZE) {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "INSERT INTO {schema}.jobs (job_id, account, user_name, partition, submit_time, \
+             start_time, end_time, cpus, gpus, state, elapsed_seconds, cpu_time_seconds, \
+             mem_requested_bytes, max_rss_bytes) "
+        ));
+
+        query.push_values(chunk, |mut row, job| {
+            row.push_bind(&job.job_id)
+                .push_bind(&job.account)
+                .push_bind(&job.user_name)
+                .push_bind(&job.partition)
+                .push_bind(job.submit_time)
+                .push_bind(job.start_time)
+                .push_bind(job.end_time)
+                .push_bind(job.cpus)
+                .push_bind(job.gpus)
+                .push_bind(&job.state)
+                .push_bind(job.elapsed_seconds)
+                .push_bind(job.cpu_time_seconds)
+                .push_bind(job.mem_requested_bytes)
+                .push_bind(job.max_rss_bytes);
+        });
+
+        query.push(
+            " ON CONFLICT (job_id) DO UPDATE SET \
+             account = EXCLUDED.account, \
+             user_name = EXCLUDED.user_name, \
+             partition = COALESCE(EXCLUDED.partition, jobs.partition), \
+             submit_time = COALESCE(EXCLUDED.submit_time, jobs.submit_time), \
+             start_time = COALESCE(EXCLUDED.start_time, jobs.start_time), \
+             end_time = COALESCE(EXCLUDED.end_time, jobs.end_time), \
+             cpus = COALESCE(EXCLUDED.cpus, jobs.cpus), \
+             gpus = COALESCE(EXCLUDED.gpus, jobs.gpus), \
+             state = COALESCE(EXCLUDED.state, jobs.state), \
+             elapsed_seconds = COALESCE(EXCLUDED.elapsed_seconds, jobs.elapsed_seconds), \
+             cpu_time_seconds = COALESCE(EXCLUDED.cpu_time_seconds, jobs.cpu_time_seconds), \
+             mem_requested_bytes = COALESCE(EXCLUDED.mem_requested_bytes, jobs.mem_requested_bytes), \
+             max_rss_bytes = COALESCE(EXCLUDED.max_rss_bytes, jobs.max_rss_bytes)",
+        );
+
+        affected += query.build().execute(&mut *tx).await?.rows_affected();
+    }
+
+    tx.commit().await?;
+
+    Ok(affected)
+}
+
+/// Splits a batch into valid records and a report listing the rejected ones. When a
+/// job appears more than once the last record wins, since a single
+/// INSERT .. ON CONFLICT can't update the same row twice.
+pub fn validate_jobs(jobs: Vec<JobRecord>) -> (Vec<JobRecord>, IngestReport) {
+    prepare_jobs(jobs.into_iter().map(Ok).collect())
+}
+
+/// Like [`validate_jobs`], for batches where some rows already failed to parse.
+pub fn prepare_jobs(rows: Vec<Result<JobRecord, String>>) -> (Vec<JobRecord>, IngestReport) {
+    let mut report = IngestReport::default();
+    let mut latest: HashMap<String, usize> = HashMap::new();
+    let mut valid = Vec::with_capacity(rows.len());
+
+    for (index, row) in rows.into_iter().enumerate() {
+        match row.and_then(|job| job.validate().map(|()| job)) {
+            Ok(job) => {
+                latest.insert(job.job_id.clone(), index);
+                valid.push((index, job));
+            }
+            Err(reason) => report.reject(index, reason),
+        }
+    }
+
+    let mut jobs = Vec::with_capacity(latest.len());
+    for (index, job) in valid {
+        let winner = latest[&job.job_id];
+        if winner == index {
+            jobs.push(job);
+        } else {
+            report.reject(index, format!("duplicate job, superseded by row {winner}"));
+        }
+    }
+
+    report.errors.sort_by_key(|error| error.index);
+    report.accepted = jobs.len();
+
+    (jobs, report)
+}
+
+pub async fn post_jobs(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(cluster): Query<ClusterQuery>,
+    Json(jobs): Json<Vec<JobRecord>>,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let (jobs, report) = validate_jobs(jobs);
+
+    upsert_jobs(&cluster.pool, &cluster.schema, &jobs)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to insert job records: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(job_id: &str) -> JobRecord {
+        JobRecord {
+            job_id: job_id.to_string(),
+            account: "ccv".to_string(),
+            user_name: "alice".to_string(),
+            partition: Some("batch".to_string()),
+            submit_time: None,
+            start_time: Some("2024-03-27T00:00:00".parse().unwrap()),
+            end_time: Some("2024-03-27T02:00:00".parse().unwrap()),
+            cpus: Some(4),
+            gpus: Some(0),
+            state: Some("COMPLETED".to_string()),
+            elapsed_seconds: Some(7200),
+            cpu_time_seconds: Some(14400),
+            mem_requested_bytes: Some(16 << 30),
+            max_rss_bytes: Some(4 << 30),
+        }
+    }
+
+    #[test]
+    fn test_job_record_accepts_user_alias() {
+        let job: JobRecord =
+            serde_json::from_str(r#"{"job_id": "1", "account": "ccv", "user": "alice"}"#).unwrap();
+
+        assert_eq!(job.user_name, "alice");
+        assert_eq!(job.cpus, None);
+        assert!(job.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_jobs_reports_rejected_rows() {
+        let mut backwards = job("2");
+        backwards.end_time = Some("2024-03-26T00:00:00".parse().unwrap());
+        let mut anonymous = job("3");
+        anonymous.user_name = String::new();
+
+        let (valid, report) = validate_jobs(vec![job("1"), backwards, anonymous]);
+
+        assert_eq!(valid.len(), 1);
+        assert_eq!(report.accepted, 1);
+        assert_eq!(report.rejected, 2);
+        assert_eq!(report.errors[0].index, 1);
+        assert_eq!(report.errors[1].index, 2);
+        assert_eq!(report.errors[1].reason, "user_name is empty");
+    }
+
+    #[test]
+    fn test_validate_jobs_keeps_last_duplicate() {
+        let mut updated = job("1");
+        updated.state = Some("FAILED".to_string());
+
+        let (valid, report) = validate_jobs(vec![job("1"), job("2"), updated]);
+
+        assert_eq!(valid.len(), 2);
+        assert_eq!(valid[1].state.as_deref(), Some("FAILED"));
+        assert_eq!(report.rejected, 1);
+        assert_eq!(report.errors[0].index, 0);
+    }
+}
@@ -1,43 +1,202 @@
+pub mod auth;
+pub mod clusters;
+pub mod collector;
+pub mod config;
+pub mod database;
+pub mod efficiency;
+pub mod fairshare;
+pub mod ingest;
+pub mod jobcomp;
+pub mod jobs;
+pub mod line_protocol;
+pub mod metrics;
+pub mod migrations;
+pub mod partitions;
+pub mod quality;
+pub mod remote_write;
+pub mod replicas;
+pub mod retention;
+pub mod rollup;
 pub mod routes;
+pub mod shutdown;
+pub mod storage;
+pub mod timeouts;
+pub mod usage;
 
 pub use routes::{TimeRange, Utilization};
 
 use anyhow::Result;
-use axum::http::HeaderValue;
+use axum::extract::FromRef;
 use dotenvy::dotenv;
-use sqlx::postgres::{PgConnectOptions, PgPool, PgSslMode};
-use std::env;
-use tower_http::cors::{Any, CorsLayer};
+use sqlx::postgres::PgPool;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tower_http::trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer};
 use tracing::Level;
 
+/// Builds the main database pool from the environment (and `.env`), without a config
+/// file or command-line overrides. The pool connects lazily, so this only fails on bad
+/// configuration; use `database::wait_for_db` to wait for the database itself.
 pub async fn get_db_connection() -> Result<PgPool> {
     dotenv().ok();
 
-    let opts = PgConnectOptions::new()
-        .host(&env::var("DB_HOST").unwrap())
-        .port(5432)
-        .database(&env::var("DB_NAME").unwrap())
-        .username(&env::var("DB_USER").unwrap())
-        .password(&env::var("DB_PASSWORD").unwrap())
-        .ssl_mode(PgSslMode::Allow);
+    let config = Config::load(None, &ConfigArgs::default())?;
+    Ok(config.database.resolve()?.lazy_pool())
+}
+
+use auth::{AdminTokens, IngestTokens};
+use clusters::ClusterRegistry;
+use config::{Config, ConfigArgs, ServerConfig};
+use database::DbStatus;
+use line_protocol::LineProtocolConfig;
+use remote_write::RemoteWriteConfig;
+use replicas::Replicas;
+use timeouts::QueryTimeouts;
+
+/// Shared state for all handlers. Handlers extract the part they need, e.g.
+/// `State<PgPool>` for the main database or `State<Arc<ClusterRegistry>>` for a
+/// cluster's tables and its read pool.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: PgPool,
+    pub clusters: Arc<ClusterRegistry>,
+    pub ingest_tokens: IngestTokens,
+    pub admin_tokens: AdminTokens,
+    pub remote_write: Arc<RemoteWriteConfig>,
+    pub line_protocol: Arc<LineProtocolConfig>,
+    pub db_status: DbStatus,
+    pub replicas: Replicas,
+    pub query_timeouts: Arc<QueryTimeouts>,
+    pub server: Arc<ServerConfig>,
+    /// Cancelled once the server starts shutting down.
+    pub shutdown: CancellationToken,
+}
+
+impl AppState {
+    /// State with no ingest or admin tokens, i.e. with every write and admin endpoint
+    /// disabled, and the database taken to be up.
+    pub fn new(pool: PgPool, clusters: ClusterRegistry) -> Self {
+        AppState {
+            pool,
+            clusters: Arc::new(clusters),
+            ingest_tokens: IngestTokens::default(),
+            admin_tokens: AdminTokens::default(),
+            remote_write: Arc::new(RemoteWriteConfig::default()),
+            line_protocol: Arc::new(LineProtocolConfig::default()),
+            db_status: DbStatus::new(true),
+            replicas: Replicas::default(),
+            query_timeouts: Arc::new(QueryTimeouts::default()),
+            server: Arc::new(ServerConfig::default()),
+            shutdown: CancellationToken::new(),
+        }
+    }
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ClusterRegistry> {
+    fn from_ref(state: &AppState) -> Self {
+        state.clusters.clone()
+    }
+}
+
+impl FromRef<AppState> for IngestTokens {
+    fn from_ref(state: &AppState) -> Self {
+        state.ingest_tokens.clone()
+    }
+}
+
+impl FromRef<AppState> for AdminTokens {
+    fn from_ref(state: &AppState) -> Self {
+        state.admin_tokens.clone()
+    }
+}
 
-    let pool = PgPool::connect_with(opts)
-        .await
-        .expect("Failed to connect to database");
+impl FromRef<AppState> for Arc<RemoteWriteConfig> {
+    fn from_ref(state: &AppState) -> Self {
+        state.remote_write.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<LineProtocolConfig> {
+    fn from_ref(state: &AppState) -> Self {
+        state.line_protocol.clone()
+    }
+}
+
+impl FromRef<AppState> for DbStatus {
+    fn from_ref(state: &AppState) -> Self {
+        state.db_status.clone()
+    }
+}
+
+impl FromRef<AppState> for Replicas {
+    fn from_ref(state: &AppState) -> Self {
+        state.replicas.clone()
+    }
+}
 
-    Ok(pool)
+impl FromRef<AppState> for Arc<QueryTimeouts> {
+    fn from_ref(state: &AppState) -> Self {
+        state.query_timeouts.clone()
+    }
 }
 
-pub async fn create_app(pool: PgPool) -> axum::Router {
-    use axum::routing::get;
+impl FromRef<AppState> for Arc<ServerConfig> {
+    fn from_ref(state: &AppState) -> Self {
+        state.server.clone()
+    }
+}
+
+impl FromRef<AppState> for CancellationToken {
+    fn from_ref(state: &AppState) -> Self {
+        state.shutdown.clone()
+    }
+}
+
+/// Ingestion batches can be much larger than axum's default 2 MB body limit.
+const MAX_INGEST_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+pub async fn create_app(state: AppState) -> axum::Router {
+    use axum::extract::DefaultBodyLimit;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::{delete, get, post};
+    use clusters::{get_bucketed_cluster_utilization, get_cluster_utilization, get_clusters};
+    use database::{get_health, get_ready};
+    use efficiency::get_efficiency;
+    use fairshare::{get_fairshare, post_fairshare_samples};
+    use ingest::post_samples;
+    use jobcomp::post_jobcomp;
+    use jobs::post_jobs;
+    use line_protocol::post_write;
+    use partitions::get_partitions;
+    use quality::{delete_quarantined, get_quarantine, release_quarantined};
+    use remote_write::post_remote_write;
+    use replicas::get_metrics;
     use routes::{
-        get_cpu_utilization, get_daily_cpu_utilization, get_daily_gpu_utilization,
+        get_cpu_utilization, get_daily_cpu_utilization, get_daily_gpu_utilization, get_gpu_types,
         get_gpu_utilization, get_hourly_cpu_utilization, get_hourly_gpu_utilization, root,
     };
+    use storage::{
+        get_daily_storage_utilization, get_fullest_quotas, get_hourly_storage_utilization,
+        get_quota_usage, get_storage_utilization, post_quota_samples, post_storage_samples,
+    };
+    use usage::{get_account_usage, get_usage_leaderboard, get_user_usage};
 
+    let origins = &state.server.cors_origins;
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        // Config::validate has checked that each origin is a valid header value.
+        AllowOrigin::list(origins.iter().filter_map(|origin| origin.parse().ok()))
+    };
     let cors = CorsLayer::new()
-        .allow_origin("*".parse::<HeaderValue>().unwrap())
+        .allow_origin(allow_origin)
         .allow_methods(Any)
         .allow_headers(Any)
         .allow_credentials(false);
@@ -55,15 +214,243 @@ pub async fn create_app(pool: PgPool) -> axum::Router {
         .on_request(DefaultOnRequest::new().level(Level::INFO))
         .on_response(DefaultOnResponse::new().level(Level::INFO));
 
+    // Every write endpoint goes through the ingest token check.
+    let writes = axum::Router::new()
+        .route("/{resource}/samples", post(post_samples))
+        .route("/storage/samples", post(post_storage_samples))
+        .route("/storage/quotas/samples", post(post_quota_samples))
+        .route("/fairshare/samples", post(post_fairshare_samples))
+        .route("/jobs", post(post_jobs))
+        // Slurm's jobcomp plugins append their own index path to the configured URL.
+        .route("/jobcomp", post(post_jobcomp))
+        .route("/jobcomp/{*path}", post(post_jobcomp))
+        .route("/api/v1/write", post(post_remote_write))
+        .route("/write", post(post_write))
+        .route_layer(from_fn_with_state(
+            state.clone(),
+            auth::require_ingest_token,
+        ))
+        .layer(DefaultBodyLimit::max(MAX_INGEST_BODY_BYTES));
+
+    let admin = axum::Router::new()
+        .route("/admin/quarantine", get(get_quarantine))
+        .route("/admin/quarantine/{id}/release", post(release_quarantined))
+        .route("/admin/quarantine/{id}", delete(delete_quarantined))
+        .route("/admin/partitions", get(get_partitions))
+        .route_layer(from_fn_with_state(state.clone(), timeouts::query_timeout))
+        .route_layer(from_fn_with_state(state.clone(), auth::require_admin_token));
+
     axum::Router::new()
-        .route("/", get(root))
         .route("/cpu", get(get_cpu_utilization))
         .route("/gpu", get(get_gpu_utilization))
         .route("/cpu/hourly", get(get_hourly_cpu_utilization))
         .route("/gpu/hourly", get(get_hourly_gpu_utilization))
         .route("/cpu/daily", get(get_daily_cpu_utilization))
         .route("/gpu/daily", get(get_daily_gpu_utilization))
+        .route("/gpu/types", get(get_gpu_types))
+        .route("/clusters", get(get_clusters))
+        .route(
+            "/clusters/{cluster}/{resource}",
+            get(get_cluster_utilization),
+        )
+        .route(
+            "/clusters/{cluster}/{resource}/{bucket}",
+            get(get_bucketed_cluster_utilization),
+        )
+        .route("/storage", get(get_storage_utilization))
+        .route("/storage/hourly", get(get_hourly_storage_utilization))
+        .route("/storage/daily", get(get_daily_storage_utilization))
+        .route("/storage/quotas", get(get_quota_usage))
+        .route("/storage/quotas/fullest", get(get_fullest_quotas))
+        .route("/fairshare", get(get_fairshare))
+        .route("/efficiency", get(get_efficiency))
+        .route("/accounts/{account}/usage", get(get_account_usage))
+        .route("/users/{user}/usage", get(get_user_usage))
+        .route("/usage/top", get(get_usage_leaderboard))
+        // Reads are cut off after their query timeout; writes run to completion.
+        .route_layer(from_fn_with_state(state.clone(), timeouts::query_timeout))
+        .route_layer(from_fn_with_state(state.clone(), routes::cache_control))
+        .merge(writes)
+        .merge(admin)
+        // While the database is down everything above answers 503 straight away.
+        .route_layer(from_fn_with_state(state.clone(), database::require_db))
+        .route("/", get(root))
+        .route("/health", get(get_health))
+        .route("/ready", get(get_ready))
+        .route("/metrics", get(get_metrics))
         .layer(cors) // Add CORS layer before trace layer
         .layer(trace_layer)
-        .with_state(pool)
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    // axum panics on conflicting routes when the router is built, so building the app
+    // against a lazy pool catches route table mistakes without needing a database.
+    #[tokio::test]
+    async fn test_create_app_builds_router() {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let clusters = ClusterRegistry::single(pool.clone());
+
+        let _app = create_app(AppState::new(pool, clusters)).await;
+    }
+
+    async fn post_samples_status(token: Option<&str>, path: &str) -> StatusCode {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let clusters = ClusterRegistry::single(pool.clone());
+        let mut state = AppState::new(pool, clusters);
+        state.ingest_tokens = IngestTokens::new(vec!["secret".to_string()]);
+        let app = create_app(state).await;
+
+        let mut request = Request::builder().method("POST").uri(path);
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {token}"));
+        }
+        // A body that is not a JSON array is rejected before the database is used.
+        let request = request.body(Body::from("{}")).unwrap();
+
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_ingestion_requires_token() {
+        assert_eq!(
+            post_samples_status(None, "/cpu/samples").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_samples_status(Some("wrong"), "/cpu/samples").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_samples_status(Some("secret"), "/cpu/samples").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_admin_token() {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let clusters = ClusterRegistry::single(pool.clone());
+        let mut state = AppState::new(pool, clusters);
+        state.ingest_tokens = IngestTokens::new(vec!["secret".to_string()]);
+        let app = create_app(state).await;
+
+        let request = Request::builder()
+            .uri("/admin/quarantine")
+            .header("authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(
+            app.oneshot(request).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ingestion_rejects_unknown_resource() {
+        assert_eq!(
+            post_samples_status(Some("secret"), "/disk/samples").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_degraded_until_database_is_up() {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let clusters = ClusterRegistry::single(pool.clone());
+        let mut state = AppState::new(pool, clusters);
+        state.db_status = DbStatus::default();
+        let app = create_app(state).await;
+
+        let status = |uri: &str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(status("/health").await, StatusCode::OK);
+        assert_eq!(status("/ready").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("/cpu/hourly").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            status("/admin/quarantine").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_once_shutting_down() {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let clusters = ClusterRegistry::single(pool.clone());
+        let state = AppState::new(pool, clusters);
+        let shutdown = state.shutdown.clone();
+        let app = create_app(state).await;
+
+        let ready = || {
+            let request = Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(ready().await, StatusCode::OK);
+        shutdown.cancel();
+        assert_eq!(ready().await, StatusCode::SERVICE_UNAVAILABLE);
+        // Liveness is unaffected, so the instance isn't restarted while it drains.
+        assert_eq!(
+            app.clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/health")
+                        .body(Body::empty())
+                        .unwrap()
+                )
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_served_during_the_shutdown_delay() {
+        let pool = PgPool::connect_lazy("postgres://localhost/elmo").unwrap();
+        let clusters = ClusterRegistry::single(pool.clone());
+        let state = AppState::new(pool, clusters);
+        let token = state.shutdown.clone();
+        let app = create_app(state).await;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let delay = std::time::Duration::from_millis(500);
+        let server = tokio::spawn(shutdown::serve(listener, app, token.clone(), delay));
+
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("http://{address}{path}")).send();
+
+        token.cancel();
+        // Load balancers see /ready fail, while requests still get answers.
+        let ready = get("/ready").await.unwrap();
+        assert_eq!(ready.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        let health = get("/health").await.unwrap();
+        assert_eq!(health.status(), reqwest::StatusCode::OK);
+
+        drop(client);
+        tokio::time::timeout(delay * 4, server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(reqwest::get(format!("http://{address}/health"))
+            .await
+            .is_err());
+    }
 }
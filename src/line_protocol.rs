@@ -0,0 +1,553 @@
+use chrono::{DateTime, NaiveDateTime};
+use std::env;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+
+use crate::clusters::{find_cluster, ClusterRegistry};
+use crate::ingest::IngestReport;
+use crate::metrics::{
+    assemble, parse_rules, write_samples, MetricPoint, MetricRule, GPU_TYPE_LABEL,
+};
+use crate::quality;
+
+/// Mapping used when `INFLUX_METRICS` is not set. Rules name metrics as
+/// `measurement.field`.
+pub const DEFAULT_RULES: &str = "slurm.cpus_alloc=cpu.allocated; slurm.cpus_total=cpu.total; \
+                                 slurm.gpus_alloc=gpu.allocated; slurm.gpus_total=gpu.total";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    String(String),
+    Boolean(bool),
+}
+
+impl FieldValue {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Float(value) => Some(*value),
+            FieldValue::Integer(value) => Some(*value as f64),
+            FieldValue::UInteger(value) => Some(*value as f64),
+            FieldValue::String(_) | FieldValue::Boolean(_) => None,
+        }
+    }
+}
+
+/// One parsed line of line protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: Option<i64>,
+}
+
+impl Line {
+    fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A syntax error, with the 1-based column it was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+struct Scanner<'a> {
+    line: &'a str,
+    position: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn peek(&self) -> Option<char> {
+        self.line[self.position..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            column: self.position + 1,
+            message: message.into(),
+        })
+    }
+
+    /// Reads an identifier (measurement, tag or field key, or tag value) up to one of
+    /// `stops`, unescaping `\,`, `\=`, `\ ` and `\\`.
+    fn identifier(&mut self, what: &str, stops: &[char]) -> Result<String, ParseError> {
+        let mut value = String::new();
+
+        while let Some(c) = self.peek() {
+            if stops.contains(&c) {
+                break;
+            }
+            self.bump();
+            if c == '\\' {
+                match self.peek() {
+                    Some(escaped @ (',' | '=' | ' ' | '\\')) => {
+                        self.bump();
+                        value.push(escaped);
+                    }
+                    _ => value.push(c),
+                }
+            } else {
+                value.push(c);
+            }
+        }
+
+        if value.is_empty() {
+            return self.error(format!("missing {what}"));
+        }
+        Ok(value)
+    }
+
+    fn expect(&mut self, expected: char, context: &str) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => self.error(format!("expected '{expected}' {context}, found '{c}'")),
+            None => self.error(format!(
+                "expected '{expected}' {context}, found end of line"
+            )),
+        }
+    }
+
+    fn skip_spaces(&mut self) -> usize {
+        let start = self.position;
+        while self.peek() == Some(' ') {
+            self.bump();
+        }
+        self.position - start
+    }
+
+    fn string_value(&mut self) -> Result<FieldValue, ParseError> {
+        let start = self.position;
+        self.bump();
+        let mut value = String::new();
+
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(FieldValue::String(value)),
+                Some('\\') => match self.peek() {
+                    Some(escaped @ ('"' | '\\')) => {
+                        self.bump();
+                        value.push(escaped);
+                    }
+                    _ => value.push('\\'),
+                },
+                Some(c) => value.push(c),
+                None => {
+                    self.position = start;
+                    return self.error("unterminated string field value");
+                }
+            }
+        }
+    }
+
+    fn field_value(&mut self) -> Result<FieldValue, ParseError> {
+        if self.peek() == Some('"') {
+            return self.string_value();
+        }
+
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if c == ',' || c == ' ' {
+                break;
+            }
+            self.bump();
+        }
+        let raw = &self.line[start..self.position];
+
+        let value = match raw {
+            "" => None,
+            "t" | "T" | "true" | "True" | "TRUE" => Some(FieldValue::Boolean(true)),
+            "f" | "F" | "false" | "False" | "FALSE" => Some(FieldValue::Boolean(false)),
+            _ if raw.ends_with('i') => raw[..raw.len() - 1].parse().ok().map(FieldValue::Integer),
+            _ if raw.ends_with('u') => raw[..raw.len() - 1].parse().ok().map(FieldValue::UInteger),
+            _ => raw
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .map(FieldValue::Float),
+        };
+
+        match value {
+            Some(value) => Ok(value),
+            None => {
+                self.position = start;
+                self.error(format!("invalid field value `{raw}`"))
+            }
+        }
+    }
+}
+
+/// Parses a single line of InfluxDB line protocol:
+/// `measurement[,tag=value...] field=value[,field=value...] [timestamp]`.
+pub fn parse_line(line: &str) -> Result<Line, ParseError> {
+    let mut scanner = Scanner { line, position: 0 };
+
+    let measurement = scanner.identifier("measurement", &[',', ' '])?;
+
+    let mut tags = Vec::new();
+    while scanner.peek() == Some(',') {
+        scanner.bump();
+        let key = scanner.identifier("tag key", &['=', ',', ' '])?;
+        scanner.expect('=', "after tag key")?;
+        let value = scanner.identifier("tag value", &[',', ' '])?;
+        tags.push((key, value));
+    }
+
+    if scanner.skip_spaces() == 0 {
+        return scanner.error("expected a space before the fields");
+    }
+
+    let mut fields = Vec::new();
+    loop {
+        let key = scanner.identifier("field key", &['=', ',', ' '])?;
+        scanner.expect('=', "after field key")?;
+        let value = scanner.field_value()?;
+        fields.push((key, value));
+
+        if scanner.peek() != Some(',') {
+            break;
+        }
+        scanner.bump();
+    }
+
+    scanner.skip_spaces();
+    let timestamp = if scanner.peek().is_some() {
+        let start = scanner.position;
+        while scanner.peek().is_some_and(|c| c != ' ') {
+            scanner.bump();
+        }
+        let raw = &line[start..scanner.position];
+        match raw.parse::<i64>() {
+            Ok(timestamp) => Some(timestamp),
+            Err(_) => {
+                scanner.position = start;
+                return scanner.error(format!("invalid timestamp `{raw}`"));
+            }
+        }
+    } else {
+        None
+    };
+
+    scanner.skip_spaces();
+    if let Some(c) = scanner.peek() {
+        return scanner.error(format!("unexpected '{c}' after the timestamp"));
+    }
+
+    Ok(Line {
+        measurement,
+        tags,
+        fields,
+        timestamp,
+    })
+}
+
+/// Timestamp precision, as in InfluxDB's `precision` query parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Precision {
+    #[default]
+    #[serde(rename = "ns", alias = "n")]
+    Nanoseconds,
+    #[serde(rename = "us", alias = "u")]
+    Microseconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "s")]
+    Seconds,
+}
+
+impl Precision {
+    fn to_time(self, timestamp: i64) -> Option<NaiveDateTime> {
+        let time = match self {
+            Precision::Nanoseconds => Some(DateTime::from_timestamp_nanos(timestamp)),
+            Precision::Microseconds => DateTime::from_timestamp_micros(timestamp),
+            Precision::Milliseconds => DateTime::from_timestamp_millis(timestamp),
+            Precision::Seconds => DateTime::from_timestamp(timestamp, 0),
+        }?;
+        Some(quality::stored_time(time))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WriteQuery {
+    #[serde(default)]
+    pub precision: Precision,
+    /// Cluster to write to. Defaults to the first configured cluster.
+    pub cluster: Option<String>,
+}
+
+/// Which measurements and fields are stored, and as what.
+#[derive(Debug, Clone)]
+pub struct LineProtocolConfig {
+    pub rules: Vec<MetricRule>,
+}
+
+impl Default for LineProtocolConfig {
+    fn default() -> Self {
+        LineProtocolConfig {
+            rules: parse_rules(DEFAULT_RULES).expect("default rules are valid"),
+        }
+    }
+}
+
+impl LineProtocolConfig {
+    /// Reads the `;`-separated `INFLUX_METRICS` rules, e.g.
+    /// `nodes.alloc{cluster="oscar"}=cpu.allocated; nodes.total{cluster="oscar"}=cpu.total`.
+    pub fn from_env() -> Result<Self> {
+        match env::var("INFLUX_METRICS") {
+            Ok(value) => Ok(LineProtocolConfig {
+                rules: parse_rules(&value)?,
+            }),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Parses a request body into metric points. Lines and fields no rule matches are
+/// ignored; malformed lines, and matched fields that aren't numbers, are reported with
+/// their 0-based line index.
+pub fn parse_body(
+    config: &LineProtocolConfig,
+    body: &str,
+    precision: Precision,
+    now: NaiveDateTime,
+) -> (Vec<MetricPoint>, IngestReport) {
+    let mut report = IngestReport::default();
+    let mut points = Vec::new();
+
+    for (index, line) in body.lines().enumerate() {
+        let trimmed = line.trim_end();
+        if trimmed.trim_start().is_empty() || trimmed.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let line = match parse_line(trimmed) {
+            Ok(line) => line,
+            Err(e) => {
+                report.reject(
+                    index,
+                    format!("line {}, column {}: {}", index + 1, e.column, e.message),
+                );
+                continue;
+            }
+        };
+
+        let time = match line.timestamp {
+            Some(timestamp) => match precision.to_time(timestamp) {
+                Some(time) => time,
+                None => {
+                    report.reject(
+                        index,
+                        format!("line {}: timestamp {timestamp} is out of range", index + 1),
+                    );
+                    continue;
+                }
+            },
+            None => now,
+        };
+        let gpu_type = line.tag(GPU_TYPE_LABEL).map(String::from);
+
+        for (field, value) in &line.fields {
+            let name = format!("{}.{}", line.measurement, field);
+            let Some(rule) = config
+                .rules
+                .iter()
+                .find(|rule| rule.matches(&name, |key| line.tag(key)))
+            else {
+                continue;
+            };
+            let Some(value) = value.as_f64() else {
+                report.reject(
+                    index,
+                    format!("line {}: field {field} is not numeric", index + 1),
+                );
+                continue;
+            };
+
+            points.push(MetricPoint {
+                index,
+                target: rule.target,
+                time,
+                gpu_type: gpu_type.clone(),
+                value,
+            });
+        }
+    }
+
+    (points, report)
+}
+
+/// InfluxDB 1.x-style write endpoint. Every line is reported on: accepted lines become
+/// samples, rejected ones are listed with their line number and reason.
+pub async fn post_write(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    State(config): State<Arc<LineProtocolConfig>>,
+    Query(query): Query<WriteQuery>,
+    body: Bytes,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, query.cluster.as_deref())?;
+    let body = std::str::from_utf8(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("body is not UTF-8: {e}")))?;
+
+    let now = quality::now();
+    let (points, parse_report) = parse_body(&config, body, query.precision, now);
+    let (samples, quarantined, mut report) = assemble(points);
+
+    report.rejected += parse_report.rejected;
+    report.errors.extend(parse_report.errors);
+    report.errors.sort_by_key(|error| error.index);
+
+    write_samples(cluster, samples, &quarantined, &mut report)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to insert line protocol samples: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Uri;
+
+    fn now() -> NaiveDateTime {
+        "2024-03-27T12:00:00".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let line = parse_line(
+            r#"slurm\ stats,cluster=oscar,gpu_type=a100 gpus_alloc=2i,ratio=0.5,up=t,note="a \"b\"" 1711497600000000000"#,
+        )
+        .unwrap();
+
+        assert_eq!(line.measurement, "slurm stats");
+        assert_eq!(line.tag("cluster"), Some("oscar"));
+        assert_eq!(line.tag("gpu_type"), Some("a100"));
+        assert_eq!(
+            line.fields,
+            vec![
+                ("gpus_alloc".to_string(), FieldValue::Integer(2)),
+                ("ratio".to_string(), FieldValue::Float(0.5)),
+                ("up".to_string(), FieldValue::Boolean(true)),
+                (
+                    "note".to_string(),
+                    FieldValue::String("a \"b\"".to_string())
+                ),
+            ]
+        );
+        assert_eq!(line.timestamp, Some(1_711_497_600_000_000_000));
+    }
+
+    #[test]
+    fn test_parse_line_without_timestamp() {
+        let line = parse_line("slurm cpus_alloc=75u").unwrap();
+
+        assert_eq!(line.fields[0].1, FieldValue::UInteger(75));
+        assert_eq!(line.timestamp, None);
+    }
+
+    #[test]
+    fn test_parse_errors_point_at_the_column() {
+        let error = |line: &str| parse_line(line).unwrap_err();
+
+        assert_eq!(
+            error("slurm"),
+            ParseError {
+                column: 6,
+                message: "expected a space before the fields".to_string()
+            }
+        );
+        assert_eq!(error("slurm,cluster cpus=1").column, 14);
+        assert_eq!(error("slurm cpus=abc").message, "invalid field value `abc`");
+        assert_eq!(error("slurm cpus=abc").column, 12);
+        assert_eq!(
+            error("slurm cpus=1 12:00").message,
+            "invalid timestamp `12:00`"
+        );
+        assert_eq!(error(r#"slurm note="open"#).column, 12);
+        assert_eq!(error("slurm cpus=1 1 2").column, 16);
+        assert_eq!(
+            error(",cluster=oscar cpus=1").message,
+            "missing measurement"
+        );
+    }
+
+    #[test]
+    fn test_parse_body_routes_measurements() {
+        let body = "# comment\n\
+                    slurm cpus_alloc=75i,cpus_total=100i,jobs=12i 1711497600\n\
+                    \n\
+                    slurm,gpu_type=a100 gpus_alloc=2i,gpus_total=4i 1711497600\n\
+                    disk used=1i 1711497600\n\
+                    slurm cpus_alloc=oops 1711497600\n\
+                    slurm cpus_alloc=\"many\",cpus_total=100i 1711497660\n";
+
+        let (points, report) = parse_body(
+            &LineProtocolConfig::default(),
+            body,
+            Precision::Seconds,
+            now(),
+        );
+
+        assert_eq!(points.len(), 5);
+        assert_eq!(report.rejected, 2);
+        assert_eq!(report.errors[0].index, 5);
+        assert_eq!(
+            report.errors[0].reason,
+            "line 6, column 18: invalid field value `oops`"
+        );
+        assert_eq!(
+            report.errors[1].reason,
+            "line 7: field cpus_alloc is not numeric"
+        );
+
+        let (samples, _, report) = assemble(points);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[1].2.gpu_type, "a100");
+        // cpus_total at 12:01 has no allocated to pair with.
+        assert_eq!(report.rejected, 1);
+    }
+
+    #[test]
+    fn test_precision() {
+        let ns = Precision::Nanoseconds.to_time(1_711_497_600_000_000_000);
+        assert_eq!(ns, Precision::Seconds.to_time(1_711_497_600));
+        assert_eq!(ns, Precision::Milliseconds.to_time(1_711_497_600_000));
+        assert_eq!(ns, Precision::Microseconds.to_time(1_711_497_600_000_000));
+
+        let uri: Uri = "/write?precision=s".parse().unwrap();
+        let Query(query) = Query::<WriteQuery>::try_from_uri(&uri).unwrap();
+        assert_eq!(query.precision, Precision::Seconds);
+
+        let uri: Uri = "/write?precision=n".parse().unwrap();
+        let Query(query) = Query::<WriteQuery>::try_from_uri(&uri).unwrap();
+        assert_eq!(query.precision, Precision::Nanoseconds);
+    }
+}
@@ -1,34 +1,280 @@
-use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use dotenvy::dotenv;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-use elmo_api::{create_app, get_db_connection};
+use elmo_api::auth::{AdminTokens, IngestTokens};
+use elmo_api::clusters::ClusterRegistry;
+use elmo_api::collector::backfill::{self, ImportArgs};
+use elmo_api::collector::{self, CollectArgs, ImportJobsArgs};
+use elmo_api::config::{Config, ConfigArgs, ConfigCommand};
+use elmo_api::database::{self, DbStatus};
+use elmo_api::line_protocol::LineProtocolConfig;
+use elmo_api::migrations::{self, MigrateCommand};
+use elmo_api::partitions::{self, PartitionArgs};
+use elmo_api::remote_write::RemoteWriteConfig;
+use elmo_api::replicas::Replicas;
+use elmo_api::retention::{self, RetentionArgs, RetentionPolicy};
+use elmo_api::rollup;
+use elmo_api::shutdown;
+use elmo_api::timeouts::QueryTimeouts;
+use elmo_api::{create_app, AppState};
+
+#[derive(Parser)]
+#[command(version, about = "ELMO utilization API")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file with server and database settings; environment variables and flags
+    /// override it.
+    #[arg(long, global = true, env = "ELMO_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    overrides: ConfigArgs,
+
+    /// Apply pending schema migrations before running the command.
+    #[arg(long, global = true, env = "MIGRATE_ON_STARTUP")]
+    migrate: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the HTTP API (the default)
+    Serve,
+    /// Sample utilization from `sinfo` and write it to the database
+    Collect(CollectArgs),
+    /// Import finished jobs from `sacct` into the jobs table
+    ImportJobs(ImportJobsArgs),
+    /// Backfill utilization samples from a CSV file
+    Import(ImportArgs),
+    /// Create upcoming monthly partitions and list every partition
+    Partitions(PartitionArgs),
+    /// Remove samples and rollups older than the RETENTION policy
+    Retention(RetentionArgs),
+    /// Show, apply or revert schema migrations
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommand,
+    },
+    /// Check the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // A .env file is only meant for local development; deployments set the environment
+    // or pass --config. It is loaded first so flags that fall back to an environment
+    // variable see it too.
+    dotenv().ok();
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref(), &cli.overrides)?;
+
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = &cli.command
+    {
+        print!("{}", config.to_redacted_toml()?);
+        eprintln!("Configuration is valid");
+        return Ok(());
+    }
+
     // Set up tracing for logging and request/response tracking
-    // This configuration can be overridden by setting the RUST_LOG environment variable
-    // For Docker deployments, ensure RUST_LOG is set in the Dockerfile or docker-compose.yml
-    // Example: ENV RUST_LOG=tower_http=trace,axum=trace,elmo_api=trace
+    // The filter comes from the configuration: log_filter, RUST_LOG or --log-filter
+    // Example: RUST_LOG=tower_http=trace,axum=trace,elmo_api=trace
     tracing_subscriber::registry()
         // Configure log levels for different components
         // tower_http: HTTP middleware logging
         // axum: Web framework logging
         // elmo_api: Our application-specific logs
-        .with(EnvFilter::new("tower_http=trace,axum=trace,elmo_api=trace"))
+        .with(EnvFilter::new(&config.server.log_filter))
         // Add formatting layer with targets enabled for better log readability
         .with(tracing_subscriber::fmt::layer().with_target(true))
         .init();
 
-    let pool = get_db_connection().await?;
+    let db = config.database.resolve()?;
+    let pool = db.lazy_pool();
+    let serving = matches!(cli.command, None | Some(Command::Serve));
+
+    // The CLI commands can't do anything without the database, so they wait for it
+    // here. The server starts straight away and waits in the background instead.
+    if !serving {
+        database::wait_for_db(&pool, Some(config.database.connect_attempts.max(1))).await?;
+
+        if let Some(Command::Migrate { command }) = &cli.command {
+            return migrations::run(command.clone(), &pool).await;
+        }
+        if cli.migrate {
+            migrations::POSTGRES.run(&pool).await?;
+            tracing::info!("Applied pending migrations");
+        }
+    }
+
+    // Only the server reads from replicas; the commands all write.
+    let replicas = if serving {
+        Replicas::from_config(&db, &config.database)?
+    } else {
+        Replicas::default()
+    };
+    let clusters = ClusterRegistry::from_env(pool.clone(), &replicas)?;
+    if !serving {
+        clusters.check_tables().await?;
+    }
+
+    match cli.command {
+        Some(Command::Collect(args)) => {
+            // Stop between runs rather than part way through writing a batch.
+            let token = CancellationToken::new();
+            tokio::spawn(shutdown::cancel_on_signal(token.clone()));
+            return collector::run(args, Arc::new(clusters), token).await;
+        }
+        Some(Command::ImportJobs(args)) => {
+            return collector::import_jobs(args, Arc::new(clusters)).await
+        }
+        Some(Command::Import(args)) => return backfill::import_csv(args, Arc::new(clusters)).await,
+        Some(Command::Partitions(args)) => {
+            return partitions::run_once(args, Arc::new(clusters)).await
+        }
+        Some(Command::Retention(args)) => {
+            return retention::run_once(args, Arc::new(clusters)).await
+        }
+        Some(Command::Serve)
+        | Some(Command::Migrate { .. })
+        | Some(Command::Config { .. })
+        | None => {}
+    }
 
-    let app = create_app(pool).await;
+    let mut state = AppState::new(pool.clone(), clusters);
+    state.db_status = DbStatus::default();
+    state.replicas = replicas.clone();
+    state.ingest_tokens = IngestTokens::from_env();
+    state.admin_tokens = AdminTokens::from_env();
+    state.remote_write = Arc::new(RemoteWriteConfig::from_env()?);
+    state.line_protocol = Arc::new(LineProtocolConfig::from_env()?);
+    state.query_timeouts = Arc::new(QueryTimeouts::from_env()?);
+    state.server = Arc::new(config.server.clone());
+    let token = state.shutdown.clone();
+    if state.ingest_tokens.is_empty() {
+        tracing::warn!("INGEST_TOKENS is not set, write endpoints will reject every request");
+    }
+
+    let rollup_interval = rollup::interval_from_env()?;
+    let months_ahead = partitions::months_ahead_from_env()?;
+    let retention = RetentionPolicy::from_env()?;
+    let retention_interval = retention::interval_from_env()?;
+
+    // Until the database answers, every endpoint that needs it returns 503. Once it is
+    // up (and migrated, with --migrate) and every cluster schema has its tables, the
+    // background jobs start and the monitor keeps the status current.
+    // Every background task is tracked so shutdown can wait for it to finish.
+    let tasks = TaskTracker::new();
+    if !replicas.is_empty() {
+        tasks.spawn(replicas.clone().monitor(token.clone()));
+    }
+
+    let clusters = state.clusters.clone();
+    let status = state.db_status.clone();
+    let migrate = cli.migrate;
+    let (db_pool, db_token, background) = (pool.clone(), token.clone(), tasks.clone());
+    tasks.spawn(async move {
+        let (pool, token, tasks) = (db_pool, db_token, background);
+
+        // Without an attempt limit this only returns once the database is reachable.
+        tokio::select! {
+            _ = database::wait_for_db(&pool, None) => {}
+            _ = token.cancelled() => return,
+        }
+
+        if migrate {
+            if let Err(e) = migrations::POSTGRES.run(&pool).await {
+                tracing::error!(
+                    "Error: failed to apply migrations, staying unavailable: {:?}",
+                    e
+                );
+                return;
+            }
+            tracing::info!("Applied pending migrations");
+        }
+        if let Err(e) = clusters.check_tables().await {
+            tracing::error!("Error: {:?}, staying unavailable", e);
+            return;
+        }
+
+        status.set_up(true);
+        tracing::info!("Database is reachable, serving requests");
+
+        tasks.spawn(rollup::run(
+            clusters.clone(),
+            retention.clone(),
+            rollup_interval,
+            token.clone(),
+        ));
+        tasks.spawn(partitions::run(
+            clusters.clone(),
+            months_ahead,
+            token.clone(),
+        ));
+        if !retention.is_empty() {
+            tasks.spawn(retention::run(
+                clusters,
+                retention,
+                retention_interval,
+                token.clone(),
+            ));
+        }
+
+        database::monitor(pool, status, token).await;
+    });
+
+    let clusters = state.clusters.clone();
+
+    let app = create_app(state).await;
 
     // run our app with hyper
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let address = config.server.address();
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("failed to listen on {address}"))?;
+
+    tracing::info!("listening on {}", listener.local_addr()?);
+
+    // On SIGTERM or SIGINT /ready starts failing at once. The listener closes after
+    // the shutdown delay, then requests in flight, the background tasks and the pools
+    // get until the drain timeout to finish.
+    tokio::spawn(shutdown::cancel_on_signal(token.clone()));
+    let drain = config.server.drain_times();
+    let mut server = tokio::spawn(shutdown::serve(listener, app, token.clone(), drain.delay));
+
+    tokio::select! {
+        result = &mut server => return Ok(result??),
+        _ = token.cancelled() => {}
+    }
+    let deadline = drain.deadline(Instant::now());
 
-    tracing::info!("listening on {}", listener.local_addr().unwrap());
+    match shutdown::until(deadline, "Requests", &mut server).await {
+        Some(result) => result??,
+        None => server.abort(),
+    }
 
-    axum::serve(listener, app).await.unwrap();
+    tasks.close();
+    shutdown::until(deadline, "Background tasks", tasks.wait()).await;
+    shutdown::until(
+        deadline,
+        "Database connections",
+        shutdown::close_pools(&pool, &clusters, &replicas),
+    )
+    .await;
+    tracing::info!("Shut down");
 
     Ok(())
 }
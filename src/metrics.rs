@@ -0,0 +1,405 @@
+use chrono::{DateTime, NaiveDateTime};
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+
+use crate::clusters::Cluster;
+use crate::ingest::{insert_samples, IngestReport, OnConflict, Sample, ValidSample};
+use crate::quality::{self, Quarantined};
+use crate::routes::Resource;
+
+/// Label (or tag) holding the GPU model of a GPU metric.
+pub const GPU_TYPE_LABEL: &str = "gpu_type";
+
+/// Which half of a `Utilization` sample a metric provides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Allocated,
+    Total,
+}
+
+/// Where a metric is stored, written as `cpu.allocated`, `gpu.total`, ...
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub resource: Resource,
+    pub field: Field,
+}
+
+impl std::str::FromStr for Target {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (resource, field) = value
+            .trim()
+            .split_once('.')
+            .ok_or_else(|| format!("target `{value}` must look like cpu.allocated"))?;
+
+        let resource = match resource {
+            "cpu" => Resource::Cpu,
+            "gpu" => Resource::Gpu,
+            _ => return Err(format!("unknown resource `{resource}`")),
+        };
+        let field = match field {
+            "allocated" => Field::Allocated,
+            "total" => Field::Total,
+            _ => {
+                return Err(format!(
+                    "unknown field `{field}`, expected allocated or total"
+                ))
+            }
+        };
+
+        Ok(Target { resource, field })
+    }
+}
+
+/// Maps a metric, optionally narrowed by label values, onto a resource field. Written
+/// as `slurm_cpus_alloc=cpu.allocated` or `slurm_gpus{state="alloc"}=gpu.allocated`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricRule {
+    pub name: String,
+    pub matchers: Vec<(String, String)>,
+    pub target: Target,
+}
+
+impl MetricRule {
+    /// Whether a metric named `name`, with labels looked up through `label`, matches.
+    pub fn matches<'a>(&self, name: &str, label: impl Fn(&str) -> Option<&'a str>) -> bool {
+        self.name == name
+            && self
+                .matchers
+                .iter()
+                .all(|(key, value)| label(key) == Some(value.as_str()))
+    }
+}
+
+fn parse_selector(selector: &str) -> Result<(String, Vec<(String, String)>)> {
+    let selector = selector.trim();
+    let Some((name, matchers)) = selector.split_once('{') else {
+        return Ok((selector.to_string(), Vec::new()));
+    };
+    let Some(matchers) = matchers.trim().strip_suffix('}') else {
+        bail!("selector `{selector}` is missing a closing brace");
+    };
+
+    let matchers = matchers
+        .split(',')
+        .filter(|matcher| !matcher.trim().is_empty())
+        .map(|matcher| {
+            let Some((key, value)) = matcher.split_once('=') else {
+                bail!("matcher `{matcher}` must look like label=\"value\"");
+            };
+            let value = value.trim().trim_matches('"');
+            Ok((key.trim().to_string(), value.to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((name.trim().to_string(), matchers))
+}
+
+/// Parses a `;`-separated list of rules such as
+/// `slurm_cpus_alloc=cpu.allocated; slurm_cpus_total=cpu.total`.
+pub fn parse_rules(value: &str) -> Result<Vec<MetricRule>> {
+    let rules = value
+        .split(';')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            // Matchers contain `=` too, but the target never does.
+            let Some((selector, target)) = rule.rsplit_once('=') else {
+                bail!("rule `{rule}` must look like metric=cpu.allocated");
+            };
+            let (name, matchers) = parse_selector(selector)?;
+            if name.is_empty() {
+                bail!("rule `{rule}` has no metric name");
+            }
+            let target = target.parse().map_err(anyhow::Error::msg)?;
+
+            Ok(MetricRule {
+                name,
+                matchers,
+                target,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if rules.is_empty() {
+        bail!("no metric rules configured");
+    }
+
+    Ok(rules)
+}
+
+/// A single value from a metrics protocol, already routed to a resource field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricPoint {
+    /// Position of the point's series or line in the request, for error reports.
+    pub index: usize,
+    pub target: Target,
+    pub time: NaiveDateTime,
+    pub gpu_type: Option<String>,
+    pub value: f64,
+}
+
+/// Converts a Unix timestamp in milliseconds to local time, like the collectors use.
+pub fn time_from_millis(millis: i64) -> Option<NaiveDateTime> {
+    let time = DateTime::from_timestamp_millis(millis)?;
+    Some(quality::stored_time(time))
+}
+
+/// Rounds a metric value to a count. Negative counts pass, so the quality checks can
+/// quarantine them.
+fn to_count(value: f64) -> Result<i32, String> {
+    if !value.is_finite() || value.abs() > f64::from(i32::MAX) {
+        return Err(format!("value {value} is not a valid count"));
+    }
+    Ok(value.round() as i32)
+}
+
+type PointKey = (Resource, NaiveDateTime, String);
+
+/// Pairs allocated and total points with the same resource, time and GPU type into
+/// samples. A point without its other half is rejected, as is any sample that fails
+/// the usual validation, and suspect samples are quarantined. When a field is sent
+/// twice the later point wins.
+pub fn assemble(
+    points: Vec<MetricPoint>,
+) -> (
+    Vec<(Resource, usize, ValidSample)>,
+    Vec<Quarantined>,
+    IngestReport,
+) {
+    let mut report = IngestReport::default();
+    let mut pairs: BTreeMap<PointKey, [Option<(usize, f64)>; 2]> = BTreeMap::new();
+
+    for point in points {
+        let key = (
+            point.target.resource,
+            point.time,
+            point.gpu_type.unwrap_or_default(),
+        );
+        let slot = match point.target.field {
+            Field::Allocated => 0,
+            Field::Total => 1,
+        };
+        pairs.entry(key).or_default()[slot] = Some((point.index, point.value));
+    }
+
+    let now = quality::now();
+    let mut samples = Vec::new();
+    let mut quarantined = Vec::new();
+    for ((resource, time, gpu_type), pair) in pairs {
+        let (index, allocated, total) = match pair {
+            [Some((index, allocated)), Some((_, total))] => (index, allocated, total),
+            [Some((index, _)), None] => {
+                report.reject(
+                    index,
+                    format!("no total for {} at {time}", resource.table()),
+                );
+                continue;
+            }
+            [None, Some((index, _))] => {
+                report.reject(
+                    index,
+                    format!("no allocated for {} at {time}", resource.table()),
+                );
+                continue;
+            }
+            [None, None] => continue,
+        };
+
+        let sample = to_count(allocated).and_then(|allocated| {
+            Sample {
+                time: Some(time),
+                allocated: Some(allocated),
+                total: Some(to_count(total)?),
+                gpu_type: (resource == Resource::Gpu).then_some(gpu_type),
+            }
+            .validate(resource)
+        });
+
+        match sample {
+            Ok(sample) => {
+                let (clean, suspect) =
+                    quality::screen(resource, [(index, sample)], now, &mut report);
+                samples.extend(
+                    clean
+                        .into_iter()
+                        .map(|(index, sample)| (resource, index, sample)),
+                );
+                quarantined.extend(suspect);
+            }
+            Err(reason) => report.reject(index, reason),
+        }
+    }
+
+    report.errors.sort_by_key(|error| error.index);
+    report.accepted = samples.len();
+
+    (samples, quarantined, report)
+}
+
+/// Writes assembled and quarantined samples to a cluster in one transaction. Samples
+/// that differ from a stored one are quarantined and counted as such in `report`.
+pub async fn write_samples(
+    cluster: &Cluster,
+    samples: Vec<(Resource, usize, ValidSample)>,
+    quarantined: &[Quarantined],
+    report: &mut IngestReport,
+) -> Result<(), sqlx::Error> {
+    let mut by_resource: BTreeMap<Resource, Vec<(usize, ValidSample)>> = BTreeMap::new();
+    for (resource, index, sample) in samples {
+        by_resource
+            .entry(resource)
+            .or_default()
+            .push((index, sample));
+    }
+
+    let mut tx = cluster.pool.begin().await?;
+    for (resource, samples) in &by_resource {
+        let conflicts = insert_samples(
+            &mut tx,
+            &cluster.schema,
+            *resource,
+            samples,
+            OnConflict::Keep,
+        )
+        .await?;
+        let conflicts = quality::quarantine_conflicts(*resource, conflicts, report);
+        quality::insert_quarantined(&mut tx, &cluster.schema, &conflicts).await?;
+    }
+    quality::insert_quarantined(&mut tx, &cluster.schema, quarantined).await?;
+    tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(index: usize, target: &str, time: &str, value: f64) -> MetricPoint {
+        MetricPoint {
+            index,
+            target: target.parse().unwrap(),
+            time: time.parse().unwrap(),
+            gpu_type: None,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            "gpu.total".parse::<Target>().unwrap(),
+            Target {
+                resource: Resource::Gpu,
+                field: Field::Total
+            }
+        );
+        assert!("cpu".parse::<Target>().is_err());
+        assert!("disk.total".parse::<Target>().is_err());
+        assert!("cpu.idle".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = parse_rules(
+            r#"slurm_cpus_alloc=cpu.allocated; slurm_gpus{state="alloc", gpu_type="a100"}=gpu.allocated"#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "slurm_cpus_alloc");
+        assert!(rules[0].matchers.is_empty());
+        assert_eq!(
+            rules[1].matchers,
+            vec![
+                ("state".to_string(), "alloc".to_string()),
+                ("gpu_type".to_string(), "a100".to_string())
+            ]
+        );
+        assert!(rules[1].matches("slurm_gpus", |label| match label {
+            "state" => Some("alloc"),
+            "gpu_type" => Some("a100"),
+            _ => None,
+        }));
+        assert!(!rules[1].matches("slurm_gpus", |_| None));
+    }
+
+    #[test]
+    fn test_parse_rules_rejects_bad_entries() {
+        assert!(parse_rules("").is_err());
+        assert!(parse_rules("slurm_cpus_alloc").is_err());
+        assert!(parse_rules("=cpu.allocated").is_err());
+        assert!(parse_rules("slurm_gpus{state=\"alloc\"=gpu.allocated").is_err());
+        assert!(parse_rules("slurm_cpus_alloc=cpu.free").is_err());
+    }
+
+    #[test]
+    fn test_assemble_pairs_fields() {
+        let points = vec![
+            point(0, "cpu.allocated", "2024-03-27T00:00:00", 75.0),
+            point(1, "cpu.total", "2024-03-27T00:00:00", 100.0),
+            point(2, "cpu.allocated", "2024-03-27T00:15:00", 80.0),
+            point(3, "cpu.allocated", "2024-03-27T00:30:00", 120.0),
+            point(4, "cpu.total", "2024-03-27T00:30:00", 100.0),
+        ];
+
+        let (samples, _, report) = assemble(points);
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].2.allocated, 75);
+        assert_eq!(report.rejected, 1);
+        assert_eq!(report.quarantined, 1);
+        assert_eq!(
+            report.errors[0].reason,
+            "no total for cpu at 2024-03-27 00:15:00"
+        );
+        assert_eq!(report.errors[1].index, 3);
+    }
+
+    #[test]
+    fn test_assemble_keeps_gpu_types_apart() {
+        let mut points = vec![
+            point(0, "gpu.allocated", "2024-03-27T00:00:00", 1.0),
+            point(1, "gpu.total", "2024-03-27T00:00:00", 4.0),
+            point(2, "gpu.allocated", "2024-03-27T00:00:00", 2.0),
+            point(3, "gpu.total", "2024-03-27T00:00:00", 8.0),
+        ];
+        points[0].gpu_type = Some("a100".to_string());
+        points[1].gpu_type = Some("a100".to_string());
+        points[2].gpu_type = Some("v100".to_string());
+        points[3].gpu_type = Some("v100".to_string());
+
+        let (samples, _, report) = assemble(points);
+
+        assert_eq!(report.rejected, 0);
+        assert_eq!(samples[0].2.gpu_type, "a100");
+        assert_eq!(samples[1].2.total, 8);
+    }
+
+    #[test]
+    fn test_assemble_rejects_invalid_counts() {
+        let (samples, _, report) = assemble(vec![
+            point(0, "cpu.allocated", "2024-03-27T00:00:00", 1e12),
+            point(1, "cpu.total", "2024-03-27T00:00:00", 100.0),
+        ]);
+
+        assert!(samples.is_empty());
+        assert_eq!(
+            report.errors[0].reason,
+            "value 1000000000000 is not a valid count"
+        );
+    }
+
+    #[test]
+    fn test_assemble_quarantines_suspect_samples() {
+        let (samples, quarantined, report) = assemble(vec![
+            point(0, "cpu.allocated", "2024-03-27T00:00:00", -1.0),
+            point(1, "cpu.total", "2024-03-27T00:00:00", 100.0),
+        ]);
+
+        assert!(samples.is_empty());
+        assert_eq!(quarantined[0].sample.allocated, -1);
+        assert_eq!(report.quarantined, 1);
+    }
+}
@@ -0,0 +1,197 @@
+use std::ops::Deref;
+
+use anyhow::Result;
+use sqlx::migrate::{AppliedMigration, Migrate, MigrateError, Migrator};
+use sqlx::postgres::PgPool;
+use sqlx::Acquire;
+
+/// Migrations building the `oscar` schema, embedded in the binary.
+pub static POSTGRES: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// The same migrations for SQLite, version for version, without the schema prefix.
+/// Tests use these to build their databases.
+pub static SQLITE: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum MigrateCommand {
+    /// List every migration and whether it has been applied
+    Status,
+    /// Apply all pending migrations
+    Up,
+    /// Revert the latest migration, or every migration above --target
+    Down {
+        /// Version to revert to; 0 reverts everything.
+        #[arg(long)]
+        target: Option<i64>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but the embedded SQL has changed since.
+    Modified,
+    /// Failed part way; the database needs fixing by hand.
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+fn statuses(
+    migrator: &Migrator,
+    applied: &[AppliedMigration],
+    dirty: Option<i64>,
+) -> Vec<MigrationStatus> {
+    migrator
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .map(|migration| {
+            let state = match applied.iter().find(|a| a.version == migration.version) {
+                _ if dirty == Some(migration.version) => MigrationState::Failed,
+                Some(applied) if applied.checksum != migration.checksum => MigrationState::Modified,
+                Some(_) => MigrationState::Applied,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+            }
+        })
+        .collect()
+}
+
+/// Reports the state of each embedded migration in the database.
+pub async fn status<'a, A>(
+    migrator: &Migrator,
+    conn: A,
+) -> Result<Vec<MigrationStatus>, MigrateError>
+where
+    A: Acquire<'a>,
+    <A::Connection as Deref>::Target: Migrate,
+{
+    let mut conn = conn.acquire().await?;
+    conn.ensure_migrations_table().await?;
+
+    let dirty = conn.dirty_version().await?;
+    let applied = conn.list_applied_migrations().await?;
+
+    Ok(statuses(migrator, &applied, dirty))
+}
+
+/// Version `down` reverts to when no target is given: the one before the latest applied.
+fn previous_version(statuses: &[MigrationStatus]) -> i64 {
+    let mut applied = statuses
+        .iter()
+        .filter(|status| status.state != MigrationState::Pending)
+        .map(|status| status.version)
+        .rev();
+    applied.next();
+    applied.next().unwrap_or(0)
+}
+
+/// Runs `elmo-api migrate <command>` against the main database.
+pub async fn run(command: MigrateCommand, pool: &PgPool) -> Result<()> {
+    match command {
+        MigrateCommand::Status => {
+            for status in status(&POSTGRES, pool).await? {
+                println!(
+                    "{:>4}  {:<9} {}",
+                    status.version,
+                    format!("{:?}", status.state).to_lowercase(),
+                    status.description
+                );
+            }
+        }
+        MigrateCommand::Up => {
+            POSTGRES.run(pool).await?;
+            tracing::info!("Database schema is up to date");
+        }
+        MigrateCommand::Down { target } => {
+            let target = match target {
+                Some(target) => target,
+                None => previous_version(&status(&POSTGRES, pool).await?),
+            };
+            POSTGRES.undo(pool, target).await?;
+            tracing::info!("Reverted migrations above version {}", target);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    async fn tables(pool: &SqlitePool) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'table' \
+             AND name NOT LIKE 'sqlite_%' AND name NOT LIKE '_sqlx%' ORDER BY name",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn test_postgres_and_sqlite_versions_match() {
+        let versions = |migrator: &Migrator| {
+            migrator
+                .iter()
+                .map(|migration| (migration.version, migration.migration_type))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(versions(&POSTGRES), versions(&SQLITE));
+        assert!(POSTGRES
+            .iter()
+            .all(|migration| migration.migration_type.is_reversible()));
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_migrations_up_and_down() {
+        let pool = SqlitePool::connect(":memory:").await.unwrap();
+
+        SQLITE.run(&pool).await.unwrap();
+        assert_eq!(
+            tables(&pool).await,
+            vec![
+                "cpu",
+                "fairshare",
+                "gpu",
+                "gpu_type_rollup",
+                "import_state",
+                "jobs",
+                "partition_utilization",
+                "quarantine",
+                "quotas",
+                "rollup",
+                "rollup_dirty",
+                "storage",
+            ]
+        );
+
+        let statuses = status(&SQLITE, &pool).await.unwrap();
+        assert!(statuses
+            .iter()
+            .all(|status| status.state == MigrationState::Applied));
+        assert_eq!(previous_version(&statuses), statuses.len() as i64 - 1);
+
+        SQLITE.undo(&pool, 2).await.unwrap();
+        assert_eq!(tables(&pool).await, vec!["cpu", "gpu", "jobs"]);
+        let statuses = status(&SQLITE, &pool).await.unwrap();
+        assert_eq!(statuses[2].state, MigrationState::Pending);
+        assert_eq!(previous_version(&statuses), 1);
+
+        SQLITE.undo(&pool, 0).await.unwrap();
+        assert!(tables(&pool).await.is_empty());
+    }
+}
@@ -0,0 +1,542 @@
+use chrono::{Datelike, Months, NaiveDate, NaiveDateTime};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::{Postgres, QueryBuilder};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+use crate::clusters::{find_cluster, Cluster, ClusterQuery, ClusterRegistry};
+use crate::quality;
+use crate::routes::Resource;
+
+/// Months of partitions kept ready after the current one, unless
+/// `PARTITION_MONTHS_AHEAD` says otherwise.
+const DEFAULT_MONTHS_AHEAD: u32 = 3;
+
+/// How often the server checks for missing partitions.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// One partition of a raw sample table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Partition {
+    pub resource: Resource,
+    pub name: String,
+    /// Start of the range, `None` when it has no lower bound.
+    pub from: Option<NaiveDateTime>,
+    /// End of the range (exclusive), `None` when it has no upper bound.
+    pub to: Option<NaiveDateTime>,
+    pub rows: i64,
+    pub size_bytes: i64,
+    /// The DEFAULT partition, which takes the samples no range covers.
+    pub default: bool,
+}
+
+/// The range `[from, to)` of a partition; `None` stands for MINVALUE or MAXVALUE.
+pub type Bound = (Option<NaiveDateTime>, Option<NaiveDateTime>);
+
+/// Parses a range bound as printed by `pg_get_expr`, e.g.
+/// `FOR VALUES FROM ('2024-04-01 00:00:00') TO ('2024-05-01 00:00:00')`.
+pub fn parse_bound(expr: &str) -> Option<Bound> {
+    let value = |value: &str| match value {
+        "MINVALUE" | "MAXVALUE" => Some(None),
+        _ => NaiveDateTime::parse_from_str(value.trim_matches('\''), "%Y-%m-%d %H:%M:%S")
+            .ok()
+            .map(Some),
+    };
+
+    let range = expr.strip_prefix("FOR VALUES FROM (")?.strip_suffix(')')?;
+    let (from, to) = range.split_once(") TO (")?;
+
+    Some((value(from)?, value(to)?))
+}
+
+pub fn month_start(time: NaiveDateTime) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(time.year(), time.month(), 1)
+        .expect("valid date")
+        .and_hms_opt(0, 0, 0)
+        .expect("valid time")
+}
+
+pub fn partition_name(resource: Resource, month: NaiveDateTime) -> String {
+    format!("{}_p{}", resource.table(), month.format("%Y_%m"))
+}
+
+/// Starts of the partitions needed so every month up to `ahead` months after `now` is
+/// covered. Partitions are added after the latest existing one, so there are no gaps;
+/// each runs to the end of the month it starts in.
+pub fn missing_months(bounds: &[Bound], now: NaiveDateTime, ahead: u32) -> Vec<NaiveDateTime> {
+    let last = month_start(now) + Months::new(ahead);
+
+    // A partition without an upper bound already takes everything.
+    if bounds.iter().any(|(_, to)| to.is_none()) {
+        return Vec::new();
+    }
+    let mut month = match bounds.iter().filter_map(|(_, to)| *to).max() {
+        Some(to) => to,
+        None => month_start(now),
+    };
+
+    let mut months = Vec::new();
+    while month <= last {
+        months.push(month);
+        month = month_start(month) + Months::new(1);
+    }
+    months
+}
+
+/// `Some(owned)` if `{schema}.{table}` is partitioned, where `owned` says whether the
+/// current user owns it and so may add and drop partitions. `None` for plain tables.
+async fn partitioning(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+) -> Result<Option<bool>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT pg_has_role(current_user, relowner, 'USAGE') FROM pg_class \
+         WHERE oid = to_regclass($1) AND relkind = 'p'",
+    )
+    .bind(format!("{schema}.{}", resource.table()))
+    .fetch_optional(pool)
+    .await
+}
+
+/// Name, bound and size of each partition of `{schema}.{table}`.
+async fn partition_bounds(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+) -> Result<Vec<(String, Bound, i64)>, sqlx::Error> {
+    let rows: Vec<(String, String, i64)> = sqlx::query_as(
+        r#"
+        SELECT
+            c.relname::text,
+            pg_get_expr(c.relpartbound, c.oid),
+            pg_total_relation_size(c.oid)
+        FROM pg_inherits i
+        JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE i.inhparent = to_regclass($1)
+        "#,
+    )
+    .bind(format!("{schema}.{}", resource.table()))
+    .fetch_all(pool)
+    .await?;
+
+    let mut partitions: Vec<_> = rows
+        .into_iter()
+        .filter_map(|(name, expr, size)| match parse_bound(&expr) {
+            Some(bound) => Some((name, bound, size)),
+            None if expr == "DEFAULT" => None,
+            None => {
+                tracing::warn!("Ignoring partition {} with bound {}", name, expr);
+                None
+            }
+        })
+        .collect();
+    partitions.sort_by_key(|(_, (from, _), _)| *from);
+
+    Ok(partitions)
+}
+
+/// Name and size of the DEFAULT partition of `{schema}.{table}`, if it has one.
+async fn default_partition(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+) -> Result<Option<(String, i64)>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT c.relname::text, pg_total_relation_size(c.oid)
+        FROM pg_inherits i
+        JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE i.inhparent = to_regclass($1) AND pg_get_expr(c.relpartbound, c.oid) = 'DEFAULT'
+        "#,
+    )
+    .bind(format!("{schema}.{}", resource.table()))
+    .fetch_optional(pool)
+    .await
+}
+
+/// Adds the partition `name` for `month` next to the DEFAULT partition `default`.
+/// Postgres refuses a new partition while the default holds rows in its range, so
+/// those samples move into the new table before it is attached. Returns how many moved.
+async fn create_beside_default(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+    name: &str,
+    default: &str,
+    month: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    let table = resource.table();
+    let to = month_start(month) + Months::new(1);
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {schema}.{name} (LIKE {schema}.{table} INCLUDING DEFAULTS)"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    let moved = sqlx::query(&format!(
+        "WITH moved AS (\
+         DELETE FROM {schema}.{default} WHERE time >= $1 AND time < $2 RETURNING *) \
+         INSERT INTO {schema}.{name} SELECT * FROM moved"
+    ))
+    .bind(month)
+    .bind(to)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    // Bounds are formatted by us, so they are safe to interpolate.
+    sqlx::query(&format!(
+        "ALTER TABLE {schema}.{table} ATTACH PARTITION {schema}.{name} \
+         FOR VALUES FROM ('{month}') TO ('{to}')"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(moved)
+}
+
+/// Creates the partitions `missing_months` asks for in every partitioned table the
+/// current user owns, and returns their names.
+pub async fn create_ahead(
+    pool: &PgPool,
+    schema: &str,
+    now: NaiveDateTime,
+    ahead: u32,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut created = Vec::new();
+
+    for resource in [Resource::Cpu, Resource::Gpu] {
+        match partitioning(pool, schema, resource).await? {
+            None => continue,
+            Some(false) => {
+                tracing::warn!(
+                    "Not creating partitions of {}.{}, the database user doesn't own it; \
+                     new months go to its default partition",
+                    schema,
+                    resource.table()
+                );
+                continue;
+            }
+            Some(true) => {}
+        }
+
+        let bounds: Vec<Bound> = partition_bounds(pool, schema, resource)
+            .await?
+            .into_iter()
+            .map(|(_, bound, _)| bound)
+            .collect();
+        let default = default_partition(pool, schema, resource).await?;
+
+        for month in missing_months(&bounds, now, ahead) {
+            let name = partition_name(resource, month_start(month));
+            match &default {
+                Some((default, _)) => {
+                    let moved =
+                        create_beside_default(pool, schema, resource, &name, default, month)
+                            .await?;
+                    if moved > 0 {
+                        tracing::info!(
+                            "Moved {} samples from {}.{} into {}",
+                            moved,
+                            schema,
+                            default,
+                            name
+                        );
+                    }
+                }
+                None => {
+                    // Bounds are formatted by us, so they are safe to interpolate.
+                    sqlx::query(&format!(
+                        "CREATE TABLE IF NOT EXISTS {schema}.{name} PARTITION OF {schema}.{table} \
+                         FOR VALUES FROM ('{from}') TO ('{to}')",
+                        table = resource.table(),
+                        from = month,
+                        to = month_start(month) + Months::new(1),
+                    ))
+                    .execute(pool)
+                    .await?;
+                }
+            }
+            created.push(name);
+        }
+    }
+
+    Ok(created)
+}
+
+/// Detaches and drops the partitions of `resource` that end at or before `cutoff`, as
+/// long as their buckets are rolled up, and returns how many samples they held. Does
+/// nothing for plain tables, or partitioned ones the current user doesn't own.
+pub async fn drop_expired(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+    cutoff: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    if partitioning(pool, schema, resource).await? != Some(true) {
+        return Ok(0);
+    }
+
+    let table = resource.table();
+    let mut dropped = 0;
+
+    for (name, (from, to), _) in partition_bounds(pool, schema, resource).await? {
+        let Some(to) = to.filter(|to| *to <= cutoff) else {
+            continue;
+        };
+
+        let dirty: bool = sqlx::query_scalar(&format!(
+            "SELECT EXISTS (SELECT 1 FROM {schema}.rollup_dirty \
+             WHERE resource = '{table}' AND time < $1 AND ($2::timestamp IS NULL OR time >= $2))"
+        ))
+        .bind(to)
+        .bind(from)
+        .fetch_one(pool)
+        .await?;
+        if dirty {
+            continue;
+        }
+
+        let rows: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {schema}.{name}"))
+            .fetch_one(pool)
+            .await?;
+        sqlx::query(&format!(
+            "ALTER TABLE {schema}.{table} DETACH PARTITION {schema}.{name}"
+        ))
+        .execute(pool)
+        .await?;
+        sqlx::query(&format!("DROP TABLE {schema}.{name}"))
+            .execute(pool)
+            .await?;
+
+        tracing::info!(
+            "Dropped expired partition {}.{} ({} rows)",
+            schema,
+            name,
+            rows
+        );
+        dropped += rows as u64;
+    }
+
+    Ok(dropped)
+}
+
+/// Every partition of the raw tables in `schema`, with row counts and sizes. Rows are
+/// counted through the parent table, so only access to it is needed.
+pub async fn list(pool: &PgPool, schema: &str) -> Result<Vec<Partition>, sqlx::Error> {
+    let mut partitions = Vec::new();
+
+    for resource in [Resource::Cpu, Resource::Gpu] {
+        for (name, (from, to), size_bytes) in partition_bounds(pool, schema, resource).await? {
+            let mut query = QueryBuilder::<Postgres>::new(format!(
+                "SELECT COUNT(*) FROM {schema}.{} WHERE TRUE",
+                resource.table()
+            ));
+            if let Some(from) = from {
+                query.push(" AND time >= ").push_bind(from);
+            }
+            if let Some(to) = to {
+                query.push(" AND time < ").push_bind(to);
+            }
+            let rows: i64 = query.build_query_scalar().fetch_one(pool).await?;
+
+            partitions.push(Partition {
+                resource,
+                name,
+                from,
+                to,
+                rows,
+                size_bytes,
+                default: false,
+            });
+        }
+
+        if let Some((name, size_bytes)) = default_partition(pool, schema, resource).await? {
+            let rows: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {schema}.{} WHERE tableoid = to_regclass($1)",
+                resource.table()
+            ))
+            .bind(format!("{schema}.{name}"))
+            .fetch_one(pool)
+            .await?;
+
+            partitions.push(Partition {
+                resource,
+                name,
+                from: None,
+                to: None,
+                rows,
+                size_bytes,
+                default: true,
+            });
+        }
+    }
+
+    Ok(partitions)
+}
+
+pub async fn get_partitions(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(query): Query<ClusterQuery>,
+) -> Result<Json<Vec<Partition>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, query.cluster.as_deref())?;
+
+    let partitions = list(&cluster.pool, &cluster.schema).await.map_err(|e| {
+        tracing::error!("Error: failed to list partitions: {:?}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    Ok(Json(partitions))
+}
+
+/// Reads `PARTITION_MONTHS_AHEAD`, how many months of partitions to keep ready.
+pub fn months_ahead_from_env() -> Result<u32> {
+    match env::var("PARTITION_MONTHS_AHEAD") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid PARTITION_MONTHS_AHEAD `{value}`")),
+        Err(_) => Ok(DEFAULT_MONTHS_AHEAD),
+    }
+}
+
+async fn maintain(cluster: &Cluster, ahead: u32) {
+    match create_ahead(&cluster.pool, &cluster.schema, quality::now(), ahead).await {
+        Ok(created) => {
+            for name in created {
+                tracing::info!("Created partition {}.{}", cluster.schema, name);
+            }
+        }
+        Err(e) => tracing::error!(
+            "Error: failed to create partitions for {}: {:?}",
+            cluster.name,
+            e
+        ),
+    }
+}
+
+/// Keeps `ahead` months of partitions ready in every cluster, until `shutdown` is
+/// cancelled. Expired partitions are dropped by the retention task.
+pub async fn run(clusters: Arc<ClusterRegistry>, ahead: u32, shutdown: CancellationToken) {
+    let mut ticker = tokio::time::interval(MAINTENANCE_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        for cluster in clusters.iter() {
+            maintain(cluster, ahead).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct PartitionArgs {
+    /// Cluster to maintain; defaults to every cluster
+    #[arg(long)]
+    pub cluster: Option<String>,
+}
+
+/// Runs `elmo-api partitions`: creates missing partitions and prints every partition.
+pub async fn run_once(args: PartitionArgs, clusters: Arc<ClusterRegistry>) -> Result<()> {
+    let ahead = months_ahead_from_env()?;
+    let selected: Vec<&Cluster> = match &args.cluster {
+        Some(name) => vec![clusters
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown cluster `{name}`"))?],
+        None => clusters.iter().collect(),
+    };
+
+    for cluster in selected {
+        maintain(cluster, ahead).await;
+
+        for partition in list(&cluster.pool, &cluster.schema).await? {
+            let bound = |time: Option<NaiveDateTime>| {
+                time.map(|time| time.date().to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            };
+            println!(
+                "{}  {:<16} {:>10} .. {:<10} {:>10} rows {:>12} bytes",
+                cluster.name,
+                partition.name,
+                bound(partition.from),
+                bound(partition.to),
+                partition.rows,
+                partition.size_bytes
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveDateTime {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_bound() {
+        assert_eq!(
+            parse_bound("FOR VALUES FROM ('2024-04-01 00:00:00') TO ('2024-05-01 00:00:00')"),
+            Some((
+                Some(time("2024-04-01T00:00:00")),
+                Some(time("2024-05-01T00:00:00"))
+            ))
+        );
+        assert_eq!(
+            parse_bound("FOR VALUES FROM (MINVALUE) TO ('2024-04-01 00:00:00')"),
+            Some((None, Some(time("2024-04-01T00:00:00"))))
+        );
+        assert_eq!(parse_bound("DEFAULT"), None);
+    }
+
+    #[test]
+    fn test_missing_months() {
+        let now = time("2024-03-27T12:00:00");
+
+        assert_eq!(
+            missing_months(&[(None, Some(time("2024-04-01T00:00:00")))], now, 2),
+            vec![time("2024-04-01T00:00:00"), time("2024-05-01T00:00:00")]
+        );
+        // Nothing is missing once the horizon is covered.
+        assert_eq!(
+            missing_months(&[(None, Some(time("2024-06-01T00:00:00")))], now, 2),
+            Vec::<NaiveDateTime>::new()
+        );
+        // Months the server was down for are filled in too.
+        assert_eq!(
+            missing_months(&[(None, Some(time("2024-01-01T00:00:00")))], now, 0).len(),
+            3
+        );
+        assert_eq!(missing_months(&[], now, 1).len(), 2);
+        assert!(missing_months(&[(None, None)], now, 1).is_empty());
+    }
+
+    #[test]
+    fn test_partition_name() {
+        assert_eq!(
+            partition_name(Resource::Gpu, time("2024-04-01T00:00:00")),
+            "gpu_p2024_04"
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgConnection;
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::ingest::{insert_samples, IngestReport, OnConflict, ValidSample};
+use crate::routes::Resource;
+
+/// How far ahead of the server clock a sample may be. Collectors with a slightly fast
+/// clock stay within this; anything later is quarantined.
+pub const MAX_CLOCK_SKEW: Duration = Duration::minutes(15);
+
+/// Rows returned by `GET /admin/quarantine` when no limit is given, and at most.
+const DEFAULT_LIST_LIMIT: i64 = 100;
+const MAX_LIST_LIMIT: i64 = 1000;
+
+/// A suspect sample, kept out of the averages until someone releases or deletes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quarantined {
+    pub resource: Resource,
+    pub sample: ValidSample,
+    pub reason: String,
+}
+
+/// Converts a point in time to the form samples are stored in: a timestamp without
+/// time zone, in the server's local time. Every write path goes through this, so
+/// stored times and [`now`] can be compared.
+pub fn stored_time<Tz: TimeZone>(time: DateTime<Tz>) -> NaiveDateTime {
+    time.with_timezone(&chrono::Local).naive_local()
+}
+
+/// The server's clock, in the zone samples are stored in.
+pub fn now() -> NaiveDateTime {
+    stored_time(Utc::now())
+}
+
+/// Returns why a sample looks wrong, or `None` if it passes every check.
+pub fn inspect(sample: &ValidSample, now: NaiveDateTime) -> Option<String> {
+    if sample.allocated < 0 || sample.total < 0 {
+        return Some("allocated and total must not be negative".to_string());
+    }
+    if sample.allocated > sample.total {
+        return Some(format!(
+            "allocated ({}) exceeds total ({})",
+            sample.allocated, sample.total
+        ));
+    }
+    if sample.time > now + MAX_CLOCK_SKEW {
+        return Some(format!("time {} is in the future", sample.time));
+    }
+
+    None
+}
+
+/// Splits `(index, sample)` rows into clean rows and quarantined samples. Quarantined
+/// rows are recorded in `report` under their index.
+pub fn screen(
+    resource: Resource,
+    rows: impl IntoIterator<Item = (usize, ValidSample)>,
+    now: NaiveDateTime,
+    report: &mut IngestReport,
+) -> (Vec<(usize, ValidSample)>, Vec<Quarantined>) {
+    let mut samples = Vec::new();
+    let mut quarantined = Vec::new();
+
+    for (index, sample) in rows {
+        match inspect(&sample, now) {
+            None => samples.push((index, sample)),
+            Some(reason) => {
+                report.quarantine(index, &reason);
+                quarantined.push(Quarantined {
+                    resource,
+                    sample,
+                    reason,
+                });
+            }
+        }
+    }
+
+    (samples, quarantined)
+}
+
+/// Quarantines rows that `insert_samples` didn't write because a different sample is
+/// already stored at their time, moving them from accepted to quarantined in `report`.
+pub fn quarantine_conflicts(
+    resource: Resource,
+    conflicts: Vec<(usize, ValidSample)>,
+    report: &mut IngestReport,
+) -> Vec<Quarantined> {
+    let reason = "conflicts with the stored sample";
+    let quarantined: Vec<Quarantined> = conflicts
+        .into_iter()
+        .map(|(index, sample)| {
+            report.quarantine(index, reason);
+            Quarantined {
+                resource,
+                sample,
+                reason: reason.to_string(),
+            }
+        })
+        .collect();
+
+    report.accepted -= quarantined.len();
+    report.errors.sort_by_key(|error| error.index);
+
+    quarantined
+}
+
+// Each row binds 7 parameters and Postgres allows at most 65535 per statement.
+const INSERT_CHUNK_SIZE: usize = 9_000;
+
+/// Adds samples to `{schema}.quarantine` on an existing connection, so they are written
+/// in the same transaction as the rest of their batch.
+pub async fn insert_quarantined(
+    conn: &mut PgConnection,
+    schema: &str,
+    quarantined: &[Quarantined],
+) -> Result<u64, sqlx::Error> {
+    let mut affected = 0;
+
+    for chunk in quarantined.chunks(INSERT_CHUNK_SIZE) {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "INSERT INTO {schema}.quarantine \
+             (resource, time, allocated, total, gpu_type, reason, quarantined_at) "
+        ));
+        // quarantined_at comes from our clock rather than the database's, so it is in
+        // the same zone as the sample times.
+        let quarantined_at = now();
+        query.push_values(chunk, |mut row, entry| {
+            row.push_bind(entry.resource.table())
+                .push_bind(entry.sample.time)
+                .push_bind(entry.sample.allocated)
+                .push_bind(entry.sample.total)
+                .push_bind(entry.sample.gpu_type.clone())
+                .push_bind(entry.reason.clone())
+                .push_bind(quarantined_at);
+        });
+
+        affected += query.build().execute(&mut *conn).await?.rows_affected();
+    }
+
+    Ok(affected)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct QuarantinedSample {
+    pub id: i64,
+    pub resource: String,
+    pub time: NaiveDateTime,
+    pub allocated: i32,
+    pub total: i32,
+    pub gpu_type: String,
+    pub reason: String,
+    pub quarantined_at: NaiveDateTime,
+}
+
+const QUARANTINE_COLUMNS: &str =
+    "id, resource, time, allocated, total, gpu_type, reason, quarantined_at";
+
+#[derive(Debug, Deserialize)]
+pub struct QuarantineQuery {
+    pub cluster: Option<String>,
+    pub resource: Option<Resource>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Lists quarantined samples, oldest first.
+pub async fn get_quarantine(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(query): Query<QuarantineQuery>,
+) -> Result<Json<Vec<QuarantinedSample>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, query.cluster.as_deref())?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    let mut builder = QueryBuilder::<Postgres>::new(format!(
+        "SELECT {QUARANTINE_COLUMNS} FROM {}.quarantine",
+        cluster.schema
+    ));
+    if let Some(resource) = query.resource {
+        builder
+            .push(" WHERE resource = ")
+            .push_bind(resource.table());
+    }
+    builder
+        .push(" ORDER BY time, id LIMIT ")
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(query.offset.unwrap_or(0).max(0));
+
+    let rows = builder
+        .build_query_as::<QuarantinedSample>()
+        .fetch_all(&cluster.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to list quarantined samples: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(rows))
+}
+
+/// Moves a quarantined sample into its resource table, replacing any sample stored for
+/// the same time (and GPU type).
+pub async fn release_quarantined(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Path(id): Path<i64>,
+    Query(query): Query<ClusterQuery>,
+) -> Result<Json<QuarantinedSample>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, query.cluster.as_deref())?;
+    let internal_error = |e: sqlx::Error| {
+        tracing::error!(
+            "Error: failed to release quarantined sample {}: {:?}",
+            id,
+            e
+        );
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    };
+
+    let mut tx = cluster.pool.begin().await.map_err(internal_error)?;
+
+    let row: Option<QuarantinedSample> = sqlx::query_as(&format!(
+        "DELETE FROM {}.quarantine WHERE id = $1 RETURNING {QUARANTINE_COLUMNS}",
+        cluster.schema
+    ))
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+
+    let Some(row) = row else {
+        return Err((StatusCode::NOT_FOUND, format!("no quarantined sample {id}")));
+    };
+    let Some(resource) = Resource::from_table(&row.resource) else {
+        tracing::error!(
+            "Error: quarantined sample {} has resource {}",
+            id,
+            row.resource
+        );
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, String::new()));
+    };
+
+    let sample = ValidSample {
+        time: row.time,
+        allocated: row.allocated,
+        total: row.total,
+        gpu_type: row.gpu_type.clone(),
+    };
+    insert_samples(
+        &mut tx,
+        &cluster.schema,
+        resource,
+        &[(0, sample)],
+        OnConflict::Replace,
+    )
+    .await
+    .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    tracing::info!("Released quarantined {} sample {}", row.resource, id);
+
+    Ok(Json(row))
+}
+
+pub async fn delete_quarantined(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Path(id): Path<i64>,
+    Query(query): Query<ClusterQuery>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, query.cluster.as_deref())?;
+
+    let result = sqlx::query(&format!(
+        "DELETE FROM {}.quarantine WHERE id = $1",
+        cluster.schema
+    ))
+    .bind(id)
+    .execute(&cluster.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to delete quarantined sample {}: {:?}", id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, format!("no quarantined sample {id}")));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: &str, allocated: i32, total: i32) -> ValidSample {
+        ValidSample {
+            time: time.parse().unwrap(),
+            allocated,
+            total,
+            gpu_type: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_inspect() {
+        let now = "2024-03-27T12:00:00".parse().unwrap();
+
+        assert_eq!(inspect(&sample("2024-03-27T12:00:00", 75, 100), now), None);
+        assert_eq!(
+            inspect(&sample("2024-03-27T12:00:00", 120, 100), now).as_deref(),
+            Some("allocated (120) exceeds total (100)")
+        );
+        assert_eq!(
+            inspect(&sample("2024-03-27T12:00:00", -1, -1), now).as_deref(),
+            Some("allocated and total must not be negative")
+        );
+        // A slightly fast collector clock is fine.
+        assert_eq!(inspect(&sample("2024-03-27T12:10:00", 75, 100), now), None);
+        assert_eq!(
+            inspect(&sample("2024-03-28T12:00:00", 75, 100), now).as_deref(),
+            Some("time 2024-03-28 12:00:00 is in the future")
+        );
+    }
+
+    #[test]
+    fn test_screen_records_quarantined_rows() {
+        let now = "2024-03-27T12:00:00".parse().unwrap();
+        let mut report = IngestReport::default();
+
+        let (samples, quarantined) = screen(
+            Resource::Cpu,
+            vec![
+                (0, sample("2024-03-27T00:00:00", 75, 100)),
+                (3, sample("2024-03-27T00:15:00", 101, 100)),
+            ],
+            now,
+            &mut report,
+        );
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(quarantined[0].resource, Resource::Cpu);
+        assert_eq!(report.quarantined, 1);
+        assert_eq!(report.errors[0].index, 3);
+        assert_eq!(
+            report.errors[0].reason,
+            "quarantined: allocated (101) exceeds total (100)"
+        );
+    }
+
+    #[test]
+    fn test_quarantine_conflicts_moves_rows_out_of_accepted() {
+        let mut report = IngestReport {
+            accepted: 2,
+            ..IngestReport::default()
+        };
+        report.reject(0, "time is missing");
+
+        let quarantined = quarantine_conflicts(
+            Resource::Gpu,
+            vec![(3, sample("2024-03-27T00:00:00", 4, 8))],
+            &mut report,
+        );
+
+        assert_eq!(quarantined[0].reason, "conflicts with the stored sample");
+        assert_eq!((report.accepted, report.quarantined), (1, 1));
+        assert_eq!(report.errors[1].index, 3);
+    }
+}
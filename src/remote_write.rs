@@ -0,0 +1,265 @@
+use std::env;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use prost::Message;
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::ingest::IngestReport;
+use crate::metrics::{
+    assemble, parse_rules, time_from_millis, write_samples, MetricPoint, MetricRule, GPU_TYPE_LABEL,
+};
+
+/// Mapping used when `REMOTE_WRITE_METRICS` is not set, matching the metric names of
+/// prometheus-slurm-exporter.
+pub const DEFAULT_RULES: &str = "slurm_cpus_alloc=cpu.allocated; slurm_cpus_total=cpu.total; \
+                                 slurm_gpus_alloc=gpu.allocated; slurm_gpus_total=gpu.total";
+
+/// The subset of Prometheus' `prometheus.WriteRequest` protobuf we read.
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<PromSample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PromSample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the Unix epoch.
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// Which remote-write series are stored, and as what.
+#[derive(Debug, Clone)]
+pub struct RemoteWriteConfig {
+    pub rules: Vec<MetricRule>,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        RemoteWriteConfig {
+            rules: parse_rules(DEFAULT_RULES).expect("default rules are valid"),
+        }
+    }
+}
+
+impl RemoteWriteConfig {
+    /// Reads the `;`-separated `REMOTE_WRITE_METRICS` rules, e.g.
+    /// `node_cpus{state="alloc"}=cpu.allocated; node_cpus{state="total"}=cpu.total`.
+    pub fn from_env() -> Result<Self> {
+        match env::var("REMOTE_WRITE_METRICS") {
+            Ok(value) => Ok(RemoteWriteConfig {
+                rules: parse_rules(&value)?,
+            }),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Decompresses (snappy block format) and decodes a remote-write body.
+pub fn decode_write_request(body: &[u8]) -> Result<WriteRequest, String> {
+    let decoded = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| format!("invalid snappy payload: {e}"))?;
+
+    WriteRequest::decode(decoded.as_slice()).map_err(|e| format!("invalid protobuf payload: {e}"))
+}
+
+/// Turns the series matching a rule into metric points. Series no rule matches are
+/// ignored, since Prometheus sends everything it scrapes. Stale markers (NaN) are
+/// dropped.
+pub fn map_series(config: &RemoteWriteConfig, request: &WriteRequest) -> Vec<MetricPoint> {
+    let mut points = Vec::new();
+
+    for (index, series) in request.timeseries.iter().enumerate() {
+        let label = |name: &str| {
+            series
+                .labels
+                .iter()
+                .find(|label| label.name == name)
+                .map(|label| label.value.as_str())
+        };
+        let Some(name) = label("__name__") else {
+            continue;
+        };
+        let Some(rule) = config.rules.iter().find(|rule| rule.matches(name, label)) else {
+            continue;
+        };
+        let gpu_type = label(GPU_TYPE_LABEL).map(String::from);
+
+        for sample in &series.samples {
+            if sample.value.is_nan() {
+                continue;
+            }
+            let Some(time) = time_from_millis(sample.timestamp) else {
+                continue;
+            };
+            points.push(MetricPoint {
+                index,
+                target: rule.target,
+                time,
+                gpu_type: gpu_type.clone(),
+                value: sample.value,
+            });
+        }
+    }
+
+    points
+}
+
+/// Prometheus remote-write receiver. Payload errors return 400 so Prometheus drops the
+/// batch instead of retrying it; database errors return 500 so it retries.
+pub async fn post_remote_write(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    State(config): State<Arc<RemoteWriteConfig>>,
+    Query(cluster): Query<ClusterQuery>,
+    body: Bytes,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let request =
+        decode_write_request(&body).map_err(|reason| (StatusCode::BAD_REQUEST, reason))?;
+
+    let (samples, quarantined, mut report) = assemble(map_series(&config, &request));
+
+    write_samples(cluster, samples, &quarantined, &mut report)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to insert remote-write samples: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(labels: &[(&str, &str)], samples: &[(f64, i64)]) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            samples: samples
+                .iter()
+                .map(|(value, timestamp)| PromSample {
+                    value: *value,
+                    timestamp: *timestamp,
+                })
+                .collect(),
+        }
+    }
+
+    fn encode(request: &WriteRequest) -> Vec<u8> {
+        snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap()
+    }
+
+    const T0: i64 = 1_711_497_600_000;
+
+    #[test]
+    fn test_decode_and_map_default_rules() {
+        let request = WriteRequest {
+            timeseries: vec![
+                series(
+                    &[("__name__", "slurm_cpus_alloc"), ("job", "slurm")],
+                    &[(75.0, T0), (80.0, T0 + 60_000)],
+                ),
+                series(
+                    &[("__name__", "slurm_cpus_total"), ("job", "slurm")],
+                    &[(100.0, T0), (100.0, T0 + 60_000)],
+                ),
+                series(&[("__name__", "up")], &[(1.0, T0)]),
+                series(
+                    &[("__name__", "slurm_gpus_alloc"), ("gpu_type", "a100")],
+                    &[(2.0, T0), (f64::NAN, T0 + 60_000)],
+                ),
+                series(
+                    &[("__name__", "slurm_gpus_total"), ("gpu_type", "a100")],
+                    &[(4.0, T0)],
+                ),
+            ],
+        };
+
+        let decoded = decode_write_request(&encode(&request)).unwrap();
+        assert_eq!(decoded.timeseries.len(), 5);
+        assert_eq!(decoded.timeseries[0], request.timeseries[0]);
+
+        let (samples, _, report) = assemble(map_series(&RemoteWriteConfig::default(), &decoded));
+
+        assert_eq!(report.rejected, 0);
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[2].2.gpu_type, "a100");
+        assert_eq!(samples[2].2.total, 4);
+    }
+
+    #[test]
+    fn test_label_matchers() {
+        let config = RemoteWriteConfig {
+            rules: parse_rules(
+                r#"node_cpus{state="alloc"}=cpu.allocated; node_cpus{state="total"}=cpu.total"#,
+            )
+            .unwrap(),
+        };
+        let request = WriteRequest {
+            timeseries: vec![
+                series(
+                    &[("__name__", "node_cpus"), ("state", "alloc")],
+                    &[(8.0, T0)],
+                ),
+                series(
+                    &[("__name__", "node_cpus"), ("state", "idle")],
+                    &[(24.0, T0)],
+                ),
+                series(
+                    &[("__name__", "node_cpus"), ("state", "total")],
+                    &[(32.0, T0)],
+                ),
+            ],
+        };
+
+        let (samples, _, report) = assemble(map_series(&config, &request));
+
+        assert_eq!(report.rejected, 0);
+        assert_eq!(samples[0].2.allocated, 8);
+        assert_eq!(samples[0].2.total, 32);
+    }
+
+    #[test]
+    fn test_rejects_uncompressed_payload() {
+        let request = WriteRequest {
+            timeseries: vec![series(&[("__name__", "up")], &[(1.0, T0)])],
+        };
+
+        assert!(decode_write_request(&request.encode_to_vec()).is_err());
+    }
+}
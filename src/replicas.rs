@@ -0,0 +1,350 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use axum::{extract::State, http::header, response::IntoResponse};
+use sqlx::postgres::PgPool;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::DatabaseConfig;
+use crate::database::{DbConfig, DbStatus};
+
+/// How often each replica's health and lag are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a replica check may take before the replica counts as down.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds the replica is behind the primary. A replica that has replayed everything
+/// it received counts as current, since the replay timestamp stops moving while the
+/// primary is idle.
+const LAG_QUERY: &str = "SELECT CASE \
+     WHEN NOT pg_is_in_recovery() OR pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+     ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0) \
+     END::float8";
+
+/// A read replica of the main database.
+#[derive(Debug)]
+pub struct Replica {
+    /// `host:port`, as used in logs and metric labels.
+    pub name: String,
+    pool: PgPool,
+    up: AtomicBool,
+    /// Bits of the last measured lag in seconds; NaN until the first successful check.
+    lag: AtomicU64,
+}
+
+impl Replica {
+    fn new(name: String, pool: PgPool) -> Self {
+        Replica {
+            name,
+            pool,
+            up: AtomicBool::new(false),
+            lag: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+
+    /// Whether the replica is reachable and close enough behind the primary to serve
+    /// reads.
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    /// Lag in seconds at the last successful check.
+    pub fn lag_seconds(&self) -> Option<f64> {
+        Some(f64::from_bits(self.lag.load(Ordering::Relaxed))).filter(|lag| !lag.is_nan())
+    }
+
+    /// Records the outcome of a check, returning whether the replica was up before.
+    fn record(&self, lag: Option<f64>, max_lag: Duration) -> bool {
+        if let Some(lag) = lag {
+            self.lag.store(lag.to_bits(), Ordering::Relaxed);
+        }
+        let up = lag.is_some_and(|lag| lag <= max_lag.as_secs_f64());
+        self.up.swap(up, Ordering::Relaxed)
+    }
+
+    async fn check(&self) -> Result<f64> {
+        tokio::time::timeout(
+            CHECK_TIMEOUT,
+            sqlx::query_scalar::<_, f64>(LAG_QUERY).fetch_one(&self.pool),
+        )
+        .await
+        .map_err(|_| anyhow!("timed out after {:?}", CHECK_TIMEOUT))?
+        .map_err(Into::into)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplicaSet {
+    replicas: Vec<Replica>,
+    max_lag: Duration,
+    next: AtomicUsize,
+}
+
+/// The read replicas of the main database, shared between the handlers and the
+/// monitor. Empty when no replicas are configured, in which case every read goes to
+/// the primary.
+#[derive(Debug, Clone, Default)]
+pub struct Replicas(Arc<ReplicaSet>);
+
+/// Parses replica entries such as `replica-1` or `replica-2:6432` into hosts and
+/// ports. Entries without a port use `default_port`, the primary's.
+pub fn parse_replica_hosts(entries: &[String], default_port: u16) -> Result<Vec<(String, u16)>> {
+    let mut hosts: Vec<(String, u16)> = Vec::new();
+
+    for entry in entries.iter().map(|entry| entry.trim()) {
+        let (host, port) = match entry.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| anyhow!("invalid port in replica `{entry}`"))?,
+            ),
+            None => (entry, default_port),
+        };
+        if host.is_empty() {
+            bail!("replica `{entry}` has no host");
+        }
+        if hosts.iter().any(|(h, p)| h == host && *p == port) {
+            bail!("replica `{entry}` is listed more than once");
+        }
+
+        hosts.push((host.to_string(), port));
+    }
+
+    Ok(hosts)
+}
+
+impl Replicas {
+    /// Builds lazy pools for the configured replicas. They use the primary's database,
+    /// credentials, TLS and pool settings; only the host and port differ.
+    pub fn from_config(primary: &DbConfig, config: &DatabaseConfig) -> Result<Self> {
+        let replicas = parse_replica_hosts(&config.replicas, primary.port)?
+            .into_iter()
+            .map(|(host, port)| {
+                let config = DbConfig {
+                    host: Some(host.clone()),
+                    port,
+                    socket_dir: None,
+                    ..primary.clone()
+                };
+                Replica::new(format!("{host}:{port}"), config.lazy_pool())
+            })
+            .collect();
+
+        Ok(Self::new(
+            replicas,
+            Duration::from_secs(config.replica_max_lag_seconds),
+        ))
+    }
+
+    fn new(replicas: Vec<Replica>, max_lag: Duration) -> Self {
+        Replicas(Arc::new(ReplicaSet {
+            replicas,
+            max_lag,
+            next: AtomicUsize::new(0),
+        }))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.replicas.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Replica> {
+        self.0.replicas.iter()
+    }
+
+    /// The pool for a read: the replicas in turn, skipping those that are down or
+    /// lagging, or `primary` when none is usable.
+    pub fn read_pool(&self, primary: &PgPool) -> PgPool {
+        let replicas = &self.0.replicas;
+        if replicas.is_empty() {
+            return primary.clone();
+        }
+
+        let start = self.0.next.fetch_add(1, Ordering::Relaxed);
+        (0..replicas.len())
+            .map(|offset| &replicas[(start + offset) % replicas.len()])
+            .find(|replica| replica.is_up())
+            .map_or_else(|| primary.clone(), |replica| replica.pool.clone())
+    }
+
+    /// Checks every replica once, logging the ones that leave or rejoin the rotation.
+    pub async fn check(&self) {
+        let max_lag = self.0.max_lag;
+
+        for replica in self.iter() {
+            match replica.check().await {
+                Ok(lag) => {
+                    let was_up = replica.record(Some(lag), max_lag);
+                    match (was_up, replica.is_up()) {
+                        (false, true) => {
+                            tracing::info!("Replica {} is serving reads", replica.name)
+                        }
+                        (true, false) => tracing::warn!(
+                            "Replica {} is {:.1}s behind, sending its reads to the primary",
+                            replica.name,
+                            lag
+                        ),
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    if replica.record(None, max_lag) {
+                        tracing::error!(
+                            "Error: replica {} is unreachable, sending its reads to the primary: {:#}",
+                            replica.name,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks the replicas every few seconds until `shutdown` is cancelled.
+    pub async fn monitor(self, shutdown: CancellationToken) {
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.cancelled() => return,
+            }
+            self.check().await;
+        }
+    }
+
+    /// Closes the replica pools, waiting for connections in use to be returned.
+    pub async fn close(&self) {
+        for replica in self.iter() {
+            replica.pool.close().await;
+        }
+    }
+}
+
+/// Database health in the Prometheus text format.
+fn render_metrics(status: &DbStatus, replicas: &Replicas) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP elmo_db_up Whether the primary database is reachable.\n");
+    out.push_str("# TYPE elmo_db_up gauge\n");
+    let _ = writeln!(out, "elmo_db_up {}", u8::from(status.is_up()));
+
+    if replicas.is_empty() {
+        return out;
+    }
+
+    out.push_str("# HELP elmo_db_replica_up Whether the read replica is serving reads.\n");
+    out.push_str("# TYPE elmo_db_replica_up gauge\n");
+    for replica in replicas.iter() {
+        let _ = writeln!(
+            out,
+            "elmo_db_replica_up{{replica=\"{}\"}} {}",
+            replica.name,
+            u8::from(replica.is_up())
+        );
+    }
+
+    out.push_str(
+        "# HELP elmo_db_replica_lag_seconds How far the read replica is behind the primary.\n",
+    );
+    out.push_str("# TYPE elmo_db_replica_lag_seconds gauge\n");
+    for replica in replicas.iter() {
+        if let Some(lag) = replica.lag_seconds() {
+            let _ = writeln!(
+                out,
+                "elmo_db_replica_lag_seconds{{replica=\"{}\"}} {}",
+                replica.name, lag
+            );
+        }
+    }
+
+    out
+}
+
+pub async fn get_metrics(
+    State(status): State<DbStatus>,
+    State(replicas): State<Replicas>,
+) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&status, &replicas),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replicas(names: &[&str]) -> Replicas {
+        let replicas = names
+            .iter()
+            .map(|name| {
+                let pool = PgPool::connect_lazy(&format!("postgres://{name}/elmo")).unwrap();
+                Replica::new(name.to_string(), pool)
+            })
+            .collect();
+        Replicas::new(replicas, Duration::from_secs(30))
+    }
+
+    fn host(pool: &PgPool) -> String {
+        pool.connect_options().get_host().to_string()
+    }
+
+    #[test]
+    fn test_parse_replica_hosts() {
+        let parse = |entries: &[&str]| {
+            let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+            parse_replica_hosts(&entries, 5432)
+        };
+
+        assert_eq!(
+            parse(&["replica-1", " replica-2:6432"]).unwrap(),
+            vec![
+                ("replica-1".to_string(), 5432),
+                ("replica-2".to_string(), 6432)
+            ]
+        );
+        assert!(parse(&[]).unwrap().is_empty());
+        assert!(parse(&["replica:x"]).is_err());
+        assert!(parse(&[":6432"]).is_err());
+        assert!(parse(&["replica", "replica:5432"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reads_fail_over_to_primary() {
+        let primary = PgPool::connect_lazy("postgres://primary/elmo").unwrap();
+        let replicas = replicas(&["a", "b"]);
+        let [a, b] = [&replicas.0.replicas[0], &replicas.0.replicas[1]];
+        let max_lag = Duration::from_secs(30);
+
+        // Replicas only serve reads once a check has passed.
+        assert_eq!(host(&replicas.read_pool(&primary)), "primary");
+
+        a.record(Some(0.5), max_lag);
+        b.record(Some(1.0), max_lag);
+        let mut hosts: Vec<_> = (0..4)
+            .map(|_| host(&replicas.read_pool(&primary)))
+            .collect();
+        hosts.sort();
+        assert_eq!(hosts, vec!["a", "a", "b", "b"]);
+
+        // A lagging replica keeps its last lag but leaves the rotation.
+        assert!(b.record(Some(45.0), max_lag));
+        assert_eq!(b.lag_seconds(), Some(45.0));
+        assert!((0..4).all(|_| host(&replicas.read_pool(&primary)) == "a"));
+
+        a.record(None, max_lag);
+        assert_eq!(host(&replicas.read_pool(&primary)), "primary");
+        assert_eq!(host(&Replicas::default().read_pool(&primary)), "primary");
+
+        let metrics = render_metrics(&DbStatus::new(true), &replicas);
+        assert!(metrics.contains("elmo_db_up 1\n"));
+        assert!(metrics.contains("elmo_db_replica_up{replica=\"a\"} 0\n"));
+        assert!(metrics.contains("elmo_db_replica_lag_seconds{replica=\"a\"} 0.5\n"));
+        assert!(metrics.contains("elmo_db_replica_lag_seconds{replica=\"b\"} 45\n"));
+    }
+}
@@ -0,0 +1,525 @@
+use chrono::{Duration, NaiveDateTime};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use sqlx::postgres::PgPool;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+use crate::clusters::{Cluster, ClusterRegistry};
+use crate::routes::{Bucket, Resource};
+use crate::{partitions, quality, rollup};
+
+/// How often expired data is removed when `RETENTION_INTERVAL_SECONDS` is not set.
+const DEFAULT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Rows removed per DELETE, so no statement holds its locks for long.
+const DELETE_BATCH_SIZE: i64 = 10_000;
+
+/// A level of detail that can expire on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Tier {
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl Tier {
+    pub const ALL: [Tier; 3] = [Tier::Raw, Tier::Hourly, Tier::Daily];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Tier::Raw => "raw",
+            Tier::Hourly => "hourly",
+            Tier::Daily => "daily",
+        }
+    }
+
+    /// The rollup bucket this tier keeps, `None` for raw samples.
+    pub fn bucket(self) -> Option<Bucket> {
+        match self {
+            Tier::Raw => None,
+            Tier::Hourly => Some(Bucket::Hour),
+            Tier::Daily => Some(Bucket::Day),
+        }
+    }
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Tier {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "raw" => Ok(Tier::Raw),
+            "hourly" => Ok(Tier::Hourly),
+            "daily" => Ok(Tier::Daily),
+            _ => Err(format!(
+                "unknown tier `{value}`, expected raw, hourly or daily"
+            )),
+        }
+    }
+}
+
+/// How long each resource and tier is kept. Anything not listed is kept forever.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    keep: BTreeMap<(Resource, Tier), Duration>,
+}
+
+/// Parses an age such as `90d`, `12w` or `2y` (365 days each), or `forever`.
+fn parse_age(value: &str) -> Result<Option<Duration>> {
+    if value == "forever" {
+        return Ok(None);
+    }
+
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| anyhow!("age `{value}` needs a unit: d, w or y"))?;
+    let (count, unit) = value.split_at(split);
+    let count: i64 = count
+        .parse()
+        .with_context(|| format!("invalid age `{value}`"))?;
+    if count <= 0 {
+        bail!("age `{value}` must be positive");
+    }
+
+    let days = match unit {
+        "d" => count,
+        "w" => count * 7,
+        "y" => count * 365,
+        _ => bail!("unknown unit in age `{value}`, expected d, w or y"),
+    };
+
+    Ok(Some(Duration::days(days)))
+}
+
+impl RetentionPolicy {
+    /// Parses a `;`-separated list such as `raw=90d; hourly=2y; gpu.raw=180d`. Entries
+    /// without a resource apply to both; entries naming one take precedence.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut general = BTreeMap::new();
+        let mut specific = BTreeMap::new();
+
+        for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((key, age)) = entry.split_once('=') else {
+                bail!("retention `{entry}` must look like cpu.raw=90d");
+            };
+            let age = parse_age(age.trim())?;
+
+            match key.trim().split_once('.') {
+                Some((resource, tier)) => {
+                    let resource = Resource::from_table(resource)
+                        .ok_or_else(|| anyhow!("unknown resource `{resource}`"))?;
+                    let tier: Tier = tier.parse().map_err(anyhow::Error::msg)?;
+                    specific.insert((resource, tier), age);
+                }
+                None => {
+                    let tier: Tier = key.trim().parse().map_err(anyhow::Error::msg)?;
+                    for resource in [Resource::Cpu, Resource::Gpu] {
+                        general.insert((resource, tier), age);
+                    }
+                }
+            }
+        }
+
+        general.extend(specific);
+        let policy = RetentionPolicy {
+            keep: general
+                .into_iter()
+                .filter_map(|(key, age)| Some((key, age?)))
+                .collect(),
+        };
+        policy.validate()?;
+
+        Ok(policy)
+    }
+
+    /// Reads `RETENTION`. Without it nothing is ever deleted.
+    pub fn from_env() -> Result<Self> {
+        match env::var("RETENTION") {
+            Ok(value) => Self::parse(&value).context("invalid RETENTION"),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keep.is_empty()
+    }
+
+    pub fn keep(&self, resource: Resource, tier: Tier) -> Option<Duration> {
+        self.keep.get(&(resource, tier)).copied()
+    }
+
+    /// The hourly and daily endpoints read rollups for periods without raw samples, so
+    /// a coarser tier must outlive the finer ones.
+    fn validate(&self) -> Result<()> {
+        for resource in [Resource::Cpu, Resource::Gpu] {
+            for pair in Tier::ALL.windows(2) {
+                let (finer, coarser) = (pair[0], pair[1]);
+                if let (Some(finer_age), coarser_age) =
+                    (self.keep(resource, finer), self.keep(resource, coarser))
+                {
+                    if coarser_age.is_some_and(|age| age < finer_age) {
+                        bail!(
+                            "{}.{coarser} must be kept at least as long as {}.{finer}",
+                            resource.table(),
+                            resource.table()
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Data older than this is removed. Cutoffs fall on midnight, so whole hourly and daily
+/// buckets expire at once and never leave a rollup that only part of its raw samples
+/// could rebuild.
+pub fn cutoff(keep: Duration, now: NaiveDateTime) -> NaiveDateTime {
+    Bucket::Day.truncate(now - keep)
+}
+
+/// A table expired by one tier, and which of its rows expire.
+struct Target {
+    table: &'static str,
+    /// Columns identifying a row, for batched deletes.
+    keys: &'static str,
+    /// Rows matching this are expired; `$1` is the cutoff.
+    expired: String,
+    /// Raw samples, whose rollups outlive them.
+    raw: bool,
+}
+
+fn targets(resource: Resource, tier: Tier) -> Vec<Target> {
+    let table = resource.table();
+
+    match tier.bucket() {
+        None => vec![Target {
+            table,
+            keys: match resource {
+                Resource::Cpu => "time",
+                Resource::Gpu => "time, gpu_type",
+            },
+            expired: "s.time < $1".to_string(),
+            raw: true,
+        }],
+        Some(bucket) => {
+            let unit = bucket.unit();
+            let mut targets = vec![Target {
+                table: "rollup",
+                keys: "resource, bucket, time",
+                expired: format!("s.resource = '{table}' AND s.bucket = '{unit}' AND s.time < $1"),
+                raw: false,
+            }];
+            if resource == Resource::Gpu {
+                targets.push(Target {
+                    table: "gpu_type_rollup",
+                    keys: "bucket, time, gpu_type",
+                    expired: format!("s.bucket = '{unit}' AND s.time < $1"),
+                    raw: false,
+                });
+            }
+            targets
+        }
+    }
+}
+
+/// Counts the rows of `target` older than `cutoff`.
+async fn count_expired(
+    pool: &PgPool,
+    schema: &str,
+    target: &Target,
+    cutoff: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar(&format!(
+        "SELECT COUNT(*) FROM {schema}.{} s WHERE {}",
+        target.table, target.expired
+    ))
+    .bind(cutoff)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count as u64)
+}
+
+/// Deletes the expired rows of `target` in batches of `DELETE_BATCH_SIZE`.
+async fn delete_expired(
+    pool: &PgPool,
+    schema: &str,
+    target: &Target,
+    cutoff: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    let query = format!(
+        "DELETE FROM {schema}.{table} WHERE ({keys}) IN (\
+         SELECT {keys} FROM {schema}.{table} s WHERE {expired} LIMIT $2)",
+        table = target.table,
+        keys = target.keys,
+        expired = target.expired,
+    );
+    let mut deleted = 0;
+
+    loop {
+        let mut tx = pool.begin().await?;
+        let batch = sqlx::query(&query)
+            .bind(cutoff)
+            .bind(DELETE_BATCH_SIZE)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if target.raw {
+            // The trigger on the sample tables marks the buckets of deleted samples
+            // dirty. Those buckets are never recomputed, and while marked they would be
+            // read from the emptied raw table, so the marks go with the samples.
+            sqlx::query(&format!(
+                "DELETE FROM {schema}.rollup_dirty WHERE resource = '{table}' AND time < $1",
+                table = target.table
+            ))
+            .bind(cutoff)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        deleted += batch;
+        if batch < DELETE_BATCH_SIZE as u64 {
+            return Ok(deleted);
+        }
+    }
+}
+
+/// What one pass removed, or with a dry run would remove, for a resource and tier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub cluster: String,
+    pub resource: Resource,
+    pub tier: Tier,
+    pub cutoff: NaiveDateTime,
+    pub rows: u64,
+}
+
+/// Removes everything in `cluster` that `policy` has expired. Dirty buckets are
+/// refreshed before raw samples are deleted, so the rollups cover them; buckets past the
+/// raw cutoff are never recomputed afterwards. Expired monthly partitions are dropped
+/// whole.
+///
+/// With `dry_run`, nothing is changed and the reports count what would be removed.
+pub async fn enforce(
+    cluster: &Cluster,
+    policy: &RetentionPolicy,
+    now: NaiveDateTime,
+    dry_run: bool,
+) -> Result<Vec<RetentionReport>, sqlx::Error> {
+    let mut reports = Vec::new();
+
+    let expires_raw = [Resource::Cpu, Resource::Gpu]
+        .into_iter()
+        .any(|resource| policy.keep(resource, Tier::Raw).is_some());
+    if expires_raw && !dry_run {
+        rollup::refresh(&cluster.pool, &cluster.schema, policy, now).await?;
+    }
+
+    for resource in [Resource::Cpu, Resource::Gpu] {
+        for tier in Tier::ALL {
+            let Some(keep) = policy.keep(resource, tier) else {
+                continue;
+            };
+            let cutoff = cutoff(keep, now);
+
+            let mut rows = 0;
+            if tier == Tier::Raw && !dry_run {
+                // Whole expired months go at once; the batched deletes take the rest.
+                rows += partitions::drop_expired(&cluster.pool, &cluster.schema, resource, cutoff)
+                    .await?;
+            }
+            for target in targets(resource, tier) {
+                rows += if dry_run {
+                    count_expired(&cluster.pool, &cluster.schema, &target, cutoff).await?
+                } else {
+                    delete_expired(&cluster.pool, &cluster.schema, &target, cutoff).await?
+                };
+            }
+
+            reports.push(RetentionReport {
+                cluster: cluster.name.clone(),
+                resource,
+                tier,
+                cutoff,
+                rows,
+            });
+        }
+    }
+
+    Ok(reports)
+}
+
+/// Reads `RETENTION_INTERVAL_SECONDS`, how often `run` removes expired data.
+pub fn interval_from_env() -> Result<std::time::Duration> {
+    match env::var("RETENTION_INTERVAL_SECONDS") {
+        Ok(value) => {
+            let seconds: u64 = value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid RETENTION_INTERVAL_SECONDS `{value}`"))?;
+            Ok(std::time::Duration::from_secs(seconds.max(1)))
+        }
+        Err(_) => Ok(DEFAULT_INTERVAL),
+    }
+}
+
+/// Enforces `policy` on every cluster each `interval`, until `shutdown` is cancelled.
+pub async fn run(
+    clusters: Arc<ClusterRegistry>,
+    policy: RetentionPolicy,
+    interval: std::time::Duration,
+    shutdown: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        for cluster in clusters.iter() {
+            match enforce(cluster, &policy, quality::now(), false).await {
+                Ok(reports) => {
+                    for report in reports.iter().filter(|report| report.rows > 0) {
+                        tracing::info!(
+                            "Removed {} {} {} rows before {} from {}",
+                            report.rows,
+                            report.tier,
+                            report.resource.table(),
+                            report.cutoff,
+                            report.cluster
+                        );
+                    }
+                }
+                Err(e) => tracing::error!(
+                    "Error: failed to enforce retention for {}: {:?}",
+                    cluster.name,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct RetentionArgs {
+    /// Only report what would be removed
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Cluster to clean up; defaults to every cluster
+    #[arg(long)]
+    pub cluster: Option<String>,
+}
+
+/// Runs `elmo-api retention` once and prints what was, or would be, removed.
+pub async fn run_once(args: RetentionArgs, clusters: Arc<ClusterRegistry>) -> Result<()> {
+    let policy = RetentionPolicy::from_env()?;
+    if policy.is_empty() {
+        println!("RETENTION is not set, nothing expires");
+        return Ok(());
+    }
+
+    let selected: Vec<&Cluster> = match &args.cluster {
+        Some(name) => vec![clusters
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown cluster `{name}`"))?],
+        None => clusters.iter().collect(),
+    };
+
+    let verb = if args.dry_run {
+        "would remove"
+    } else {
+        "removed"
+    };
+    for cluster in selected {
+        for report in enforce(cluster, &policy, quality::now(), args.dry_run).await? {
+            println!(
+                "{}  {}.{:<7} before {}  {} {} rows",
+                report.cluster,
+                report.resource.table(),
+                report.tier.as_str(),
+                report.cutoff,
+                verb,
+                report.rows
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy =
+            RetentionPolicy::parse("raw=90d; hourly=2y; gpu.raw=26w; gpu.hourly=forever").unwrap();
+
+        assert_eq!(
+            policy.keep(Resource::Cpu, Tier::Raw),
+            Some(Duration::days(90))
+        );
+        assert_eq!(
+            policy.keep(Resource::Cpu, Tier::Hourly),
+            Some(Duration::days(730))
+        );
+        assert_eq!(policy.keep(Resource::Cpu, Tier::Daily), None);
+        assert_eq!(
+            policy.keep(Resource::Gpu, Tier::Raw),
+            Some(Duration::days(182))
+        );
+        assert_eq!(policy.keep(Resource::Gpu, Tier::Hourly), None);
+
+        assert!(RetentionPolicy::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_policy_errors() {
+        let error = |value| RetentionPolicy::parse(value).unwrap_err().to_string();
+
+        assert_eq!(error("raw"), "retention `raw` must look like cpu.raw=90d");
+        assert_eq!(error("raw=90"), "age `90` needs a unit: d, w or y");
+        assert_eq!(
+            error("raw=3m"),
+            "unknown unit in age `3m`, expected d, w or y"
+        );
+        assert_eq!(error("mem.raw=1d"), "unknown resource `mem`");
+        assert_eq!(
+            error("weekly=1y"),
+            "unknown tier `weekly`, expected raw, hourly or daily"
+        );
+        assert_eq!(
+            error("raw=1y; cpu.hourly=90d"),
+            "cpu.hourly must be kept at least as long as cpu.raw"
+        );
+    }
+
+    #[test]
+    fn test_cutoff_falls_on_midnight() {
+        let now = "2024-03-27T12:30:00".parse().unwrap();
+
+        assert_eq!(
+            cutoff(Duration::days(90), now),
+            "2023-12-28T00:00:00".parse().unwrap()
+        );
+    }
+}
@@ -0,0 +1,566 @@
+use chrono::NaiveDateTime;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::{Postgres, QueryBuilder};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+use crate::clusters::ClusterRegistry;
+use crate::quality;
+use crate::retention::{self, RetentionPolicy, Tier};
+use crate::routes::{push_filters, Bucket, GpuTypeUtilization, Resource, TimeRange, Utilization};
+
+/// The buckets kept in the rollup tables.
+pub const BUCKETS: [Bucket; 2] = [Bucket::Hour, Bucket::Day];
+
+/// How often dirty buckets are recomputed when `ROLLUP_INTERVAL_SECONDS` is not set.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Dirty buckets recomputed per transaction.
+const REFRESH_BATCH_SIZE: i64 = 500;
+
+/// The bucket starts `[from, to)` a query may read from the rollup tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RollupWindow {
+    /// `None` when the query has no time range.
+    pub from: Option<NaiveDateTime>,
+    pub to: NaiveDateTime,
+}
+
+/// The buckets that can be served from the rollups: closed ones that lie wholly inside
+/// the time range. The open bucket is still filling up, and a bucket the range cuts
+/// through only averages some of its samples, so both are read from the raw table.
+pub fn window(bucket: Bucket, time_range: &TimeRange, now: NaiveDateTime) -> Option<RollupWindow> {
+    let open = bucket.truncate(now);
+
+    let window = match time_range.bounds() {
+        (Some(start), Some(end)) => {
+            let mut from = bucket.truncate(start);
+            if from < start {
+                from += bucket.width();
+            }
+            // The range includes `end`, so a bucket starting at `end` is partial too.
+            RollupWindow {
+                from: Some(from),
+                to: bucket.truncate(end).min(open),
+            }
+        }
+        _ => RollupWindow {
+            from: None,
+            to: open,
+        },
+    };
+
+    match window.from {
+        Some(from) if from >= window.to => None,
+        _ => Some(window),
+    }
+}
+
+/// Pushes a `buckets` CTE with the sums and sample count of every bucket in the range:
+/// rollup rows inside the window, unless the bucket is dirty, and the raw samples for
+/// everything else. With `per_type`, GPU buckets are kept apart by GPU type.
+#[allow(clippy::too_many_arguments)]
+fn push_buckets(
+    query: &mut QueryBuilder<'_, Postgres>,
+    schema: &str,
+    resource: Resource,
+    bucket: Bucket,
+    time_range: &TimeRange,
+    gpu_type: Option<&str>,
+    per_type: bool,
+    now: NaiveDateTime,
+) {
+    let unit = bucket.unit();
+    let table = resource.table();
+    let window = window(bucket, time_range, now);
+    let dirty = format!(
+        "SELECT time FROM {schema}.rollup_dirty WHERE resource = '{table}' AND bucket = '{unit}'"
+    );
+    let gpu_type = gpu_type.filter(|_| resource == Resource::Gpu);
+    let (keys, type_column) = if per_type {
+        ("time, gpu_type", ", gpu_type")
+    } else {
+        ("time", "")
+    };
+    // GPU samples are summed per time into the cluster-wide series first, unless a
+    // single GPU type is selected.
+    let summed = resource == Resource::Gpu && !per_type && gpu_type.is_none();
+
+    query.push(format!("WITH per_sample AS (SELECT {keys}, "));
+    if summed {
+        query.push("SUM(allocated) AS allocated, SUM(total) AS total");
+    } else {
+        query.push("allocated, total");
+    }
+    query.push(format!(" FROM {schema}.{table}"));
+
+    let separator = push_filters(query, time_range, gpu_type);
+    if let Some(window) = window {
+        query.push(separator).push("(");
+        if let Some(from) = window.from {
+            query.push("time < ").push_bind(from).push(" OR ");
+        }
+        query
+            .push("time >= ")
+            .push_bind(window.to)
+            .push(format!(" OR date_trunc('{unit}', time) IN ({dirty}))"));
+    }
+    if summed {
+        query.push(" GROUP BY time");
+    }
+
+    query.push(format!(
+        r#"
+        ),
+        buckets AS (
+            SELECT
+                date_trunc('{unit}', time) AS time{type_column},
+                SUM(allocated)::bigint AS allocated_sum,
+                SUM(total)::bigint AS total_sum,
+                COUNT(*) AS samples
+            FROM per_sample
+            GROUP BY {groups}"#,
+        groups = if per_type { "1, 2" } else { "1" }
+    ));
+
+    if let Some(window) = window {
+        query.push(format!(
+            r#"
+            UNION ALL
+            SELECT
+                {keys},
+                allocated_sum,
+                total_sum,
+                samples
+            FROM"#
+        ));
+        if per_type || gpu_type.is_some() {
+            query.push(format!(" {schema}.gpu_type_rollup WHERE bucket = '{unit}'"));
+        } else {
+            query.push(format!(
+                " {schema}.rollup WHERE resource = '{table}' AND bucket = '{unit}'"
+            ));
+        }
+        if let Some(gpu_type) = gpu_type {
+            query
+                .push(" AND gpu_type = ")
+                .push_bind(gpu_type.to_string());
+        }
+        if let Some(from) = window.from {
+            query.push(" AND time >= ").push_bind(from);
+        }
+        query
+            .push(" AND time < ")
+            .push_bind(window.to)
+            .push(format!(" AND time NOT IN ({dirty})"));
+    }
+
+    query.push(format!(
+        r#"
+        )
+        SELECT
+            {keys},
+            CAST(ROUND(allocated_sum::numeric / samples) AS INTEGER) as allocated,
+            CAST(ROUND(total_sum::numeric / samples) AS INTEGER) as total
+        FROM buckets
+        ORDER BY {keys}
+        "#
+    ));
+}
+
+/// Cluster-wide utilization averaged into `bucket`s, read from the rollups where they
+/// are up to date and from the raw samples otherwise.
+pub async fn fetch_bucketed(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+    bucket: Bucket,
+    time_range: &TimeRange,
+    gpu_type: Option<&str>,
+    now: NaiveDateTime,
+) -> Result<Vec<Utilization>, sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new("");
+    push_buckets(
+        &mut query, schema, resource, bucket, time_range, gpu_type, false, now,
+    );
+
+    query.build_query_as::<Utilization>().fetch_all(pool).await
+}
+
+/// Like `fetch_bucketed` for GPUs, but keeps one row per GPU type.
+pub async fn fetch_gpu_type_bucketed(
+    pool: &PgPool,
+    schema: &str,
+    bucket: Bucket,
+    time_range: &TimeRange,
+    gpu_type: Option<&str>,
+    now: NaiveDateTime,
+) -> Result<Vec<GpuTypeUtilization>, sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new("");
+    push_buckets(
+        &mut query,
+        schema,
+        Resource::Gpu,
+        bucket,
+        time_range,
+        gpu_type,
+        true,
+        now,
+    );
+
+    query
+        .build_query_as::<GpuTypeUtilization>()
+        .fetch_all(pool)
+        .await
+}
+
+/// The buckets among `times` that may be rebuilt from the raw samples. Raw samples
+/// before the retention cutoff are, or are about to be, deleted, so the rollups are all
+/// that is left of those buckets and a recompute would wipe them out.
+fn recomputable(times: &[NaiveDateTime], raw_cutoff: Option<NaiveDateTime>) -> Vec<NaiveDateTime> {
+    times
+        .iter()
+        .filter(|time| raw_cutoff.is_none_or(|cutoff| **time >= cutoff))
+        .copied()
+        .collect()
+}
+
+/// Rebuilds the rollup rows of the given buckets from the raw samples. Buckets without
+/// samples are removed.
+async fn recompute(
+    conn: &mut PgConnection,
+    schema: &str,
+    resource: Resource,
+    bucket: Bucket,
+    times: &[NaiveDateTime],
+) -> Result<(), sqlx::Error> {
+    let (Some(from), Some(last)) = (times.iter().min(), times.iter().max()) else {
+        return Ok(());
+    };
+    let to = *last + bucket.width();
+    let unit = bucket.unit();
+    let table = resource.table();
+
+    sqlx::query(&format!(
+        "DELETE FROM {schema}.rollup WHERE resource = '{table}' AND bucket = '{unit}' \
+         AND time = ANY($1)"
+    ))
+    .bind(times)
+    .execute(&mut *conn)
+    .await?;
+
+    let per_sample = match resource {
+        Resource::Cpu => format!(
+            "SELECT time, allocated, total FROM {schema}.cpu WHERE time >= $2 AND time < $3"
+        ),
+        Resource::Gpu => format!(
+            "SELECT time, SUM(allocated) AS allocated, SUM(total) AS total FROM {schema}.gpu \
+             WHERE time >= $2 AND time < $3 GROUP BY time"
+        ),
+    };
+    sqlx::query(&format!(
+        r#"
+        INSERT INTO {schema}.rollup (resource, bucket, time, allocated_sum, total_sum, samples)
+        SELECT
+            '{table}',
+            '{unit}',
+            date_trunc('{unit}', time),
+            SUM(allocated)::bigint,
+            SUM(total)::bigint,
+            COUNT(*)
+        FROM ({per_sample}) per_sample
+        WHERE date_trunc('{unit}', time) = ANY($1)
+        GROUP BY 3
+        "#
+    ))
+    .bind(times)
+    .bind(from)
+    .bind(to)
+    .execute(&mut *conn)
+    .await?;
+
+    if resource == Resource::Gpu {
+        sqlx::query(&format!(
+            "DELETE FROM {schema}.gpu_type_rollup WHERE bucket = '{unit}' AND time = ANY($1)"
+        ))
+        .bind(times)
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO {schema}.gpu_type_rollup
+                (bucket, time, gpu_type, allocated_sum, total_sum, samples)
+            SELECT
+                '{unit}',
+                date_trunc('{unit}', time),
+                gpu_type,
+                SUM(allocated),
+                SUM(total),
+                COUNT(*)
+            FROM {schema}.gpu
+            WHERE time >= $2 AND time < $3 AND date_trunc('{unit}', time) = ANY($1)
+            GROUP BY 2, 3
+            "#
+        ))
+        .bind(times)
+        .bind(from)
+        .bind(to)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Recomputes up to `REFRESH_BATCH_SIZE` dirty buckets in one transaction and returns
+/// how many it took. Buckets another refresh is working on are skipped, so several API
+/// instances can refresh the same schema. Dirty buckets whose raw samples `policy` has
+/// expired are dropped without touching their rollups.
+async fn refresh_batch(
+    pool: &PgPool,
+    schema: &str,
+    policy: &RetentionPolicy,
+    now: NaiveDateTime,
+) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // The dirty rows are removed before the buckets are recomputed: a sample written
+    // meanwhile either is seen by the recompute or marks its bucket dirty again.
+    let claimed: Vec<(String, String, NaiveDateTime)> = sqlx::query_as(&format!(
+        r#"
+        DELETE FROM {schema}.rollup_dirty
+        WHERE (resource, bucket, time) IN (
+            SELECT resource, bucket, time
+            FROM {schema}.rollup_dirty
+            ORDER BY time
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING resource, bucket, time
+        "#
+    ))
+    .bind(REFRESH_BATCH_SIZE)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut groups: BTreeMap<(Resource, Bucket), Vec<NaiveDateTime>> = BTreeMap::new();
+    for (resource, bucket, time) in &claimed {
+        match (Resource::from_table(resource), Bucket::from_unit(bucket)) {
+            (Some(resource), Some(bucket)) => {
+                groups.entry((resource, bucket)).or_default().push(*time)
+            }
+            _ => tracing::warn!(
+                "Dropping dirty rollup bucket with resource {} and bucket {}",
+                resource,
+                bucket
+            ),
+        }
+    }
+
+    for ((resource, bucket), times) in &groups {
+        let raw_cutoff = policy
+            .keep(*resource, Tier::Raw)
+            .map(|keep| retention::cutoff(keep, now));
+        let times = recomputable(times, raw_cutoff);
+        recompute(&mut tx, schema, *resource, *bucket, &times).await?;
+    }
+    tx.commit().await?;
+
+    Ok(claimed.len())
+}
+
+/// Recomputes every dirty bucket in `schema`, returning how many there were.
+pub async fn refresh(
+    pool: &PgPool,
+    schema: &str,
+    policy: &RetentionPolicy,
+    now: NaiveDateTime,
+) -> Result<usize, sqlx::Error> {
+    let mut refreshed = 0;
+
+    loop {
+        let batch = refresh_batch(pool, schema, policy, now).await?;
+        refreshed += batch;
+        if batch < REFRESH_BATCH_SIZE as usize {
+            return Ok(refreshed);
+        }
+    }
+}
+
+/// Reads `ROLLUP_INTERVAL_SECONDS`, how often `run` refreshes the rollups.
+pub fn interval_from_env() -> Result<Duration> {
+    match env::var("ROLLUP_INTERVAL_SECONDS") {
+        Ok(value) => {
+            let seconds: u64 = value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid ROLLUP_INTERVAL_SECONDS `{value}`"))?;
+            Ok(Duration::from_secs(seconds.max(1)))
+        }
+        Err(_) => Ok(DEFAULT_REFRESH_INTERVAL),
+    }
+}
+
+/// Refreshes the rollups of every cluster each `interval`, until `shutdown` is
+/// cancelled. Failures are logged and retried on the next tick.
+pub async fn run(
+    clusters: Arc<ClusterRegistry>,
+    policy: RetentionPolicy,
+    interval: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        for cluster in clusters.iter() {
+            match refresh(&cluster.pool, &cluster.schema, &policy, quality::now()).await {
+                Ok(0) => {}
+                Ok(refreshed) => tracing::debug!(
+                    "Refreshed {} rollup buckets for {}",
+                    refreshed,
+                    cluster.name
+                ),
+                Err(e) => tracing::error!(
+                    "Error: failed to refresh rollups for {}: {:?}",
+                    cluster.name,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveDateTime {
+        value.parse().unwrap()
+    }
+
+    fn range(start: &str, end: &str) -> TimeRange {
+        TimeRange {
+            start: Some(time(start)),
+            end: Some(time(end)),
+        }
+    }
+
+    #[test]
+    fn test_window_skips_open_and_partial_buckets() {
+        let now = time("2024-03-27T12:30:00");
+
+        // Without a range, every closed bucket comes from the rollups.
+        let unbounded = TimeRange {
+            start: None,
+            end: None,
+        };
+        assert_eq!(
+            window(Bucket::Hour, &unbounded, now),
+            Some(RollupWindow {
+                from: None,
+                to: time("2024-03-27T12:00:00"),
+            })
+        );
+
+        // 09:00-10:00 is cut by the start, and the bucket at `end` is partial.
+        assert_eq!(
+            window(
+                Bucket::Hour,
+                &range("2024-03-27T09:15:00", "2024-03-27T11:00:00"),
+                now
+            ),
+            Some(RollupWindow {
+                from: Some(time("2024-03-27T10:00:00")),
+                to: time("2024-03-27T11:00:00"),
+            })
+        );
+
+        // Today is still open.
+        assert_eq!(
+            window(
+                Bucket::Day,
+                &range("2024-03-26T00:00:00", "2024-03-28T00:00:00"),
+                now
+            ),
+            Some(RollupWindow {
+                from: Some(time("2024-03-26T00:00:00")),
+                to: time("2024-03-27T00:00:00"),
+            })
+        );
+        assert_eq!(
+            window(
+                Bucket::Day,
+                &range("2024-03-26T06:00:00", "2024-03-27T06:00:00"),
+                now
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_buckets_before_the_raw_cutoff_are_not_recomputed() {
+        let times = [
+            time("2024-03-25T23:00:00"),
+            time("2024-03-26T00:00:00"),
+            time("2024-03-27T10:00:00"),
+        ];
+
+        assert_eq!(recomputable(&times, None), times.to_vec());
+        assert_eq!(
+            recomputable(&times, Some(time("2024-03-26T00:00:00"))),
+            vec![time("2024-03-26T00:00:00"), time("2024-03-27T10:00:00")]
+        );
+        assert!(recomputable(&times, Some(time("2024-03-28T00:00:00"))).is_empty());
+    }
+
+    #[test]
+    fn test_push_buckets_picks_rollup_table() {
+        let now = time("2024-03-27T12:30:00");
+        let time_range = TimeRange {
+            start: None,
+            end: None,
+        };
+        let sql = |resource, gpu_type, per_type| {
+            let mut query = QueryBuilder::<Postgres>::new("");
+            push_buckets(
+                &mut query,
+                "oscar",
+                resource,
+                Bucket::Day,
+                &time_range,
+                gpu_type,
+                per_type,
+                now,
+            );
+            query.into_sql()
+        };
+
+        let cpu = sql(Resource::Cpu, Some("a100"), false);
+        assert!(cpu.contains("oscar.rollup WHERE resource = 'cpu' AND bucket = 'day'"));
+        assert!(!cpu.contains("gpu_type"));
+
+        let gpu = sql(Resource::Gpu, None, false);
+        assert!(gpu.contains("SUM(allocated) AS allocated"));
+        assert!(gpu.contains("oscar.rollup WHERE resource = 'gpu'"));
+
+        let a100 = sql(Resource::Gpu, Some("a100"), false);
+        assert!(a100.contains("oscar.gpu_type_rollup WHERE bucket = 'day' AND gpu_type = $"));
+
+        let by_type = sql(Resource::Gpu, None, true);
+        assert!(by_type.contains("oscar.gpu_type_rollup"));
+        assert!(by_type.contains("ORDER BY time, gpu_type"));
+    }
+}
@@ -1,14 +1,21 @@
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime, Timelike};
+use std::sync::Arc;
 use std::time::Instant;
 
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Query, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::Deserialize;
 use sqlx::postgres::PgPool;
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::clusters::{find_cluster, Cluster, ClusterQuery, ClusterRegistry};
+use crate::config::ServerConfig;
+use crate::{quality, rollup};
 
 #[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
 pub struct Utilization {
@@ -23,402 +30,500 @@ pub struct TimeRange {
     pub end: Option<NaiveDateTime>,
 }
 
-pub async fn root() -> &'static str {
-    "Hello, World!"
+impl TimeRange {
+    /// The range to filter on, as `(start, end)`. A range is only applied when both
+    /// ends are given, otherwise both are `None`.
+    pub fn bounds(&self) -> (Option<NaiveDateTime>, Option<NaiveDateTime>) {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) => (Some(start), Some(end)),
+            _ => (None, None),
+        }
+    }
 }
 
-pub async fn get_cpu_utilization(
-    State(pool): State<PgPool>,
-    Query(time_range): Query<TimeRange>,
-) -> impl IntoResponse {
-    let t1 = Instant::now();
+/// A GPU sample for a single GPU model.
+#[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct GpuTypeUtilization {
+    pub time: Option<NaiveDateTime>,
+    pub gpu_type: Option<String>,
+    pub allocated: Option<i32>,
+    pub total: Option<i32>,
+}
 
-    let query = if time_range.start.is_some() && time_range.end.is_some() {
-        r#"
-        SELECT 
-            time, 
-            allocated, 
-            total 
-        FROM  
-            oscar.cpu 
-        WHERE time BETWEEN $1 AND $2
-        ORDER BY time
-        "#
-    } else {
-        r#"
-        SELECT 
-            time, 
-            allocated, 
-            total 
-        FROM  
-            oscar.cpu 
-        ORDER BY time
-        "#
-    };
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GpuGrouping {
+    GpuType,
+}
 
-    let cpu_utilization = if let (Some(start), Some(end)) = (time_range.start, time_range.end) {
-        sqlx::query_as::<_, Utilization>(query)
-            .bind(start)
-            .bind(end)
-            .fetch_all(&pool)
-            .await
-    } else {
-        sqlx::query_as::<_, Utilization>(query)
-            .fetch_all(&pool)
-            .await
-    };
+/// Query parameters accepted by the GPU endpoints in addition to a `TimeRange`.
+#[derive(Debug, Default, Deserialize)]
+pub struct GpuFilter {
+    pub gpu_type: Option<String>,
+    pub group_by: Option<GpuGrouping>,
+}
 
-    let cpu_utilization = cpu_utilization
-        .map_err(|e| {
-            tracing::error!("Error: failed to get cpu utilization: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })
-        .expect("Error: Could not get CPU utilization info");
+/// A utilization resource, backed by a table of the same name in each cluster schema.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Deserialize,
+    serde::Serialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Resource {
+    Cpu,
+    Gpu,
+}
 
-    let t2 = Instant::now();
-    let elapsed = t2.duration_since(t1);
-    tracing::info!("Retrieved result in: {:?}", elapsed);
+impl Resource {
+    pub fn table(self) -> &'static str {
+        match self {
+            Resource::Cpu => "cpu",
+            Resource::Gpu => "gpu",
+        }
+    }
 
-    Json(cpu_utilization)
+    /// The resource stored under `table`, the inverse of `table()`.
+    pub fn from_table(table: &str) -> Option<Resource> {
+        match table {
+            "cpu" => Some(Resource::Cpu),
+            "gpu" => Some(Resource::Gpu),
+            _ => None,
+        }
+    }
 }
 
-pub async fn get_hourly_cpu_utilization(
-    State(pool): State<PgPool>,
-    Query(time_range): Query<TimeRange>,
-) -> impl IntoResponse {
-    // We use a Common Table Expression (CTE) to first format the timestamps into hourly buckets.
-    // This ensures that all entries within the same hour are properly grouped together.
-    let query = if time_range.start.is_some() && time_range.end.is_some() {
-        r#"
-        WITH formatted_time AS (
-            -- Format timestamps to hourly precision (YYYY-MM-DD HH:00:00)
-            SELECT 
-                date_trunc('hour', time::timestamp) as time,
-                allocated,
-                total
-            FROM 
-                oscar.cpu
-            WHERE time BETWEEN $1 AND $2
-        )
-        SELECT 
-            time,
-            ROUND(AVG(allocated))::integer as allocated,
-            ROUND(AVG(total))::integer as total
-        FROM 
-            formatted_time
-        GROUP BY time
-        ORDER BY time
-        "#
-    } else {
-        r#"
-        WITH formatted_time AS (
-            -- Format timestamps to hourly precision (YYYY-MM-DD HH:00:00)
-            SELECT 
-                date_trunc('hour', time::timestamp) as time,
-                allocated,
-                total
-            FROM 
-                oscar.cpu
-        )
-        SELECT 
-            time,
-            ROUND(AVG(allocated))::integer as allocated,
-            ROUND(AVG(total))::integer as total
-        FROM 
-            formatted_time
-        GROUP BY time
-        ORDER BY time
-        "#
-    };
+/// Aggregation bucket for the hourly and daily endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+pub enum Bucket {
+    #[serde(rename = "hourly")]
+    Hour,
+    #[serde(rename = "daily")]
+    Day,
+}
 
-    let hourly_cpu_utilization =
-        if let (Some(start), Some(end)) = (time_range.start, time_range.end) {
-            sqlx::query_as::<_, Utilization>(query)
-                .bind(start)
-                .bind(end)
-                .fetch_all(&pool)
-                .await
-        } else {
-            sqlx::query_as::<_, Utilization>(query)
-                .fetch_all(&pool)
-                .await
+impl Bucket {
+    /// The `date_trunc` field for this bucket.
+    pub fn unit(self) -> &'static str {
+        match self {
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+        }
+    }
+
+    /// The bucket for a `unit()`, as stored in the rollup tables.
+    pub fn from_unit(unit: &str) -> Option<Bucket> {
+        match unit {
+            "hour" => Some(Bucket::Hour),
+            "day" => Some(Bucket::Day),
+            _ => None,
+        }
+    }
+
+    pub fn width(self) -> Duration {
+        match self {
+            Bucket::Hour => Duration::hours(1),
+            Bucket::Day => Duration::days(1),
+        }
+    }
+
+    /// The start of the bucket `time` falls in, like `date_trunc(unit, time)`.
+    pub fn truncate(self, time: NaiveDateTime) -> NaiveDateTime {
+        let hour = match self {
+            Bucket::Hour => time.hour(),
+            Bucket::Day => 0,
         };
+        time.date().and_hms_opt(hour, 0, 0).expect("valid time")
+    }
+}
 
-    let hourly_cpu_utilization = hourly_cpu_utilization
-        .map_err(|e| {
-            tracing::error!("Error: failed to get hourly cpu utilization: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })
-        .expect("Error: Could not get hourly CPU utilization info");
+/// Appends the WHERE clause shared by the utilization queries. Like the original
+/// queries, the time range is only applied when both ends are given. Returns the
+/// separator for any further conditions.
+pub(crate) fn push_filters(
+    query: &mut QueryBuilder<'_, Postgres>,
+    time_range: &TimeRange,
+    gpu_type: Option<&str>,
+) -> &'static str {
+    let mut separator = " WHERE ";
+
+    if let (Some(start), Some(end)) = time_range.bounds() {
+        query
+            .push(separator)
+            .push("time BETWEEN ")
+            .push_bind(start)
+            .push(" AND ")
+            .push_bind(end);
+        separator = " AND ";
+    }
 
-    Json(hourly_cpu_utilization)
+    if let Some(gpu_type) = gpu_type {
+        query
+            .push(separator)
+            .push("gpu_type = ")
+            .push_bind(gpu_type.to_string());
+        separator = " AND ";
+    }
+
+    separator
 }
 
-pub async fn get_gpu_utilization(
-    State(pool): State<PgPool>,
-    Query(time_range): Query<TimeRange>,
-) -> impl IntoResponse {
-    let query = if time_range.start.is_some() && time_range.end.is_some() {
-        r#"
-        SELECT 
-            time, 
-            allocated, 
-            total 
-        FROM  
-            oscar.gpu 
-        WHERE time BETWEEN $1 AND $2
-        ORDER BY time
-        "#
-    } else {
-        r#"
-        SELECT 
-            time, 
-            allocated, 
-            total 
-        FROM  
-            oscar.gpu 
-        ORDER BY time
-        "#
-    };
+/// Reads cluster-wide utilization for `resource` from the tables in `schema`, either
+/// as raw samples or averaged into hourly or daily buckets. Buckets come from the
+/// rollup tables where they are up to date, see `rollup::fetch_bucketed`.
+pub async fn fetch_utilization(
+    pool: &PgPool,
+    schema: &str,
+    resource: Resource,
+    bucket: Option<Bucket>,
+    time_range: &TimeRange,
+    gpu_type: Option<&str>,
+) -> Result<Vec<Utilization>, sqlx::Error> {
+    if let Some(bucket) = bucket {
+        return rollup::fetch_bucketed(
+            pool,
+            schema,
+            resource,
+            bucket,
+            time_range,
+            gpu_type,
+            quality::now(),
+        )
+        .await;
+    }
 
-    let utilization = if let (Some(start), Some(end)) = (time_range.start, time_range.end) {
-        sqlx::query_as::<_, Utilization>(query)
-            .bind(start)
-            .bind(end)
-            .fetch_all(&pool)
-            .await
-    } else {
-        sqlx::query_as::<_, Utilization>(query)
-            .fetch_all(&pool)
-            .await
+    // GPU samples can have one row per GPU type at the same time, so they are summed
+    // back into a single cluster-wide row per sample.
+    let mut query = QueryBuilder::<Postgres>::new("");
+    let gpu_type = match resource {
+        Resource::Cpu => {
+            query.push(format!(
+                r#"
+            SELECT
+                time,
+                allocated,
+                total
+            FROM
+                {schema}.cpu"#
+            ));
+            None
+        }
+        Resource::Gpu => {
+            query.push(format!(
+                r#"
+            SELECT
+                time,
+                SUM(allocated)::integer as allocated,
+                SUM(total)::integer as total
+            FROM
+                {schema}.gpu"#
+            ));
+            gpu_type
+        }
     };
 
-    let utilization = utilization
-        .map_err(|e| {
-            tracing::error!("Error: failed to get gpu utilization: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })
-        .expect("Error: Could not get GPU utilization info");
+    push_filters(&mut query, time_range, gpu_type);
+
+    if resource == Resource::Gpu {
+        query.push(" GROUP BY time");
+    }
+    query.push(" ORDER BY time");
 
-    Json(utilization)
+    query.build_query_as::<Utilization>().fetch_all(pool).await
 }
 
-pub async fn get_hourly_gpu_utilization(
-    State(pool): State<PgPool>,
-    Query(time_range): Query<TimeRange>,
-) -> impl IntoResponse {
-    // We use a Common Table Expression (CTE) to first format the timestamps into hourly buckets.
-    // This ensures that all entries within the same hour are properly grouped together.
-    let query = if time_range.start.is_some() && time_range.end.is_some() {
-        r#"
-        WITH formatted_time AS (
-            -- First, format all timestamps to hourly precision (YYYY-MM-DD HH:00:00)
-            -- This ensures all entries within the same hour have the same timestamp
-            SELECT 
-                date_trunc('hour', time::timestamp) as time,
-                allocated,
-                total
-            FROM 
-                oscar.gpu
-            WHERE time BETWEEN $1 AND $2
+/// Like `fetch_utilization` for GPUs, but keeps one row per GPU type.
+pub async fn fetch_gpu_type_utilization(
+    pool: &PgPool,
+    schema: &str,
+    bucket: Option<Bucket>,
+    time_range: &TimeRange,
+    gpu_type: Option<&str>,
+) -> Result<Vec<GpuTypeUtilization>, sqlx::Error> {
+    if let Some(bucket) = bucket {
+        return rollup::fetch_gpu_type_bucketed(
+            pool,
+            schema,
+            bucket,
+            time_range,
+            gpu_type,
+            quality::now(),
         )
-        SELECT 
+        .await;
+    }
+
+    let mut query = QueryBuilder::<Postgres>::new(format!(
+        r#"
+        SELECT
             time,
-            CAST(ROUND(AVG(allocated)) AS INTEGER) as allocated,
-            CAST(ROUND(AVG(total)) AS INTEGER) as total
-        FROM formatted_time
-        GROUP BY time
-        ORDER BY time
-        "#
-    } else {
+            gpu_type,
+            allocated,
+            total
+        FROM
+            {schema}.gpu"#
+    ));
+    push_filters(&mut query, time_range, gpu_type);
+    query.push(" ORDER BY time, gpu_type");
+
+    query
+        .build_query_as::<GpuTypeUtilization>()
+        .fetch_all(pool)
+        .await
+}
+
+/// How far behind the newest GPU sample a model's latest sample may be for
+/// `/gpu/types` to still list it.
+const GPU_TYPE_WINDOW: &str = "1 hour";
+
+/// The latest sample for each GPU model in `schema` that is still in service, i.e.
+/// was sampled within [`GPU_TYPE_WINDOW`] of the newest sample. Samples recorded
+/// before GPU types were tracked have an empty gpu_type and are left out.
+pub async fn fetch_gpu_types(
+    pool: &PgPool,
+    schema: &str,
+) -> Result<Vec<GpuTypeUtilization>, sqlx::Error> {
+    let query = format!(
         r#"
-        WITH formatted_time AS (
-            -- First, format all timestamps to hourly precision (YYYY-MM-DD HH:00:00)
-            -- This ensures all entries within the same hour have the same timestamp
-            SELECT 
-                date_trunc('hour', time::timestamp) as time,
+        WITH latest AS (
+            SELECT DISTINCT ON (gpu_type)
+                time,
+                gpu_type,
                 allocated,
                 total
-            FROM 
-                oscar.gpu
+            FROM
+                {schema}.gpu
+            WHERE gpu_type <> ''
+            ORDER BY gpu_type, time DESC
         )
-        SELECT 
+        SELECT
             time,
-            CAST(ROUND(AVG(allocated)) AS INTEGER) as allocated,
-            CAST(ROUND(AVG(total)) AS INTEGER) as total
-        FROM formatted_time
-        GROUP BY time
-        ORDER BY time
+            gpu_type,
+            allocated,
+            total
+        FROM
+            latest
+        WHERE time > (SELECT MAX(time) FROM latest) - interval '{GPU_TYPE_WINDOW}'
+        ORDER BY gpu_type
         "#
-    };
+    );
 
-    let hourly_utilization = if let (Some(start), Some(end)) = (time_range.start, time_range.end) {
-        sqlx::query_as::<_, Utilization>(query)
-            .bind(start)
-            .bind(end)
-            .fetch_all(&pool)
-            .await
+    sqlx::query_as::<_, GpuTypeUtilization>(&query)
+        .fetch_all(pool)
+        .await
+}
+
+/// Utilization for one cluster as a JSON response, honouring the GPU filter and
+/// grouping options. The filter is ignored for CPUs.
+pub async fn utilization_response(
+    cluster: &Cluster,
+    resource: Resource,
+    bucket: Option<Bucket>,
+    time_range: &TimeRange,
+    filter: &GpuFilter,
+) -> Result<Response, sqlx::Error> {
+    let gpu_type = filter.gpu_type.as_deref();
+
+    if resource == Resource::Gpu && filter.group_by == Some(GpuGrouping::GpuType) {
+        let utilization = fetch_gpu_type_utilization(
+            &cluster.read_pool(),
+            &cluster.schema,
+            bucket,
+            time_range,
+            gpu_type,
+        )
+        .await?;
+        Ok(Json(utilization).into_response())
     } else {
-        sqlx::query_as::<_, Utilization>(query)
-            .fetch_all(&pool)
-            .await
-    };
+        let utilization = fetch_utilization(
+            &cluster.read_pool(),
+            &cluster.schema,
+            resource,
+            bucket,
+            time_range,
+            gpu_type,
+        )
+        .await?;
+        Ok(Json(utilization).into_response())
+    }
+}
 
-    let hourly_utilization = hourly_utilization
-        .map_err(|e| {
-            tracing::error!("Error: failed to get hourly gpu utilization: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })
-        .expect("Error: Could not get hourly GPU utilization info");
+pub async fn root() -> &'static str {
+    "Hello, World!"
+}
+
+/// Middleware letting browsers and proxies reuse successful read responses for the
+/// configured `cache_max_age_seconds`.
+pub async fn cache_control(
+    State(server): State<Arc<ServerConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+
+    if server.cache_max_age_seconds > 0 && response.status().is_success() {
+        let value = format!("public, max-age={}", server.cache_max_age_seconds);
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+    }
+
+    response
+}
+
+pub async fn get_cpu_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+) -> Result<Json<Vec<Utilization>>, StatusCode> {
+    let t1 = Instant::now();
+
+    let cluster = clusters.default_cluster();
+
+    let cpu_utilization = fetch_utilization(
+        &cluster.read_pool(),
+        &cluster.schema,
+        Resource::Cpu,
+        None,
+        &time_range,
+        None,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get cpu utilization: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let t2 = Instant::now();
+    let elapsed = t2.duration_since(t1);
+    tracing::info!("Retrieved result in: {:?}", elapsed);
 
-    Json(hourly_utilization)
+    Ok(Json(cpu_utilization))
+}
+
+pub async fn get_hourly_cpu_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+) -> Result<Json<Vec<Utilization>>, StatusCode> {
+    let cluster = clusters.default_cluster();
+
+    let hourly_cpu_utilization = fetch_utilization(
+        &cluster.read_pool(),
+        &cluster.schema,
+        Resource::Cpu,
+        Some(Bucket::Hour),
+        &time_range,
+        None,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get hourly cpu utilization: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(hourly_cpu_utilization))
 }
 
 pub async fn get_daily_cpu_utilization(
-    State(pool): State<PgPool>,
+    State(clusters): State<Arc<ClusterRegistry>>,
     Query(time_range): Query<TimeRange>,
-) -> impl IntoResponse {
-    // We use a Common Table Expression (CTE) to first format the timestamps into daily buckets.
-    // This ensures that all entries within the same day are properly grouped together.
-    // The CTE approach is cleaner than using strftime directly in the GROUP BY clause
-    // and makes the query more maintainable.
-    let query = if time_range.start.is_some() && time_range.end.is_some() {
-        r#"
-        WITH formatted_time AS (
-            -- First, format all timestamps to daily precision (YYYY-MM-DD)
-            -- This ensures all entries within the same day have the same timestamp
-            SELECT 
-                date_trunc('day', time::timestamp) as time,
-                allocated,
-                total
-            FROM 
-                oscar.cpu
-            WHERE time BETWEEN $1 AND $2
-        )
-        SELECT 
-            time,
-            CAST(ROUND(AVG(allocated)) AS INTEGER) as allocated,
-            CAST(ROUND(AVG(total)) AS INTEGER) as total
-        FROM formatted_time
-        GROUP BY time
-        ORDER BY time
-        "#
-    } else {
-        r#"
-        WITH formatted_time AS (
-            -- First, format all timestamps to daily precision (YYYY-MM-DD)
-            -- This ensures all entries within the same day have the same timestamp
-            SELECT 
-                date_trunc('day', time::timestamp) as time,
-                allocated,
-                total
-            FROM 
-                oscar.cpu
-        )
-        SELECT 
-            time,
-            CAST(ROUND(AVG(allocated)) AS INTEGER) as allocated,
-            CAST(ROUND(AVG(total)) AS INTEGER) as total
-        FROM formatted_time
-        GROUP BY time
-        ORDER BY time
-        "#
-    };
+) -> Result<Json<Vec<Utilization>>, StatusCode> {
+    let cluster = clusters.default_cluster();
+
+    let daily_cpu_utilization = fetch_utilization(
+        &cluster.read_pool(),
+        &cluster.schema,
+        Resource::Cpu,
+        Some(Bucket::Day),
+        &time_range,
+        None,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get daily cpu utilization: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(daily_cpu_utilization))
+}
 
-    let daily_cpu_utilization = if let (Some(start), Some(end)) = (time_range.start, time_range.end)
-    {
-        sqlx::query_as::<_, Utilization>(query)
-            .bind(start)
-            .bind(end)
-            .fetch_all(&pool)
-            .await
-    } else {
-        sqlx::query_as::<_, Utilization>(query)
-            .fetch_all(&pool)
-            .await
-    };
+pub async fn get_gpu_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<GpuFilter>,
+) -> Result<Response, StatusCode> {
+    let cluster = clusters.default_cluster();
 
-    let daily_cpu_utilization = daily_cpu_utilization
+    utilization_response(cluster, Resource::Gpu, None, &time_range, &filter)
+        .await
         .map_err(|e| {
-            tracing::error!("Error: failed to get daily cpu utilization: {:?}", e);
+            tracing::error!("Error: failed to get gpu utilization: {:?}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })
-        .expect("Error: Could not get daily CPU utilization info");
+}
 
-    Json(daily_cpu_utilization)
+pub async fn get_hourly_gpu_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<GpuFilter>,
+) -> Result<Response, StatusCode> {
+    let cluster = clusters.default_cluster();
+
+    utilization_response(
+        cluster,
+        Resource::Gpu,
+        Some(Bucket::Hour),
+        &time_range,
+        &filter,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get hourly gpu utilization: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
 }
 
 pub async fn get_daily_gpu_utilization(
-    State(pool): State<PgPool>,
+    State(clusters): State<Arc<ClusterRegistry>>,
     Query(time_range): Query<TimeRange>,
-) -> impl IntoResponse {
-    // We use a Common Table Expression (CTE) to first format the timestamps into daily buckets.
-    // This ensures that all entries within the same day are properly grouped together.
-    // The CTE approach is cleaner than using strftime directly in the GROUP BY clause
-    // and makes the query more maintainable.
-    let query = if time_range.start.is_some() && time_range.end.is_some() {
-        r#"
-        WITH formatted_time AS (
-            -- First, format all timestamps to daily precision (YYYY-MM-DD)
-            -- This ensures all entries within the same day have the same timestamp
-            SELECT 
-                date_trunc('day', time::timestamp) as time,
-                allocated,
-                total
-            FROM 
-                oscar.gpu
-            WHERE time BETWEEN $1 AND $2
-        )
-        SELECT 
-            time,
-            CAST(ROUND(AVG(allocated)) AS INTEGER) as allocated,
-            CAST(ROUND(AVG(total)) AS INTEGER) as total
-        FROM formatted_time
-        GROUP BY time
-        ORDER BY time
-        "#
-    } else {
-        r#"
-        WITH formatted_time AS (
-            -- First, format all timestamps to daily precision (YYYY-MM-DD)
-            -- This ensures all entries within the same day have the same timestamp
-            SELECT 
-                date_trunc('day', time::timestamp) as time,
-                allocated,
-                total
-            FROM 
-                oscar.gpu
-        )
-        SELECT 
-            time,
-            CAST(ROUND(AVG(allocated)) AS INTEGER) as allocated,
-            CAST(ROUND(AVG(total)) AS INTEGER) as total
-        FROM formatted_time
-        GROUP BY time
-        ORDER BY time
-        "#
-    };
+    Query(filter): Query<GpuFilter>,
+) -> Result<Response, StatusCode> {
+    let cluster = clusters.default_cluster();
+
+    utilization_response(
+        cluster,
+        Resource::Gpu,
+        Some(Bucket::Day),
+        &time_range,
+        &filter,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get daily gpu utilization: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
 
-    let daily_utilization = if let (Some(start), Some(end)) = (time_range.start, time_range.end) {
-        sqlx::query_as::<_, Utilization>(query)
-            .bind(start)
-            .bind(end)
-            .fetch_all(&pool)
-            .await
-    } else {
-        sqlx::query_as::<_, Utilization>(query)
-            .fetch_all(&pool)
-            .await
-    };
+pub async fn get_gpu_types(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<GpuTypeUtilization>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
 
-    let daily_utilization = daily_utilization
+    let gpu_types = fetch_gpu_types(&cluster.read_pool(), &cluster.schema)
+        .await
         .map_err(|e| {
-            tracing::error!("Error: failed to get daily gpu utilization: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })
-        .expect("Error: Could not get daily GPU utilization info");
+            tracing::error!("Error: failed to get gpu types: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
 
-    Json(daily_utilization)
+    Ok(Json(gpu_types))
 }
 
 #[cfg(test)]
@@ -431,24 +536,7 @@ mod tests {
     async fn setup_test_db() -> SqlitePool {
         let pool = SqlitePool::connect(":memory:").await.unwrap();
 
-        // Create test tables - SQLite doesn't support schemas like PostgreSQL
-        sqlx::query(
-            r#"
-            CREATE TABLE cpu (
-                time TEXT NOT NULL,
-                allocated INTEGER NOT NULL,
-                total INTEGER NOT NULL
-            );
-            CREATE TABLE gpu (
-                time TEXT NOT NULL,
-                allocated INTEGER NOT NULL,
-                total INTEGER NOT NULL
-            );
-            "#,
-        )
-        .execute(&pool)
-        .await
-        .unwrap();
+        crate::migrations::SQLITE.run(&pool).await.unwrap();
 
         // Insert test data
         sqlx::query(
@@ -681,6 +769,38 @@ mod tests {
         bytes.to_vec()
     }
 
+    #[test]
+    fn test_range_bounds_requires_both_ends() {
+        let time_range = TimeRange {
+            start: Some("2024-03-27T00:00:00".parse().unwrap()),
+            end: None,
+        };
+
+        assert_eq!(time_range.bounds(), (None, None));
+    }
+
+    #[test]
+    fn test_gpu_filter_parses_type_and_grouping() {
+        let uri: axum::http::Uri = "/gpu/hourly?gpu_type=a100&group_by=gpu_type"
+            .parse()
+            .unwrap();
+        let Query(filter) = Query::<GpuFilter>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(filter.gpu_type.as_deref(), Some("a100"));
+        assert_eq!(filter.group_by, Some(GpuGrouping::GpuType));
+    }
+
+    #[test]
+    fn test_gpu_filter_is_optional() {
+        let uri: axum::http::Uri = "/gpu?start=2024-03-27T00:00:00&end=2024-03-27T01:00:00"
+            .parse()
+            .unwrap();
+        let Query(filter) = Query::<GpuFilter>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(filter.gpu_type, None);
+        assert_eq!(filter.group_by, None);
+    }
+
     #[tokio::test]
     async fn test_get_cpu_utilization_without_time_range() {
         let pool = setup_test_db().await;
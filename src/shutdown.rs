@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use axum::Router;
+use sqlx::postgres::PgPool;
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::clusters::ClusterRegistry;
+use crate::replicas::Replicas;
+
+/// Waits for SIGTERM, which Cloud Run and Kubernetes send before stopping an instance,
+/// or for SIGINT (Ctrl-C). Returns the signal's name.
+pub async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                tracing::error!("Error: failed to listen for SIGTERM: {:?}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+/// Cancels `shutdown` on the first signal. A second signal exits straight away, for
+/// when draining takes longer than whoever is waiting for it wants.
+pub async fn cancel_on_signal(shutdown: CancellationToken) {
+    let name = signal().await;
+    tracing::info!("Received {}, shutting down", name);
+    shutdown.cancel();
+
+    let name = signal().await;
+    tracing::warn!("Received {} again, exiting without waiting", name);
+    std::process::exit(1);
+}
+
+/// How the server winds down once `shutdown` is cancelled.
+#[derive(Debug, Clone, Copy)]
+pub struct DrainTimes {
+    /// How long `/ready` fails before the listener closes, so load balancers stop
+    /// sending new requests first.
+    pub delay: Duration,
+    /// How long in-flight requests, background tasks and the pools get to finish after
+    /// that, all together.
+    pub timeout: Duration,
+}
+
+impl DrainTimes {
+    /// When everything has to be done, counted from the start of the shutdown.
+    pub fn deadline(&self, started: Instant) -> Instant {
+        started + self.delay + self.timeout
+    }
+}
+
+/// Serves `app` on `listener`. Once `shutdown` is cancelled `/ready` fails, but the
+/// listener stays open for `delay` more, so requests keep being served until load
+/// balancers have noticed; then the server drains the requests in flight.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    shutdown: CancellationToken,
+    delay: Duration,
+) -> std::io::Result<()> {
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown.cancelled().await;
+            tokio::time::sleep(delay).await;
+            tracing::info!("No longer accepting connections, draining requests");
+        })
+        .await
+}
+
+/// Closes the main pool and every cluster and replica pool, waiting for connections in
+/// use to be returned.
+pub async fn close_pools(pool: &PgPool, clusters: &ClusterRegistry, replicas: &Replicas) {
+    pool.close().await;
+    for cluster in clusters.iter() {
+        cluster.pool.close().await;
+    }
+    replicas.close().await;
+}
+
+/// Waits for `future` until `deadline`, logging `what` if it doesn't finish in time.
+/// Returns its output if it did.
+pub async fn until<F: std::future::Future>(
+    deadline: Instant,
+    what: &str,
+    future: F,
+) -> Option<F::Output> {
+    match tokio::time::timeout_at(deadline, future).await {
+        Ok(output) => Some(output),
+        Err(_) => {
+            tracing::warn!(
+                "{} still running after the drain timeout, giving up on them",
+                what
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_until_gives_up_at_the_deadline() {
+        let drain = DrainTimes {
+            delay: Duration::from_secs(2),
+            timeout: Duration::from_secs(8),
+        };
+        let started = Instant::now();
+        let deadline = drain.deadline(started);
+        assert_eq!(deadline - started, Duration::from_secs(10));
+
+        let quick = until(deadline, "Quick tasks", async { 1 }).await;
+        assert_eq!(quick, Some(1));
+
+        let stuck = until(deadline, "Stuck tasks", std::future::pending::<()>()).await;
+        assert_eq!(stuck, None);
+        assert_eq!(Instant::now(), deadline);
+    }
+}
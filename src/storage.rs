@@ -0,0 +1,626 @@
+use chrono::NaiveDateTime;
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::ingest::{is_ndjson, keep_last, parse_batch, IngestReport};
+use crate::routes::TimeRange;
+
+/// Capacity sample for one filesystem.
+#[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct StorageUtilization {
+    pub time: Option<NaiveDateTime>,
+    pub filesystem: Option<String>,
+    pub used_bytes: Option<i64>,
+    pub capacity_bytes: Option<i64>,
+    pub inodes_used: Option<i64>,
+    pub inodes_total: Option<i64>,
+}
+
+/// Quota sample for one group on one filesystem. The fractions are `None` when the
+/// group has no limit for that dimension.
+#[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct QuotaUsage {
+    pub time: Option<NaiveDateTime>,
+    pub filesystem: Option<String>,
+    pub group_name: Option<String>,
+    pub used_bytes: Option<i64>,
+    pub limit_bytes: Option<i64>,
+    pub inodes_used: Option<i64>,
+    pub inode_limit: Option<i64>,
+    pub bytes_fraction: Option<f64>,
+    pub inodes_fraction: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StorageFilter {
+    pub filesystem: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QuotaFilter {
+    pub filesystem: Option<String>,
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaDimension {
+    #[default]
+    Bytes,
+    Inodes,
+}
+
+impl QuotaDimension {
+    fn column(self) -> &'static str {
+        match self {
+            QuotaDimension::Bytes => "bytes_fraction",
+            QuotaDimension::Inodes => "inodes_fraction",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FullestQuotasQuery {
+    pub filesystem: Option<String>,
+    pub by: Option<QuotaDimension>,
+    pub limit: Option<i64>,
+}
+
+const DEFAULT_FULLEST_LIMIT: i64 = 20;
+const MAX_FULLEST_LIMIT: i64 = 1000;
+
+/// A filesystem capacity sample as sent to `POST /storage/samples`, e.g. from `df`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageSample {
+    pub time: NaiveDateTime,
+    pub filesystem: String,
+    pub used_bytes: i64,
+    pub capacity_bytes: i64,
+    pub inodes_used: i64,
+    pub inodes_total: i64,
+}
+
+impl StorageSample {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.filesystem.trim().is_empty() {
+            return Err("filesystem is empty".to_string());
+        }
+        let counts = [
+            self.used_bytes,
+            self.capacity_bytes,
+            self.inodes_used,
+            self.inodes_total,
+        ];
+        if counts.iter().any(|count| *count < 0) {
+            return Err("bytes and inodes must not be negative".to_string());
+        }
+        if self.used_bytes > self.capacity_bytes {
+            return Err(format!(
+                "used_bytes ({}) exceeds capacity_bytes ({})",
+                self.used_bytes, self.capacity_bytes
+            ));
+        }
+        if self.inodes_used > self.inodes_total {
+            return Err(format!(
+                "inodes_used ({}) exceeds inodes_total ({})",
+                self.inodes_used, self.inodes_total
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A group's quota sample as sent to `POST /storage/quotas/samples`. Usage may be over
+/// the limit, as it is during a soft quota's grace period.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaSample {
+    pub time: NaiveDateTime,
+    pub filesystem: String,
+    #[serde(alias = "group")]
+    pub group_name: String,
+    pub used_bytes: i64,
+    pub limit_bytes: Option<i64>,
+    pub inodes_used: i64,
+    pub inode_limit: Option<i64>,
+}
+
+impl QuotaSample {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.filesystem.trim().is_empty() {
+            return Err("filesystem is empty".to_string());
+        }
+        if self.group_name.trim().is_empty() {
+            return Err("group_name is empty".to_string());
+        }
+        let counts = [
+            Some(self.used_bytes),
+            self.limit_bytes,
+            Some(self.inodes_used),
+            self.inode_limit,
+        ];
+        if counts.iter().flatten().any(|count| *count < 0) {
+            return Err("bytes, inodes and limits must not be negative".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Each row binds at most 7 parameters and Postgres allows at most 65535 per statement.
+const UPSERT_CHUNK_SIZE: usize = 5000;
+
+/// Upserts filesystem samples into `schema`, keyed on filesystem and time.
+pub async fn upsert_storage(
+    pool: &PgPool,
+    schema: &str,
+    samples: &[StorageSample],
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut affected = 0;
+
+    for chunk in samples.chunks(UPSERT_CHUNK_SIZE) {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "INSERT INTO {schema}.storage (time, filesystem, used_bytes, capacity_bytes, \
+             inodes_used, inodes_total) "
+        ));
+        query.push_values(chunk, |mut row, sample| {
+            row.push_bind(sample.time)
+                .push_bind(&sample.filesystem)
+                .push_bind(sample.used_bytes)
+                .push_bind(sample.capacity_bytes)
+                .push_bind(sample.inodes_used)
+                .push_bind(sample.inodes_total);
+        });
+        query.push(
+            " ON CONFLICT (filesystem, time) DO UPDATE SET \
+             used_bytes = EXCLUDED.used_bytes, \
+             capacity_bytes = EXCLUDED.capacity_bytes, \
+             inodes_used = EXCLUDED.inodes_used, \
+             inodes_total = EXCLUDED.inodes_total",
+        );
+
+        affected += query.build().execute(&mut *tx).await?.rows_affected();
+    }
+
+    tx.commit().await?;
+
+    Ok(affected)
+}
+
+/// Upserts quota samples into `schema`, keyed on filesystem, group and time.
+pub async fn upsert_quotas(
+    pool: &PgPool,
+    schema: &str,
+    samples: &[QuotaSample],
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut affected = 0;
+
+    for chunk in samples.chunks(UPSERT_CHUNK_SIZE) {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "INSERT INTO {schema}.quotas (time, filesystem, group_name, used_bytes, \
+             limit_bytes, inodes_used, inode_limit) "
+        ));
+        query.push_values(chunk, |mut row, sample| {
+            row.push_bind(sample.time)
+                .push_bind(&sample.filesystem)
+                .push_bind(&sample.group_name)
+                .push_bind(sample.used_bytes)
+                .push_bind(sample.limit_bytes)
+                .push_bind(sample.inodes_used)
+                .push_bind(sample.inode_limit);
+        });
+        query.push(
+            " ON CONFLICT (filesystem, group_name, time) DO UPDATE SET \
+             used_bytes = EXCLUDED.used_bytes, \
+             limit_bytes = EXCLUDED.limit_bytes, \
+             inodes_used = EXCLUDED.inodes_used, \
+             inode_limit = EXCLUDED.inode_limit",
+        );
+
+        affected += query.build().execute(&mut *tx).await?.rows_affected();
+    }
+
+    tx.commit().await?;
+
+    Ok(affected)
+}
+
+/// Validates a batch of filesystem samples; the last sample per filesystem and time wins.
+pub fn prepare_storage(
+    rows: Vec<Result<StorageSample, String>>,
+) -> (Vec<StorageSample>, IngestReport) {
+    keep_last(rows, StorageSample::validate, |sample| {
+        (sample.filesystem.clone(), sample.time)
+    })
+}
+
+/// Validates a batch of quota samples; the last sample per filesystem, group and time
+/// wins.
+pub fn prepare_quotas(rows: Vec<Result<QuotaSample, String>>) -> (Vec<QuotaSample>, IngestReport) {
+    keep_last(rows, QuotaSample::validate, |sample| {
+        (
+            sample.filesystem.clone(),
+            sample.group_name.clone(),
+            sample.time,
+        )
+    })
+}
+
+pub async fn post_storage_samples(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(cluster): Query<ClusterQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let rows = parse_batch(&body, is_ndjson(&headers))
+        .map_err(|reason| (StatusCode::BAD_REQUEST, reason))?;
+    let (samples, report) = prepare_storage(rows);
+
+    upsert_storage(&cluster.pool, &cluster.schema, &samples)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to insert storage samples: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    tracing::info!(
+        "Ingested storage samples: {} accepted, {} rejected",
+        report.accepted,
+        report.rejected
+    );
+
+    Ok(Json(report))
+}
+
+pub async fn post_quota_samples(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(cluster): Query<ClusterQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<IngestReport>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let rows = parse_batch(&body, is_ndjson(&headers))
+        .map_err(|reason| (StatusCode::BAD_REQUEST, reason))?;
+    let (samples, report) = prepare_quotas(rows);
+
+    upsert_quotas(&cluster.pool, &cluster.schema, &samples)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to insert quota samples: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    tracing::info!(
+        "Ingested quota samples: {} accepted, {} rejected",
+        report.accepted,
+        report.rejected
+    );
+
+    Ok(Json(report))
+}
+
+pub async fn get_storage_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<StorageFilter>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<StorageUtilization>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let query = format!(
+        r#"
+        SELECT
+            time,
+            filesystem,
+            used_bytes,
+            capacity_bytes,
+            inodes_used,
+            inodes_total
+        FROM
+            {schema}.storage
+        WHERE ($1::timestamp IS NULL OR time BETWEEN $1 AND $2)
+          AND ($3::text IS NULL OR filesystem = $3)
+        ORDER BY time, filesystem
+        "#,
+        schema = cluster.schema
+    );
+
+    let (start, end) = time_range.bounds();
+
+    let storage = sqlx::query_as::<_, StorageUtilization>(&query)
+        .bind(start)
+        .bind(end)
+        .bind(filter.filesystem)
+        .fetch_all(&cluster.read_pool())
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to get storage utilization: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(storage))
+}
+
+/// Shared implementation of the hourly and daily storage endpoints. `unit` is passed
+/// straight to `date_trunc`.
+async fn get_bucketed_storage_utilization(
+    pool: &PgPool,
+    schema: &str,
+    unit: &str,
+    time_range: TimeRange,
+    filter: StorageFilter,
+) -> Result<Vec<StorageUtilization>, sqlx::Error> {
+    let query = format!(
+        r#"
+        WITH formatted_time AS (
+            -- First, format all timestamps to the bucket precision
+            -- This ensures all entries within the same bucket have the same timestamp
+            SELECT
+                date_trunc($4, time::timestamp) as time,
+                filesystem,
+                used_bytes,
+                capacity_bytes,
+                inodes_used,
+                inodes_total
+            FROM
+                {schema}.storage
+            WHERE ($1::timestamp IS NULL OR time BETWEEN $1 AND $2)
+              AND ($3::text IS NULL OR filesystem = $3)
+        )
+        SELECT
+            time,
+            filesystem,
+            CAST(ROUND(AVG(used_bytes)) AS BIGINT) as used_bytes,
+            CAST(ROUND(AVG(capacity_bytes)) AS BIGINT) as capacity_bytes,
+            CAST(ROUND(AVG(inodes_used)) AS BIGINT) as inodes_used,
+            CAST(ROUND(AVG(inodes_total)) AS BIGINT) as inodes_total
+        FROM formatted_time
+        GROUP BY time, filesystem
+        ORDER BY time, filesystem
+        "#
+    );
+
+    let (start, end) = time_range.bounds();
+
+    sqlx::query_as::<_, StorageUtilization>(&query)
+        .bind(start)
+        .bind(end)
+        .bind(filter.filesystem)
+        .bind(unit)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_hourly_storage_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<StorageFilter>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<StorageUtilization>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let storage = get_bucketed_storage_utilization(
+        &cluster.read_pool(),
+        &cluster.schema,
+        "hour",
+        time_range,
+        filter,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get hourly storage utilization: {:?}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    Ok(Json(storage))
+}
+
+pub async fn get_daily_storage_utilization(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<StorageFilter>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<StorageUtilization>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let storage = get_bucketed_storage_utilization(
+        &cluster.read_pool(),
+        &cluster.schema,
+        "day",
+        time_range,
+        filter,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get daily storage utilization: {:?}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    Ok(Json(storage))
+}
+
+pub async fn get_quota_usage(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(filter): Query<QuotaFilter>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<QuotaUsage>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let query = format!(
+        r#"
+        SELECT
+            time,
+            filesystem,
+            group_name,
+            used_bytes,
+            limit_bytes,
+            inodes_used,
+            inode_limit,
+            used_bytes::float8 / NULLIF(limit_bytes, 0) as bytes_fraction,
+            inodes_used::float8 / NULLIF(inode_limit, 0) as inodes_fraction
+        FROM
+            {schema}.quotas
+        WHERE ($1::timestamp IS NULL OR time BETWEEN $1 AND $2)
+          AND ($3::text IS NULL OR filesystem = $3)
+          AND ($4::text IS NULL OR group_name = $4)
+        ORDER BY time, filesystem, group_name
+        "#,
+        schema = cluster.schema
+    );
+
+    let (start, end) = time_range.bounds();
+
+    let quotas = sqlx::query_as::<_, QuotaUsage>(&query)
+        .bind(start)
+        .bind(end)
+        .bind(filter.filesystem)
+        .bind(filter.group)
+        .fetch_all(&cluster.read_pool())
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to get quota usage: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(quotas))
+}
+
+pub async fn get_fullest_quotas(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(params): Query<FullestQuotasQuery>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<QuotaUsage>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let by = params.by.unwrap_or_default();
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_FULLEST_LIMIT)
+        .clamp(1, MAX_FULLEST_LIMIT);
+
+    // Rank each group by its most recent quota sample, skipping groups without a
+    // limit for the requested dimension.
+    let query = format!(
+        r#"
+        WITH latest AS (
+            SELECT DISTINCT ON (filesystem, group_name)
+                time,
+                filesystem,
+                group_name,
+                used_bytes,
+                limit_bytes,
+                inodes_used,
+                inode_limit
+            FROM
+                {schema}.quotas
+            WHERE ($1::text IS NULL OR filesystem = $1)
+            ORDER BY filesystem, group_name, time DESC
+        ),
+        ratios AS (
+            SELECT
+                *,
+                used_bytes::float8 / NULLIF(limit_bytes, 0) as bytes_fraction,
+                inodes_used::float8 / NULLIF(inode_limit, 0) as inodes_fraction
+            FROM latest
+        )
+        SELECT *
+        FROM ratios
+        WHERE {order} IS NOT NULL
+        ORDER BY {order} DESC, filesystem, group_name
+        LIMIT $2
+        "#,
+        schema = cluster.schema,
+        order = by.column()
+    );
+
+    let quotas = sqlx::query_as::<_, QuotaUsage>(&query)
+        .bind(params.filesystem)
+        .bind(limit)
+        .fetch_all(&cluster.read_pool())
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to get fullest quotas: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(quotas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Uri;
+
+    #[test]
+    fn test_quota_filter_parses_filesystem_and_group() {
+        let uri: Uri = "/storage/quotas?filesystem=/scratch&group=ccv"
+            .parse()
+            .unwrap();
+        let Query(filter) = Query::<QuotaFilter>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(filter.filesystem.as_deref(), Some("/scratch"));
+        assert_eq!(filter.group.as_deref(), Some("ccv"));
+    }
+
+    #[test]
+    fn test_fullest_quotas_defaults_to_bytes() {
+        let uri: Uri = "/storage/quotas/fullest".parse().unwrap();
+        let Query(params) = Query::<FullestQuotasQuery>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(params.by.unwrap_or_default(), QuotaDimension::Bytes);
+        assert_eq!(QuotaDimension::Inodes.column(), "inodes_fraction");
+    }
+
+    #[test]
+    fn test_prepare_storage_rejects_bad_rows() {
+        let body = br#"[
+            {"time": "2024-03-27T00:00:00", "filesystem": "/scratch", "used_bytes": 10,
+             "capacity_bytes": 100, "inodes_used": 1, "inodes_total": 10},
+            {"time": "2024-03-27T00:00:00", "filesystem": "/data", "used_bytes": 200,
+             "capacity_bytes": 100, "inodes_used": 1, "inodes_total": 10},
+            {"time": "2024-03-27T00:00:00", "filesystem": "/scratch", "used_bytes": 20,
+             "capacity_bytes": 100, "inodes_used": 2, "inodes_total": 10},
+            {"time": "2024-03-27T00:00:00", "filesystem": "/home"}
+        ]"#;
+
+        let (samples, report) = prepare_storage(parse_batch(body, false).unwrap());
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].used_bytes, 20);
+        assert_eq!(report.accepted, 1);
+        assert_eq!(report.rejected, 3);
+        assert_eq!(
+            report.errors[0].reason,
+            "duplicate row, superseded by row 2"
+        );
+        assert_eq!(
+            report.errors[1].reason,
+            "used_bytes (200) exceeds capacity_bytes (100)"
+        );
+        assert_eq!(report.errors[2].index, 3);
+    }
+
+    #[test]
+    fn test_prepare_quotas_allows_missing_and_exceeded_limits() {
+        let body = br#"[
+            {"time": "2024-03-27T00:00:00", "filesystem": "/data", "group": "ccv",
+             "used_bytes": 120, "limit_bytes": 100, "inodes_used": 5},
+            {"time": "2024-03-27T00:00:00", "filesystem": "/data", "group": "",
+             "used_bytes": 1, "inodes_used": 1}
+        ]"#;
+
+        let (samples, report) = prepare_quotas(parse_batch(body, false).unwrap());
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].group_name, "ccv");
+        assert_eq!(samples[0].inode_limit, None);
+        assert_eq!(report.errors[0].reason, "group_name is empty");
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashSet;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use sqlx::postgres::{PgConnectOptions, PgConnection, PgPoolOptions};
+use sqlx::Connection;
+
+/// `application_name` of connections that aren't running a request's queries.
+pub const APPLICATION_NAME: &str = "elmo-api";
+
+/// Limit for endpoints without their own entry when `QUERY_TIMEOUT_SECONDS` is not set.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How much longer than the endpoint's limit the server-side `statement_timeout` is.
+/// Queries are normally cancelled when the limit passes; this only stops them if that
+/// cancellation can't get through.
+const STATEMENT_TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// Connections idle for longer than this are checked before they are handed out, even
+/// when they need no preparing. Released connections were just checked by the pool.
+const RECHECK_IDLE_AFTER: Duration = Duration::from_secs(30);
+
+/// How long the database work behind each endpoint may take.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryTimeouts {
+    /// Limit for endpoints without an entry; `None` means unlimited.
+    pub default: Option<Duration>,
+    /// Limits by route, e.g. `/clusters/{cluster}/{resource}`.
+    pub endpoints: Vec<(String, Option<Duration>)>,
+}
+
+impl Default for QueryTimeouts {
+    fn default() -> Self {
+        QueryTimeouts {
+            default: Some(DEFAULT_TIMEOUT),
+            endpoints: Vec::new(),
+        }
+    }
+}
+
+/// Parses a number of seconds, where 0 means no limit.
+fn parse_limit(value: &str) -> Option<Option<Duration>> {
+    let seconds: u64 = value.trim().parse().ok()?;
+    Some(Some(Duration::from_secs(seconds)).filter(|limit| !limit.is_zero()))
+}
+
+impl QueryTimeouts {
+    /// Parses the default limit and a list of per-endpoint limits such as
+    /// `/usage/top=10,/clusters/{cluster}/{resource}=120`, all in seconds. The routes
+    /// are written as in the API docs, with `{...}` for path parameters.
+    pub fn parse(default: Option<&str>, endpoints: &str) -> Result<Self> {
+        let default = match default {
+            Some(value) => parse_limit(value)
+                .ok_or_else(|| anyhow!("invalid QUERY_TIMEOUT_SECONDS `{value}`"))?,
+            None => Some(DEFAULT_TIMEOUT),
+        };
+
+        let mut timeouts = QueryTimeouts {
+            default,
+            endpoints: Vec::new(),
+        };
+        for entry in endpoints
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let Some((route, limit)) = entry.split_once('=') else {
+                bail!("query timeout `{entry}` must look like /route=seconds");
+            };
+            let route = route.trim();
+            if !route.starts_with('/') {
+                bail!("query timeout route `{route}` must start with /");
+            }
+            if timeouts.endpoints.iter().any(|(r, _)| r == route) {
+                bail!("query timeout for `{route}` is listed more than once");
+            }
+            let limit = parse_limit(limit)
+                .ok_or_else(|| anyhow!("invalid query timeout `{entry}`, expected seconds"))?;
+
+            timeouts.endpoints.push((route.to_string(), limit));
+        }
+
+        Ok(timeouts)
+    }
+
+    /// Reads `QUERY_TIMEOUT_SECONDS` and `QUERY_TIMEOUTS`.
+    pub fn from_env() -> Result<Self> {
+        let default = env::var("QUERY_TIMEOUT_SECONDS").ok();
+        let endpoints = env::var("QUERY_TIMEOUTS").unwrap_or_default();
+
+        Self::parse(default.as_deref(), &endpoints).context("invalid query timeouts")
+    }
+
+    /// The limit for `route`, the path pattern the request matched.
+    pub fn limit(&self, route: &str) -> Option<Duration> {
+        self.endpoints
+            .iter()
+            .find(|(r, _)| r == route)
+            .map_or(self.default, |(_, limit)| *limit)
+    }
+}
+
+/// A connection a request has used, and how to reach its server to cancel it.
+#[derive(Debug)]
+struct Backend {
+    connect: Arc<PgConnectOptions>,
+    pid: i32,
+}
+
+/// The database connections one request has taken from the pools.
+#[derive(Debug)]
+struct RequestQueries {
+    /// `application_name` of the request's connections, so that a cancellation only
+    /// hits a backend still working for this request.
+    tag: String,
+    statement_timeout: Option<Duration>,
+    backends: Mutex<Vec<Backend>>,
+}
+
+tokio::task_local! {
+    static REQUEST: Arc<RequestQueries>;
+}
+
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(1);
+
+impl RequestQueries {
+    fn new(limit: Option<Duration>) -> Self {
+        RequestQueries {
+            tag: format!(
+                "{APPLICATION_NAME} request {}",
+                NEXT_REQUEST.fetch_add(1, Ordering::Relaxed)
+            ),
+            statement_timeout: limit.map(|limit| limit + STATEMENT_TIMEOUT_GRACE),
+            backends: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// Backends of one pool checked out with a request's `application_name` and
+/// `statement_timeout`. They get the defaults back as they are released, so idle
+/// connections never carry a request's settings.
+type Tagged = Arc<Mutex<HashSet<i32>>>;
+
+/// Tags a connection for the request acquiring it (if any), sets its
+/// `statement_timeout` to match, and records its backend for cancellation.
+async fn prepare(
+    conn: &mut PgConnection,
+    connect: Arc<PgConnectOptions>,
+    tagged: Tagged,
+) -> Result<(), sqlx::Error> {
+    let request = REQUEST.try_with(Arc::clone).ok();
+    let (tag, statement_timeout) = match &request {
+        Some(request) => (request.tag.as_str(), request.statement_timeout),
+        None => (APPLICATION_NAME, None),
+    };
+    let statement_timeout = statement_timeout.map_or(0, |timeout| timeout.as_millis());
+
+    let pid: i32 = sqlx::query_scalar(
+        "SELECT pg_backend_pid() FROM (SELECT set_config('statement_timeout', $1, false), \
+         set_config('application_name', $2, false)) AS settings",
+    )
+    .bind(statement_timeout.to_string())
+    .bind(tag)
+    .fetch_one(conn)
+    .await?;
+
+    match &request {
+        Some(_) => tagged.lock().unwrap().insert(pid),
+        None => tagged.lock().unwrap().remove(&pid),
+    };
+    if let Some(request) = request {
+        let mut backends = request.backends.lock().unwrap();
+        if !backends.iter().any(|backend| backend.pid == pid) {
+            backends.push(Backend { connect, pid });
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a connection must be prepared before it is handed out. Requests always
+/// tag theirs; elsewhere only long idle connections are checked, as released ones
+/// already have the defaults.
+fn needs_prepare(in_request: bool, idle_for: Duration) -> bool {
+    in_request || idle_for > RECHECK_IDLE_AFTER
+}
+
+/// Pool options that prepare each connection for the request acquiring it, and
+/// restore the defaults when a request releases it. The preparation query doubles as
+/// the check that an idle connection still works.
+pub fn pool_options(connect: &PgConnectOptions) -> PgPoolOptions {
+    let connect = Arc::new(connect.clone());
+    let (on_acquire, on_release) = (connect.clone(), connect.clone());
+    let tagged = Tagged::default();
+    let (on_acquire_tagged, on_release_tagged) = (tagged.clone(), tagged.clone());
+
+    PgPoolOptions::new()
+        .test_before_acquire(false)
+        .after_connect(move |conn, _| Box::pin(prepare(conn, connect.clone(), tagged.clone())))
+        .before_acquire(move |conn, meta| {
+            let connect = on_acquire.clone();
+            let tagged = on_acquire_tagged.clone();
+            Box::pin(async move {
+                let in_request = REQUEST.try_with(|_| ()).is_ok();
+                if needs_prepare(in_request, meta.idle_for) {
+                    prepare(conn, connect, tagged).await?;
+                }
+                Ok(true)
+            })
+        })
+        .after_release(move |conn, _| {
+            let connect = on_release.clone();
+            let tagged = on_release_tagged.clone();
+            Box::pin(async move {
+                // The released connection can't be told apart from the others, so while
+                // any request holds a tagged one, every release restores the defaults.
+                // Release runs outside the request, which untags the connection.
+                let any_tagged = !tagged.lock().unwrap().is_empty();
+                if any_tagged {
+                    prepare(conn, connect, tagged).await?;
+                }
+                Ok(true)
+            })
+        })
+}
+
+/// Stops the queries a request left running on the server.
+async fn cancel(tag: String, backends: Vec<Backend>) {
+    for Backend { connect, pid } in backends {
+        let result = async {
+            let mut conn = PgConnection::connect_with(&connect).await?;
+            let cancelled: bool = sqlx::query_scalar(
+                "SELECT COALESCE(bool_or(pg_cancel_backend(pid)), false) FROM pg_stat_activity \
+                 WHERE pid = $1 AND application_name = $2 AND state = 'active'",
+            )
+            .bind(pid)
+            .bind(&tag)
+            .fetch_one(&mut conn)
+            .await?;
+            conn.close().await?;
+            Ok::<_, sqlx::Error>(cancelled)
+        }
+        .await;
+
+        match result {
+            Ok(true) => tracing::info!("Cancelled the query of backend {} for {}", pid, tag),
+            Ok(false) => {}
+            Err(e) => tracing::error!(
+                "Error: failed to cancel the query of backend {}: {:?}",
+                pid,
+                e
+            ),
+        }
+    }
+}
+
+/// Cancels the request's queries unless it finished. Dropped without finishing when
+/// the request times out, or when the client goes away and axum drops the handler.
+struct CancelOnDrop {
+    queries: Arc<RequestQueries>,
+    finished: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        let backends = std::mem::take(&mut *self.queries.backends.lock().unwrap());
+        if backends.is_empty() {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(cancel(self.queries.tag.clone(), backends));
+        }
+    }
+}
+
+/// Middleware limiting how long an endpoint's database work may take. Past the limit
+/// the request fails with 504, and its queries are cancelled on the server, as they
+/// are when the client disconnects first.
+pub async fn query_timeout(
+    State(timeouts): State<Arc<QueryTimeouts>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => request.uri().path().to_string(),
+    };
+    let limit = timeouts.limit(&route);
+
+    let queries = Arc::new(RequestQueries::new(limit));
+    let mut guard = CancelOnDrop {
+        queries: queries.clone(),
+        finished: false,
+    };
+
+    let response = REQUEST
+        .scope(queries, async move {
+            match limit {
+                Some(limit) => tokio::time::timeout(limit, next.run(request)).await.ok(),
+                None => Some(next.run(request).await),
+            }
+        })
+        .await;
+
+    match response {
+        Some(response) => {
+            guard.finished = true;
+            Ok(response)
+        }
+        None => {
+            let limit = limit.unwrap_or_default();
+            tracing::warn!(
+                "Request to {} took longer than {:?}, cancelling its queries",
+                route,
+                limit
+            );
+            Err((
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("query timed out after {limit:?}"),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_parse_query_timeouts() {
+        let timeouts = QueryTimeouts::parse(
+            Some("60"),
+            "/usage/top=10, /clusters/{cluster}/{resource}=0",
+        )
+        .unwrap();
+
+        assert_eq!(timeouts.limit("/cpu"), Some(Duration::from_secs(60)));
+        assert_eq!(timeouts.limit("/usage/top"), Some(Duration::from_secs(10)));
+        assert_eq!(timeouts.limit("/clusters/{cluster}/{resource}"), None);
+        assert_eq!(
+            QueryTimeouts::parse(None, "").unwrap(),
+            QueryTimeouts::default()
+        );
+        assert_eq!(
+            QueryTimeouts::parse(Some("0"), "").unwrap().limit("/cpu"),
+            None
+        );
+
+        assert!(QueryTimeouts::parse(Some("soon"), "").is_err());
+        assert!(QueryTimeouts::parse(None, "/cpu").is_err());
+        assert!(QueryTimeouts::parse(None, "cpu=10").is_err());
+        assert!(QueryTimeouts::parse(None, "/cpu=10s").is_err());
+        assert!(QueryTimeouts::parse(None, "/cpu=10,/cpu=20").is_err());
+    }
+
+    #[test]
+    fn test_needs_prepare() {
+        let idle = Duration::from_secs;
+
+        // Outside a request, a recently used connection is ready.
+        assert!(!needs_prepare(false, idle(1)));
+        assert!(needs_prepare(false, idle(60)));
+        assert!(needs_prepare(true, idle(1)));
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout_returns_504() {
+        let timeouts = Arc::new(QueryTimeouts {
+            default: Some(Duration::from_millis(50)),
+            endpoints: vec![("/fast".to_string(), None)],
+        });
+        let app = axum::Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "done"
+                }),
+            )
+            .route("/fast", get(|| async { "done" }))
+            .route_layer(from_fn_with_state(timeouts, query_timeout));
+
+        let response = |uri: &str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let slow = response("/slow").await.unwrap();
+        assert_eq!(slow.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = to_bytes(slow.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "query timed out after 50ms");
+
+        assert_eq!(response("/fast").await.unwrap().status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,336 @@
+use chrono::NaiveDateTime;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use sqlx::postgres::PgPool;
+
+use crate::clusters::{find_cluster, ClusterQuery, ClusterRegistry};
+use crate::routes::TimeRange;
+
+/// Core-hours, GPU-hours and finished jobs for one day or month bucket.
+#[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct Usage {
+    pub time: Option<NaiveDateTime>,
+    pub cpu_hours: Option<f64>,
+    pub gpu_hours: Option<f64>,
+    pub jobs: Option<i64>,
+}
+
+/// One row of the usage leaderboard, either an account or a user.
+#[derive(Debug, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct UsageRank {
+    pub name: Option<String>,
+    pub cpu_hours: Option<f64>,
+    pub gpu_hours: Option<f64>,
+    pub jobs: Option<i64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageInterval {
+    #[default]
+    Day,
+    Month,
+}
+
+impl UsageInterval {
+    fn as_str(self) -> &'static str {
+        match self {
+            UsageInterval::Day => "day",
+            UsageInterval::Month => "month",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageGroup {
+    #[default]
+    Account,
+    User,
+}
+
+impl UsageGroup {
+    fn column(self) -> &'static str {
+        match self {
+            UsageGroup::Account => "account",
+            UsageGroup::User => "user_name",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RankBy {
+    #[default]
+    CpuHours,
+    GpuHours,
+    Jobs,
+}
+
+impl RankBy {
+    fn column(self) -> &'static str {
+        match self {
+            RankBy::CpuHours => "cpu_hours",
+            RankBy::GpuHours => "gpu_hours",
+            RankBy::Jobs => "jobs",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    pub interval: Option<UsageInterval>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeaderboardQuery {
+    pub group: Option<UsageGroup>,
+    pub by: Option<RankBy>,
+    pub limit: Option<i64>,
+}
+
+const DEFAULT_LEADERBOARD_LIMIT: i64 = 10;
+const MAX_LEADERBOARD_LIMIT: i64 = 1000;
+
+async fn usage_series(
+    pool: &PgPool,
+    schema: &str,
+    group: UsageGroup,
+    name: &str,
+    interval: UsageInterval,
+    time_range: &TimeRange,
+) -> Result<Vec<Usage>, sqlx::Error> {
+    // A job is charged to every bucket it overlaps, in proportion to the time it ran
+    // inside that bucket. The first and last buckets are clipped to the requested range
+    // (GREATEST and LEAST skip a missing bound), so a range starting at noon only counts
+    // the afternoon. The buckets themselves come from generate_series so that idle days
+    // still show up as zero instead of disappearing from the series.
+    let query = format!(
+        r#"
+        WITH jobs AS (
+            SELECT
+                start_time,
+                end_time,
+                cpus,
+                gpus
+            FROM
+                {schema}.jobs
+            WHERE {column} = $1
+              AND start_time IS NOT NULL
+              AND end_time IS NOT NULL
+        ),
+        bounds AS (
+            SELECT
+                date_trunc($2, COALESCE($3, MIN(start_time))) AS lo,
+                COALESCE($4, MAX(end_time)) AS hi
+            FROM jobs
+        ),
+        buckets AS (
+            SELECT
+                bucket AS time,
+                bucket + ('1 ' || $2)::interval AS bucket_end
+            FROM
+                bounds,
+                generate_series(bounds.lo, bounds.hi, ('1 ' || $2)::interval) AS bucket
+            WHERE bucket < bounds.hi
+        ),
+        clipped AS (
+            SELECT
+                time,
+                GREATEST(time, $3) AS lo,
+                LEAST(bucket_end, $4) AS hi
+            FROM buckets
+        )
+        SELECT
+            b.time,
+            COALESCE(SUM(j.cpus * EXTRACT(EPOCH FROM LEAST(j.end_time, b.hi) - GREATEST(j.start_time, b.lo)) / 3600.0), 0)::float8 AS cpu_hours,
+            COALESCE(SUM(j.gpus * EXTRACT(EPOCH FROM LEAST(j.end_time, b.hi) - GREATEST(j.start_time, b.lo)) / 3600.0), 0)::float8 AS gpu_hours,
+            COUNT(j.end_time) FILTER (WHERE j.end_time <= b.hi) AS jobs
+        FROM clipped b
+        LEFT JOIN jobs j ON j.start_time < b.hi AND j.end_time > b.lo
+        GROUP BY b.time
+        ORDER BY b.time
+        "#,
+        column = group.column()
+    );
+
+    let (start, end) = time_range.bounds();
+
+    sqlx::query_as::<_, Usage>(&query)
+        .bind(name)
+        .bind(interval.as_str())
+        .bind(start)
+        .bind(end)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_account_usage(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Path(account): Path<String>,
+    Query(time_range): Query<TimeRange>,
+    Query(params): Query<UsageQuery>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<Usage>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let interval = params.interval.unwrap_or_default();
+
+    let usage = usage_series(
+        &cluster.read_pool(),
+        &cluster.schema,
+        UsageGroup::Account,
+        &account,
+        interval,
+        &time_range,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!(
+            "Error: failed to get usage for account {}: {:?}",
+            account,
+            e
+        );
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    Ok(Json(usage))
+}
+
+pub async fn get_user_usage(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Path(user): Path<String>,
+    Query(time_range): Query<TimeRange>,
+    Query(params): Query<UsageQuery>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<Usage>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let interval = params.interval.unwrap_or_default();
+
+    let usage = usage_series(
+        &cluster.read_pool(),
+        &cluster.schema,
+        UsageGroup::User,
+        &user,
+        interval,
+        &time_range,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Error: failed to get usage for user {}: {:?}", user, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+
+    Ok(Json(usage))
+}
+
+pub async fn get_usage_leaderboard(
+    State(clusters): State<Arc<ClusterRegistry>>,
+    Query(time_range): Query<TimeRange>,
+    Query(params): Query<LeaderboardQuery>,
+    Query(cluster): Query<ClusterQuery>,
+) -> Result<Json<Vec<UsageRank>>, (StatusCode, String)> {
+    let cluster = find_cluster(&clusters, cluster.cluster.as_deref())?;
+    let group = params.group.unwrap_or_default();
+    let by = params.by.unwrap_or_default();
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LEADERBOARD_LIMIT)
+        .clamp(1, MAX_LEADERBOARD_LIMIT);
+
+    // Usage is clipped to the requested range, so a long job that started before
+    // `start` only contributes the hours it ran inside the range.
+    let query = format!(
+        r#"
+        WITH usage AS (
+            SELECT
+                {column} AS name,
+                cpus * EXTRACT(EPOCH FROM LEAST(end_time, COALESCE($2, end_time)) - GREATEST(start_time, COALESCE($1, start_time))) / 3600.0 AS cpu_hours,
+                gpus * EXTRACT(EPOCH FROM LEAST(end_time, COALESCE($2, end_time)) - GREATEST(start_time, COALESCE($1, start_time))) / 3600.0 AS gpu_hours,
+                end_time
+            FROM
+                {schema}.jobs
+            WHERE start_time IS NOT NULL
+              AND end_time IS NOT NULL
+              AND ($1::timestamp IS NULL OR end_time > $1)
+              AND ($2::timestamp IS NULL OR start_time < $2)
+        )
+        SELECT
+            name,
+            COALESCE(SUM(cpu_hours), 0)::float8 AS cpu_hours,
+            COALESCE(SUM(gpu_hours), 0)::float8 AS gpu_hours,
+            COUNT(*) FILTER (WHERE $2::timestamp IS NULL OR end_time <= $2) AS jobs
+        FROM usage
+        GROUP BY name
+        ORDER BY {order} DESC, name
+        LIMIT $3
+        "#,
+        schema = cluster.schema,
+        column = group.column(),
+        order = by.column()
+    );
+
+    let (start, end) = time_range.bounds();
+
+    let leaderboard = sqlx::query_as::<_, UsageRank>(&query)
+        .bind(start)
+        .bind(end)
+        .bind(limit)
+        .fetch_all(&cluster.read_pool())
+        .await
+        .map_err(|e| {
+            tracing::error!("Error: failed to get usage leaderboard: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        })?;
+
+    Ok(Json(leaderboard))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Uri;
+
+    #[test]
+    fn test_usage_query_defaults_to_daily() {
+        let uri: Uri = "/accounts/ccv/usage".parse().unwrap();
+        let Query(params) = Query::<UsageQuery>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(params.interval.unwrap_or_default(), UsageInterval::Day);
+    }
+
+    #[test]
+    fn test_usage_query_parses_month() {
+        let uri: Uri = "/users/alice/usage?interval=month".parse().unwrap();
+        let Query(params) = Query::<UsageQuery>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(params.interval, Some(UsageInterval::Month));
+        assert_eq!(UsageInterval::Month.as_str(), "month");
+    }
+
+    #[test]
+    fn test_leaderboard_query_parses_ranking() {
+        let uri: Uri = "/usage/top?group=user&by=gpu_hours&limit=5"
+            .parse()
+            .unwrap();
+        let Query(params) = Query::<LeaderboardQuery>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(params.group, Some(UsageGroup::User));
+        assert_eq!(params.by, Some(RankBy::GpuHours));
+        assert_eq!(params.limit, Some(5));
+        assert_eq!(UsageGroup::User.column(), "user_name");
+        assert_eq!(RankBy::GpuHours.column(), "gpu_hours");
+    }
+
+    #[test]
+    fn test_leaderboard_query_rejects_unknown_ranking() {
+        let uri: Uri = "/usage/top?by=memory".parse().unwrap();
+
+        assert!(Query::<LeaderboardQuery>::try_from_uri(&uri).is_err());
+    }
+}
@@ -216,24 +216,7 @@ async fn root_test() -> &'static str {
 async fn setup_test_db() -> SqlitePool {
     let pool = SqlitePool::connect(":memory:").await.unwrap();
 
-    // Create test tables
-    sqlx::query(
-        r#"
-        CREATE TABLE cpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        CREATE TABLE gpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    elmo_api::migrations::SQLITE.run(&pool).await.unwrap();
 
     // Insert test data
     sqlx::query(
@@ -15,23 +15,7 @@ use tower::ServiceExt;
 async fn setup_e2e_test_db() -> SqlitePool {
     let pool = SqlitePool::connect(":memory:").await.unwrap();
 
-    sqlx::query(
-        r#"
-        CREATE TABLE cpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        CREATE TABLE gpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    elmo_api::migrations::SQLITE.run(&pool).await.unwrap();
 
     sqlx::query(
         r#"
@@ -382,6 +366,7 @@ async fn get_body_bytes(response: Response) -> Vec<u8> {
     bytes.to_vec()
 }
 
+#[allow(dead_code)]
 async fn spawn_test_server() -> SocketAddr {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
@@ -12,23 +12,7 @@ use tower::ServiceExt;
 async fn setup_integration_test_db() -> SqlitePool {
     let pool = SqlitePool::connect(":memory:").await.unwrap();
 
-    sqlx::query(
-        r#"
-        CREATE TABLE cpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        CREATE TABLE gpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    elmo_api::migrations::SQLITE.run(&pool).await.unwrap();
 
     sqlx::query(
         r#"
@@ -649,7 +633,7 @@ async fn test_response_format() {
     let body = get_body_bytes(response).await;
     let cpu_data: Vec<Utilization> = serde_json::from_slice(&body).unwrap();
 
-    assert!(cpu_data.len() > 0);
+    assert!(!cpu_data.is_empty());
 
     let first_record = &cpu_data[0];
     assert!(first_record.time.is_some());
@@ -694,7 +678,7 @@ async fn test_all_endpoints_return_json() {
 
         let body = get_body_bytes(response).await;
         let _: Vec<Utilization> = serde_json::from_slice(&body)
-            .expect(&format!("Failed to parse JSON for endpoint: {}", endpoint));
+            .unwrap_or_else(|_| panic!("Failed to parse JSON for endpoint: {}", endpoint));
     }
 }
 
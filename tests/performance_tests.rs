@@ -12,23 +12,7 @@ use tower::ServiceExt;
 async fn setup_performance_test_db() -> SqlitePool {
     let pool = SqlitePool::connect(":memory:").await.unwrap();
 
-    sqlx::query(
-        r#"
-        CREATE TABLE cpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        CREATE TABLE gpu (
-            time TEXT NOT NULL,
-            allocated INTEGER NOT NULL,
-            total INTEGER NOT NULL
-        );
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    elmo_api::migrations::SQLITE.run(&pool).await.unwrap();
 
     let mut cpu_inserts = Vec::new();
     let mut gpu_inserts = Vec::new();
@@ -425,7 +409,7 @@ async fn test_hourly_aggregation_performance() {
 
     let body = get_body_bytes(response).await;
     let hourly_data: Vec<Utilization> = serde_json::from_slice(&body).unwrap();
-    assert!(hourly_data.len() > 0);
+    assert!(!hourly_data.is_empty());
 }
 
 #[tokio::test]
@@ -453,7 +437,7 @@ async fn test_daily_aggregation_performance() {
 
     let body = get_body_bytes(response).await;
     let daily_data: Vec<Utilization> = serde_json::from_slice(&body).unwrap();
-    assert!(daily_data.len() > 0);
+    assert!(!daily_data.is_empty());
 }
 
 #[tokio::test]
@@ -481,7 +465,7 @@ async fn test_time_range_query_performance() {
 
     let body = get_body_bytes(response).await;
     let cpu_data: Vec<Utilization> = serde_json::from_slice(&body).unwrap();
-    assert!(cpu_data.len() > 0);
+    assert!(!cpu_data.is_empty());
 }
 
 #[tokio::test]
@@ -0,0 +1,600 @@
+//! Tests that run the Postgres queries themselves instead of SQLite copies of them.
+//! They need a server whose user may create databases, given as TEST_DATABASE_URL,
+//! and are skipped when it is not set:
+//!
+//! TEST_DATABASE_URL=postgres://postgres@localhost/postgres cargo test --test postgres_tests
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{Request, Response, StatusCode},
+};
+use http_body_util::BodyExt;
+use serde::de::DeserializeOwned;
+use sqlx::postgres::{PgConnectOptions, PgPool};
+use tower::ServiceExt;
+
+use elmo_api::auth::{AdminTokens, IngestTokens};
+use elmo_api::clusters::{parse_cluster_specs, ClusterRegistry};
+use elmo_api::collector::sacct::parse_sacct;
+use elmo_api::fairshare::Fairshare;
+use elmo_api::ingest::IngestReport;
+use elmo_api::jobs::upsert_jobs;
+use elmo_api::partitions::{self, Partition};
+use elmo_api::quality::QuarantinedSample;
+use elmo_api::replicas::Replicas;
+use elmo_api::retention::{self, RetentionPolicy};
+use elmo_api::rollup;
+use elmo_api::routes::{GpuTypeUtilization, Utilization};
+use elmo_api::storage::{QuotaUsage, StorageUtilization};
+use elmo_api::timeouts;
+use elmo_api::usage::Usage;
+use elmo_api::{create_app, AppState};
+
+static NEXT_DATABASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Ingest and admin token of the test apps.
+const TOKEN: &str = "secret";
+
+/// A database of its own for one test, with every migration applied.
+struct TestDb {
+    pool: PgPool,
+    admin: PgPool,
+    name: String,
+}
+
+async fn test_db() -> Option<TestDb> {
+    let Ok(url) = env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set, skipping");
+        return None;
+    };
+
+    let admin = PgPool::connect(&url).await.unwrap();
+    let name = format!(
+        "elmo_test_{}_{}",
+        std::process::id(),
+        NEXT_DATABASE.fetch_add(1, Ordering::Relaxed)
+    );
+    sqlx::query(&format!("CREATE DATABASE {name}"))
+        .execute(&admin)
+        .await
+        .unwrap();
+
+    let options: PgConnectOptions = url.parse().unwrap();
+    let pool = PgPool::connect_with(options.database(&name)).await.unwrap();
+    elmo_api::migrations::POSTGRES.run(&pool).await.unwrap();
+
+    Some(TestDb { pool, admin, name })
+}
+
+impl TestDb {
+    fn app_state(&self) -> AppState {
+        let mut state = AppState::new(
+            self.pool.clone(),
+            ClusterRegistry::single(self.pool.clone()),
+        );
+        state.ingest_tokens = IngestTokens::new(vec![TOKEN.to_string()]);
+        state.admin_tokens = AdminTokens::new(vec![TOKEN.to_string()]);
+        state
+    }
+
+    /// State serving `oscar` and a second cluster, `hydra`, whose tables are copies of
+    /// the `oscar` ones in a schema of their own.
+    async fn two_cluster_state(&self) -> AppState {
+        self.execute(
+            r#"
+            CREATE SCHEMA hydra;
+            CREATE TABLE hydra.jobs (LIKE oscar.jobs INCLUDING ALL);
+            CREATE TABLE hydra.cpu (LIKE oscar.cpu INCLUDING ALL);
+            CREATE TABLE hydra.gpu (LIKE oscar.gpu INCLUDING ALL);
+            SELECT oscar.create_rollup_tables('hydra');
+            "#,
+        )
+        .await;
+
+        let specs = parse_cluster_specs("oscar=oscar,hydra=hydra").unwrap();
+        let clusters =
+            ClusterRegistry::from_specs(specs, self.pool.clone(), &Replicas::default()).unwrap();
+        let mut state = self.app_state();
+        state.clusters = Arc::new(clusters);
+        state
+    }
+
+    async fn execute(&self, sql: &str) {
+        sqlx::raw_sql(sql).execute(&self.pool).await.unwrap();
+    }
+
+    async fn drop(self) {
+        self.pool.close().await;
+        sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", self.name))
+            .execute(&self.admin)
+            .await
+            .unwrap();
+    }
+}
+
+async fn request(state: AppState, method: &str, uri: &str, body: &str) -> Response<Body> {
+    let app = create_app(state).await;
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", format!("Bearer {TOKEN}"))
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.oneshot(request).await.unwrap()
+}
+
+async fn send<T: DeserializeOwned>(state: AppState, method: &str, uri: &str, body: &str) -> T {
+    let response = request(state, method, uri, body).await;
+    assert_eq!(response.status(), StatusCode::OK, "{method} {uri}");
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn get_json<T: DeserializeOwned>(state: AppState, uri: &str) -> T {
+    send(state, "GET", uri, "").await
+}
+
+async fn post_json(state: AppState, uri: &str, body: &str) -> IngestReport {
+    send(state, "POST", uri, body).await
+}
+
+fn time(value: &str) -> chrono::NaiveDateTime {
+    value.parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_usage_clips_buckets_to_time_range() {
+    let Some(db) = test_db().await else { return };
+    db.execute(
+        r#"
+        INSERT INTO oscar.jobs (job_id, account, user_name, start_time, end_time, cpus, gpus) VALUES
+            ('1', 'ccv', 'alice', '2024-03-27T06:00:00', '2024-03-28T06:00:00', 4, 0),
+            ('2', 'ccv', 'bob', '2024-03-27T13:00:00', '2024-03-27T15:00:00', 2, 1),
+            ('3', 'other', 'carol', '2024-03-27T13:00:00', '2024-03-27T15:00:00', 64, 8);
+        "#,
+    )
+    .await;
+
+    let usage: Vec<Usage> = get_json(
+        db.app_state(),
+        "/accounts/ccv/usage?start=2024-03-27T12:00:00&end=2024-03-28T03:00:00",
+    )
+    .await;
+
+    // Job 1 only counts from noon on the first day and until 3am on the second.
+    let days: Vec<_> = usage
+        .iter()
+        .map(|u| (u.time.unwrap().date().to_string(), u.cpu_hours.unwrap()))
+        .collect();
+    assert_eq!(
+        days,
+        vec![
+            ("2024-03-27".to_string(), 52.0),
+            ("2024-03-28".to_string(), 12.0)
+        ]
+    );
+    assert_eq!(usage[0].gpu_hours, Some(2.0));
+    // Job 1 ends after the range, so only job 2 counts as finished.
+    assert_eq!((usage[0].jobs, usage[1].jobs), (Some(1), Some(0)));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_gpu_types_leave_out_retired_models() {
+    let Some(db) = test_db().await else { return };
+    db.execute(
+        r#"
+        INSERT INTO oscar.gpu (time, gpu_type, allocated, total) VALUES
+            ('2024-02-01T00:00:00', 'k80', 10, 16),
+            ('2024-03-27T00:00:00', 'a100', 6, 8),
+            ('2024-03-27T00:00:00', 'h100', 2, 4),
+            ('2024-03-27T00:05:00', 'a100', 7, 8);
+        "#,
+    )
+    .await;
+
+    let types: Vec<GpuTypeUtilization> = get_json(db.app_state(), "/gpu/types").await;
+
+    let types: Vec<_> = types
+        .iter()
+        .map(|t| (t.gpu_type.clone().unwrap(), t.allocated.unwrap()))
+        .collect();
+    assert_eq!(
+        types,
+        vec![("a100".to_string(), 7), ("h100".to_string(), 2)]
+    );
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_storage_samples_are_ingested() {
+    let Some(db) = test_db().await else { return };
+
+    let report = post_json(
+        db.app_state(),
+        "/storage/samples",
+        r#"[
+            {"time": "2024-03-27T00:00:00", "filesystem": "/scratch", "used_bytes": 40,
+             "capacity_bytes": 100, "inodes_used": 4, "inodes_total": 10},
+            {"time": "2024-03-27T00:30:00", "filesystem": "/scratch", "used_bytes": 60,
+             "capacity_bytes": 100, "inodes_used": 6, "inodes_total": 10},
+            {"time": "2024-03-27T00:30:00", "filesystem": "/data", "used_bytes": 200,
+             "capacity_bytes": 100, "inodes_used": 6, "inodes_total": 10}
+        ]"#,
+    )
+    .await;
+    assert_eq!((report.accepted, report.rejected), (2, 1));
+
+    let report = post_json(
+        db.app_state(),
+        "/storage/quotas/samples",
+        r#"[
+            {"time": "2024-03-27T00:00:00", "filesystem": "/data", "group": "ccv",
+             "used_bytes": 90, "limit_bytes": 100, "inodes_used": 1, "inode_limit": 10},
+            {"time": "2024-03-27T00:00:00", "filesystem": "/data", "group": "bio",
+             "used_bytes": 10, "limit_bytes": 100, "inodes_used": 1}
+        ]"#,
+    )
+    .await;
+    assert_eq!(report.accepted, 2);
+
+    let hourly: Vec<StorageUtilization> =
+        get_json(db.app_state(), "/storage/hourly?filesystem=/scratch").await;
+    assert_eq!(hourly.len(), 1);
+    assert_eq!(hourly[0].used_bytes, Some(50));
+
+    let fullest: Vec<QuotaUsage> = get_json(db.app_state(), "/storage/quotas/fullest").await;
+    let groups: Vec<_> = fullest
+        .iter()
+        .map(|q| q.group_name.clone().unwrap())
+        .collect();
+    assert_eq!(groups, vec!["ccv", "bio"]);
+    assert_eq!(fullest[0].bytes_fraction, Some(0.9));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_fairshare_snapshots_are_ingested() {
+    let Some(db) = test_db().await else { return };
+
+    let report = post_json(
+        db.app_state(),
+        "/fairshare/samples",
+        r#"[
+            {"time": "2024-03-27T00:00:00", "account": "ccv", "raw_shares": 100,
+             "effective_usage": 0.2, "fairshare": 0.6},
+            {"time": "2024-03-27T01:00:00", "account": "ccv", "raw_shares": 100,
+             "effective_usage": 0.3, "fairshare": 0.5},
+            {"time": "2024-03-27T01:00:00", "account": "ccv", "user": "alice",
+             "raw_shares": 1, "effective_usage": 0.1, "fairshare": 0.9}
+        ]"#,
+    )
+    .await;
+    assert_eq!((report.accepted, report.rejected), (3, 0));
+
+    let fairshare: Fairshare = get_json(db.app_state(), "/fairshare?account=ccv").await;
+    assert_eq!(fairshare.current.unwrap().fairshare, Some(0.5));
+    assert_eq!(fairshare.history.len(), 2);
+
+    let fairshare: Fairshare = get_json(db.app_state(), "/fairshare?account=ccv&user=alice").await;
+    let current = fairshare.current.unwrap();
+    assert_eq!(current.user_name.as_deref(), Some("alice"));
+    assert_eq!(current.raw_shares, Some(1));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_cluster_parameter_selects_the_schema() {
+    let Some(db) = test_db().await else { return };
+    let state = db.two_cluster_state().await;
+
+    let jobs = r#"[{"job_id": "1", "account": "ccv", "user": "alice", "cpus": 4,
+        "start_time": "2024-03-27T00:00:00", "end_time": "2024-03-27T02:00:00"}]"#;
+    let report = post_json(state.clone(), "/jobs?cluster=hydra", jobs).await;
+    assert_eq!(report.accepted, 1);
+
+    let hydra: Vec<Usage> = get_json(state.clone(), "/accounts/ccv/usage?cluster=hydra").await;
+    assert_eq!(hydra[0].cpu_hours, Some(8.0));
+    let oscar: Vec<Usage> = get_json(state.clone(), "/accounts/ccv/usage").await;
+    assert!(oscar.is_empty());
+
+    let response = request(state.clone(), "GET", "/accounts/ccv/usage?cluster=nope", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = request(state, "POST", "/jobs?cluster=nope", jobs).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_cluster_schemas_need_every_table() {
+    let Some(db) = test_db().await else { return };
+    let state = db.two_cluster_state().await;
+
+    let error = state.clusters.check_tables().await.unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("`hydra` is missing the tables storage"));
+
+    db.execute(
+        r#"
+        CREATE TABLE hydra.storage (LIKE oscar.storage INCLUDING ALL);
+        CREATE TABLE hydra.quotas (LIKE oscar.quotas INCLUDING ALL);
+        CREATE TABLE hydra.fairshare (LIKE oscar.fairshare INCLUDING ALL);
+        CREATE TABLE hydra.partition_utilization (LIKE oscar.partition_utilization INCLUDING ALL);
+        CREATE TABLE hydra.import_state (LIKE oscar.import_state INCLUDING ALL);
+        CREATE TABLE hydra.quarantine (LIKE oscar.quarantine INCLUDING ALL);
+        "#,
+    )
+    .await;
+    state.clusters.check_tables().await.unwrap();
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_jobcomp_keeps_sizes_from_sacct() {
+    let Some(db) = test_db().await else { return };
+    let jobs = parse_sacct(include_str!("fixtures/sacct.txt")).unwrap();
+    upsert_jobs(&db.pool, "oscar", &jobs).await.unwrap();
+
+    // A jobcomp document without `total_cpus` or `tres_alloc` arriving after sacct.
+    let report = post_json(
+        db.app_state(),
+        "/jobcomp",
+        r#"{"jobid": 1002, "username": "bob", "account": "ccv", "state": "TIMEOUT",
+            "@end": "2024-03-28T01:15:00"}"#,
+    )
+    .await;
+    assert_eq!(report.accepted, 1);
+
+    let sizes: (Option<i32>, Option<i32>) =
+        sqlx::query_as("SELECT cpus, gpus FROM oscar.jobs WHERE job_id = '1002'")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+    assert_eq!(sizes, (Some(16), Some(4)));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_conflicts_with_stored_samples_are_quarantined() {
+    let Some(db) = test_db().await else { return };
+    let stored = || async {
+        sqlx::query_as::<_, (chrono::NaiveDateTime, i32)>("SELECT time, allocated FROM oscar.cpu")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap()
+    };
+
+    let line = |allocated| format!("slurm cpus_alloc={allocated}i,cpus_total=100i 1711497600");
+    let report = post_json(db.app_state(), "/write?precision=s", &line(75)).await;
+    assert_eq!(report.accepted, 1);
+    let (time, _) = stored().await;
+
+    // Re-sending the stored sample is fine, a different one is not.
+    let sample =
+        |allocated| format!(r#"[{{"time": "{time:?}", "allocated": {allocated}, "total": 100}}]"#);
+    let report = post_json(db.app_state(), "/cpu/samples", &sample(75)).await;
+    assert_eq!((report.accepted, report.quarantined), (1, 0));
+    let report = post_json(db.app_state(), "/cpu/samples", &sample(50)).await;
+    assert_eq!((report.accepted, report.quarantined), (0, 1));
+    assert_eq!(
+        report.errors[0].reason,
+        "quarantined: conflicts with the stored sample"
+    );
+    let report = post_json(db.app_state(), "/write?precision=s", &line(60)).await;
+    assert_eq!((report.accepted, report.quarantined), (0, 1));
+    assert_eq!(stored().await, (time, 75));
+
+    // Releasing a quarantined sample still replaces the stored one.
+    let quarantine: Vec<QuarantinedSample> = get_json(db.app_state(), "/admin/quarantine").await;
+    assert_eq!(quarantine.len(), 2);
+    let conflict = quarantine.iter().find(|q| q.allocated == 50).unwrap();
+    let _: QuarantinedSample = send(
+        db.app_state(),
+        "POST",
+        &format!("/admin/quarantine/{}/release", conflict.id),
+        "",
+    )
+    .await;
+    assert_eq!(stored().await, (time, 50));
+
+    db.drop().await;
+}
+
+/// The hourly CPU rollup row at `time`, as (allocated_sum, samples).
+async fn hourly_cpu_rollup(db: &TestDb, time: &str) -> Option<(i64, i64)> {
+    sqlx::query_as(
+        "SELECT allocated_sum, samples FROM oscar.rollup \
+         WHERE resource = 'cpu' AND bucket = 'hour' AND time = $1::timestamp",
+    )
+    .bind(time)
+    .fetch_optional(&db.pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_rollups_follow_writes_made_outside_the_app() {
+    let Some(db) = test_db().await else { return };
+    let now = time("2024-03-27T12:00:00");
+    let policy = RetentionPolicy::default();
+    let refresh = || rollup::refresh(&db.pool, "oscar", &policy, now);
+
+    db.execute(
+        "INSERT INTO oscar.cpu (time, allocated, total) VALUES \
+         ('2024-03-26 10:00', 40, 100), ('2024-03-26 10:30', 60, 100)",
+    )
+    .await;
+    assert_eq!(refresh().await.unwrap(), 2);
+    assert_eq!(
+        hourly_cpu_rollup(&db, "2024-03-26 10:00").await,
+        Some((100, 2))
+    );
+
+    db.execute("UPDATE oscar.cpu SET allocated = 80 WHERE time = '2024-03-26 10:30'")
+        .await;
+    refresh().await.unwrap();
+    assert_eq!(
+        hourly_cpu_rollup(&db, "2024-03-26 10:00").await,
+        Some((120, 2))
+    );
+
+    db.execute("DELETE FROM oscar.cpu").await;
+    refresh().await.unwrap();
+    assert_eq!(hourly_cpu_rollup(&db, "2024-03-26 10:00").await, None);
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_rollups_of_another_cluster_schema() {
+    let Some(db) = test_db().await else { return };
+    let state = db.two_cluster_state().await;
+    let now = time("2024-03-27T12:00:00");
+    let policy = RetentionPolicy::default();
+
+    db.execute(
+        "INSERT INTO oscar.cpu (time, allocated, total) VALUES ('2024-03-26 10:00', 40, 100); \
+         INSERT INTO hydra.cpu (time, allocated, total) VALUES ('2024-03-26 10:00', 10, 50)",
+    )
+    .await;
+    // Each write marks the buckets of its own schema.
+    let dirty: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM hydra.rollup_dirty")
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+    assert_eq!(dirty, 2);
+
+    for schema in ["oscar", "hydra"] {
+        assert_eq!(
+            rollup::refresh(&db.pool, schema, &policy, now)
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    let hydra: Vec<Utilization> = get_json(state.clone(), "/clusters/hydra/cpu/hourly").await;
+    assert_eq!(hydra.len(), 1);
+    assert_eq!(hydra[0].allocated, Some(10));
+    let all: Vec<Utilization> = get_json(state, "/clusters/all/cpu/hourly").await;
+    assert_eq!(all.len(), 1);
+    assert_eq!((all[0].allocated, all[0].total), (Some(50), Some(150)));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_late_samples_leave_expired_rollups_alone() {
+    let Some(db) = test_db().await else { return };
+    let now = time("2024-03-27T12:00:00");
+    let policy = RetentionPolicy::parse("raw=30d").unwrap();
+    let state = db.app_state();
+    let cluster = state.clusters.default_cluster();
+
+    db.execute(
+        "INSERT INTO oscar.cpu (time, allocated, total) VALUES \
+         ('2024-01-10 10:00', 40, 100), ('2024-01-10 10:30', 60, 100)",
+    )
+    .await;
+    // Rolled up while the samples were still kept.
+    rollup::refresh(&db.pool, "oscar", &RetentionPolicy::default(), now)
+        .await
+        .unwrap();
+    retention::enforce(cluster, &policy, now, false)
+        .await
+        .unwrap();
+    let raw: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM oscar.cpu")
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+    assert_eq!(raw, 0);
+    assert_eq!(
+        hourly_cpu_rollup(&db, "2024-01-10 10:00").await,
+        Some((100, 2))
+    );
+
+    // A sample arriving for the expired bucket must not rebuild it from itself alone.
+    let sample = r#"[{"time": "2024-01-10T10:15:00", "allocated": 90, "total": 100}]"#;
+    let report = post_json(db.app_state(), "/cpu/samples", sample).await;
+    assert_eq!(report.accepted, 1);
+    rollup::refresh(&db.pool, "oscar", &policy, now)
+        .await
+        .unwrap();
+    assert_eq!(
+        hourly_cpu_rollup(&db, "2024-01-10 10:00").await,
+        Some((100, 2))
+    );
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_default_partition_catches_months_without_a_partition() {
+    let Some(db) = test_db().await else { return };
+    let rows = |partitions: &[Partition], name: &str| {
+        partitions
+            .iter()
+            .find(|partition| partition.name == name)
+            .map(|partition| partition.rows)
+    };
+
+    db.execute("INSERT INTO oscar.cpu (time, allocated, total) VALUES ('2040-01-15', 40, 100)")
+        .await;
+    let listed = partitions::list(&db.pool, "oscar").await.unwrap();
+    assert_eq!(rows(&listed, "cpu_default"), Some(1));
+
+    // The sample moves once its month gets a partition.
+    let created = partitions::create_ahead(&db.pool, "oscar", time("2039-12-15T00:00:00"), 1)
+        .await
+        .unwrap();
+    assert!(created.contains(&"cpu_p2040_01".to_string()));
+    let listed = partitions::list(&db.pool, "oscar").await.unwrap();
+    assert_eq!(rows(&listed, "cpu_default"), Some(0));
+    assert_eq!(rows(&listed, "cpu_p2040_01"), Some(1));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn test_connections_lose_request_settings_outside_requests() {
+    let Some(db) = test_db().await else { return };
+    let options: PgConnectOptions = env::var("TEST_DATABASE_URL").unwrap().parse().unwrap();
+    let pool = timeouts::pool_options(&options)
+        .max_connections(1)
+        .connect_with(options.database(&db.name))
+        .await
+        .unwrap();
+    let settings = || async {
+        sqlx::query_as::<_, (String, String)>(
+            "SELECT current_setting('application_name'), current_setting('statement_timeout')",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+    };
+    let defaults = (timeouts::APPLICATION_NAME.to_string(), "0".to_string());
+    assert_eq!(settings().await, defaults);
+
+    // A request tags the only connection and resets it when releasing it.
+    let mut state = AppState::new(pool.clone(), ClusterRegistry::single(pool.clone()));
+    state.admin_tokens = AdminTokens::new(vec![TOKEN.to_string()]);
+    let _: Vec<Partition> = get_json(state, "/admin/partitions").await;
+    assert_eq!(settings().await, defaults);
+
+    pool.close().await;
+    db.drop().await;
+}